@@ -0,0 +1,235 @@
+//! Snapshots of the store, taken once a day by the running process and kept in the data directory. They're rotated
+//! so that there's one left for each of the last few days and weeks.
+//!
+//! A snapshot is a gzipped JSON Lines export, sealed as well if the store is.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, Local, NaiveDateTime};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::storage::{dump::Dump, Storage};
+
+/// The rotation, in the config directory
+pub const CONFIG_FILE: &str = "backup.json";
+const EXT: &str = ".jsonl.gz";
+/// The names of the snapshots, which sort the same way as the times they were taken
+const NAME_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// How many snapshots are kept: the latest of each of the last `daily` days, and of each of the last `weekly`
+/// weeks
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rotation {
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Rotation {
+        Rotation { daily: 7, weekly: 4 }
+    }
+}
+
+impl Rotation {
+    /// The rotation in the JSON object at `path`, the default one if there's no such file
+    pub fn load(path: &Path) -> anyhow::Result<Rotation> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Rotation::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Which of `snapshots`, newest first, are kept
+    fn kept(&self, snapshots: &[Snapshot]) -> Vec<bool> {
+        let mut days = BTreeSet::new();
+        let mut weeks = BTreeSet::new();
+        snapshots
+            .iter()
+            .map(|snapshot| {
+                let day = snapshot.time.date();
+                let week = (day.iso_week().year(), day.iso_week().week());
+                let mut keep = false;
+                if !days.contains(&day) && days.len() < self.daily {
+                    days.insert(day);
+                    keep = true;
+                }
+                if !weeks.contains(&week) && weeks.len() < self.weekly {
+                    weeks.insert(week);
+                    keep = true;
+                }
+                keep
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub name: String,
+    /// When it was taken, in local time
+    pub time: NaiveDateTime,
+    pub path: PathBuf,
+}
+
+/// Where the snapshots of the store of the user are kept
+pub fn dir() -> anyhow::Result<PathBuf> {
+    let data_dir = dirs::data_dir().ok_or_else(|| anyhow::anyhow!("Can't find the data directory"))?;
+    Ok(data_dir.join("sched").join("backups"))
+}
+
+/// The snapshots in `dir`, newest first
+pub fn list(dir: &Path) -> anyhow::Result<Vec<Snapshot>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = match path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(EXT))
+        {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // Anything else that's been put there is left alone
+        if let Ok(time) = NaiveDateTime::parse_from_str(&name, NAME_FORMAT) {
+            snapshots.push(Snapshot { name, time, path });
+        }
+    }
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.time));
+    Ok(snapshots)
+}
+
+/// The snapshot in `dir` called `name`
+pub fn find(dir: &Path, name: &str) -> anyhow::Result<Snapshot> {
+    list(dir)?
+        .into_iter()
+        .find(|s| s.name == name)
+        .ok_or_else(|| anyhow::anyhow!("There's no snapshot {}, see `sched backup list`", name))
+}
+
+/// Writes `bytes` to `path` through a temporary file, so that there's never half a snapshot
+pub fn write(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let tmp = stage(path, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Writes `bytes` to a file next to `path` and returns it, for renaming over `path` once everything else it goes along
+/// with is in place
+pub fn stage(path: &Path, bytes: &[u8]) -> anyhow::Result<PathBuf> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    Ok(tmp)
+}
+
+/// Takes a snapshot of `store` into `dir`
+pub fn take(store: &Storage, dir: &Path) -> anyhow::Result<Snapshot> {
+    fs::create_dir_all(dir)?;
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&store.snapshot()?)?;
+    let bytes = store.seal_blob(gz.finish()?)?;
+    let time = Local::now().naive_local();
+    let name = time.format(NAME_FORMAT).to_string();
+    let path = dir.join(format!("{}{}", name, EXT));
+    write(&path, &bytes)?;
+    Ok(Snapshot { name, time, path })
+}
+
+/// Removes the snapshots in `dir` that `rotation` doesn't keep, and returns them
+pub fn rotate(dir: &Path, rotation: Rotation) -> anyhow::Result<Vec<Snapshot>> {
+    let snapshots = list(dir)?;
+    let kept = rotation.kept(&snapshots);
+    let mut removed = Vec::new();
+    for (snapshot, kept) in snapshots.into_iter().zip(kept) {
+        if !kept {
+            fs::remove_file(&snapshot.path)?;
+            removed.push(snapshot);
+        }
+    }
+    Ok(removed)
+}
+
+/// Takes a snapshot of `store` unless there's one from today already, then rotates them. What's configured in
+/// `config` is what's kept.
+pub fn backup_if_due(store: &Storage, dir: &Path, config: &Path) -> anyhow::Result<Option<Snapshot>> {
+    let today = Local::now().naive_local().date();
+    if list(dir)?.first().is_some_and(|s| s.time.date() == today) {
+        return Ok(None);
+    }
+    let snapshot = take(store, dir)?;
+    rotate(dir, Rotation::load(config)?)?;
+    Ok(Some(snapshot))
+}
+
+/// Replaces everything in `store` with what's in `snapshot`
+pub fn restore(store: &Storage, snapshot: &Snapshot) -> anyhow::Result<()> {
+    let bytes = store.unseal_blob(fs::read(&snapshot.path)?)?;
+    let mut lines = Vec::new();
+    GzDecoder::new(&bytes[..]).read_to_end(&mut lines)?;
+    let mut dump = Dump::read_lines(&lines[..])?;
+    // The IDs handed out since may be known elsewhere already, e.g. to a synced store, so they aren't handed out
+    // again
+    let (logs_id, objs_id) = store.counters()?;
+    if dump.logs_id.device() == logs_id.device() {
+        dump.logs_id = dump.logs_id.max(logs_id);
+    }
+    if dump.objs_id.device() == objs_id.device() {
+        dump.objs_id = dump.objs_id.max(objs_id);
+    }
+    store.import(dump, false, true)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(name: &str) -> Snapshot {
+        Snapshot {
+            name: name.into(),
+            time: NaiveDateTime::parse_from_str(name, NAME_FORMAT).unwrap(),
+            path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn test_rotate() {
+        let snapshots = [
+            "2021-01-20T10-00-00",
+            "2021-01-20T09-00-00",
+            "2021-01-19T09-00-00",
+            "2021-01-18T09-00-00",
+            // The week before, from Monday the 11th
+            "2021-01-16T09-00-00",
+            "2021-01-12T09-00-00",
+            "2021-01-09T09-00-00",
+            "2021-01-01T09-00-00",
+        ]
+        .iter()
+        .map(|&n| snapshot(n))
+        .collect::<Vec<_>>();
+        let kept = Rotation { daily: 2, weekly: 3 }.kept(&snapshots);
+        assert_eq!(kept, [true, false, true, false, true, false, true, false]);
+        assert!(Rotation { daily: 0, weekly: 0 }.kept(&snapshots).iter().all(|k| !k));
+
+        let dir = std::env::temp_dir().join(format!("sched-test-backups-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Storage::in_memory().unwrap();
+        let taken = take(&store, &dir).unwrap();
+        fs::write(dir.join("notes.txt"), "not a snapshot").unwrap();
+        assert_eq!(list(&dir).unwrap().len(), 1);
+        assert!(backup_if_due(&store, &dir, &dir.join(CONFIG_FILE)).unwrap().is_none());
+        restore(&store, &find(&dir, &taken.name).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
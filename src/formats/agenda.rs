@@ -0,0 +1,385 @@
+//! An agenda of a range of days to paste into a daily note, in Org mode or Markdown: for each day, the sub tasks still
+//! to be done by then, the events happening, and what's been logged. What's overdue is on the first day.
+
+use std::collections::{btree_map, BTreeMap};
+use std::io::{self, Write};
+
+use chrono::{Local, NaiveDate};
+
+use crate::storage::{
+    self,
+    api::*,
+    time::{DateTime, Duration},
+    OptRepeated, Storage,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Org,
+    Markdown,
+}
+
+#[derive(Clone, Debug)]
+pub enum Entry {
+    /// An unfinished sub task, named after its task. `current` if it's the one `find_current` says to do now.
+    Todo {
+        sub: ObjId,
+        name: String,
+        deadline: DateTime,
+        current: bool,
+    },
+    Event {
+        id: ObjId,
+        name: String,
+        start: DateTime,
+        duration: Duration,
+    },
+    Log {
+        id: LogId,
+        time: DateTime,
+        typ: String,
+        /// The object it's about and what it's called
+        obj: Option<(ObjId, String)>,
+    },
+}
+
+impl Entry {
+    fn time(&self) -> DateTime {
+        match self {
+            Entry::Todo { deadline, .. } => *deadline,
+            Entry::Event { start, .. } => *start,
+            Entry::Log { time, .. } => *time,
+        }
+    }
+}
+
+/// What's on each day from `from` up to `to`, by local dates
+#[derive(Clone, Debug)]
+pub struct Agenda {
+    pub from: DateTime,
+    pub to: DateTime,
+    pub days: BTreeMap<NaiveDate, Vec<Entry>>,
+}
+
+fn local(time: DateTime) -> chrono::DateTime<Local> {
+    time.0.with_timezone(&Local)
+}
+
+/// What an object was called at `time`, or its task's name for a sub task
+fn name_at(store: &Storage, id: ObjId, time: DateTime) -> storage::Result<Option<String>> {
+    let obj = match store.get_obj_at(id, time)? {
+        Some(obj) => obj,
+        None => return Ok(None),
+    };
+    let task = obj
+        .props
+        .get("task-id")
+        .and_then(|t| serde_json::from_value::<ObjId>(t.clone()).ok());
+    match (obj.typ.as_str(), task) {
+        // Sub tasks are created before their task
+        (SubTask::OBJ_TYPE, Some(task)) => Ok(name_at(store, task, time)?
+            .or(name_at(store, task, DateTime::now())?)
+            .or(Some(obj.name))),
+        _ => Ok(Some(obj.name)),
+    }
+}
+
+impl Agenda {
+    /// Gathers the agenda from the store
+    pub fn collect(store: &Storage, from: DateTime, to: DateTime) -> storage::Result<Agenda> {
+        let mut entries = Vec::new();
+
+        let subs = store.find_obj_old::<SubTask, _>(|s| s.inner.finished.is_none() && s.inner.deadline < to, None)?;
+        let mut current = BTreeMap::new();
+        for sub in subs {
+            let task_id = sub.inner.task_id;
+            if let btree_map::Entry::Vacant(entry) = current.entry(task_id) {
+                entry.insert(store.find_current(task_id)?);
+            }
+            entries.push(Entry::Todo {
+                sub: sub.id,
+                name: store.get_obj::<Task>(task_id)?.name,
+                deadline: sub.inner.deadline,
+                current: current[&task_id] == Some(sub.id),
+            });
+        }
+
+        for event in store.find_obj_old::<Event, _>(|_| true, None)? {
+            let duration = event.inner.duration;
+            let starts = match &event.inner.start {
+                OptRepeated::Single(start) => vec![*start],
+                OptRepeated::Repeat(repeat) => repeat.occurrences_between(DateTime(from.0 - duration.0), to),
+            };
+            // Still going on at the start of the range counts as well
+            for start in starts
+                .into_iter()
+                .filter(|s| *s < to && (from <= *s || from.0 < s.0 + duration.0))
+            {
+                entries.push(Entry::Event {
+                    id: event.id,
+                    name: event.name.clone(),
+                    start,
+                    duration,
+                });
+            }
+        }
+
+        for log in store.logs_between(from, to, "*")? {
+            // Only the logs on objects have one, as `id`
+            let obj = match log.typ.split('.').next() {
+                Some("obj") | Some("task") => log
+                    .props
+                    .get("id")
+                    .and_then(|id| serde_json::from_value::<ObjId>(id.clone()).ok()),
+                _ => None,
+            };
+            // The state changes every time a notification goes out
+            if obj == Some(State::ID) {
+                continue;
+            }
+            let obj = match obj {
+                Some(id) => {
+                    // A deleted object is only named in the log
+                    let deleted = log
+                        .props
+                        .get("obj")
+                        .and_then(|o| o.get("name"))
+                        .and_then(|n| n.as_str());
+                    let name = match name_at(store, id, log.time)? {
+                        Some(name) => name,
+                        None => deleted.unwrap_or_default().to_string(),
+                    };
+                    Some((id, name))
+                }
+                None => None,
+            };
+            entries.push(Entry::Log {
+                id: log.id,
+                time: log.time,
+                typ: log.typ,
+                obj,
+            });
+        }
+
+        entries.sort_by_key(Entry::time);
+        let mut days = BTreeMap::<_, Vec<_>>::new();
+        for entry in entries {
+            // What's overdue or started before the range is put on its first day
+            let day = local(entry.time().max(from)).naive_local().date();
+            days.entry(day).or_default().push(entry);
+        }
+        Ok(Agenda { from, to, days })
+    }
+
+    /// Writes the agenda as a heading for each day that has anything on it
+    pub fn write(&self, out: &mut dyn Write, style: Style) -> io::Result<()> {
+        let range = format!(
+            "{} to {}",
+            local(self.from).format("%Y-%m-%d %H:%M"),
+            local(self.to).format("%Y-%m-%d %H:%M")
+        );
+        match style {
+            Style::Org => writeln!(out, "#+TITLE: Agenda from {}", range)?,
+            Style::Markdown => writeln!(out, "# Agenda from {}", range)?,
+        }
+        for (day, entries) in &self.days {
+            writeln!(out)?;
+            match style {
+                Style::Org => writeln!(out, "* {}", day.format("%a %Y-%m-%d"))?,
+                Style::Markdown => writeln!(out, "## {}\n", day.format("%a %Y-%m-%d"))?,
+            }
+            for entry in entries.iter().filter(|e| !matches!(e, Entry::Log { .. })) {
+                write_entry(out, entry, style)?;
+            }
+            let mut logs = entries.iter().filter(|e| matches!(e, Entry::Log { .. })).peekable();
+            if logs.peek().is_some() {
+                match style {
+                    Style::Org => writeln!(out, "** Log")?,
+                    Style::Markdown => writeln!(out, "\n### Log\n")?,
+                }
+                for entry in logs {
+                    write_entry(out, entry, style)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An Org mode timestamp, active as it's in the agenda
+fn org_time(time: DateTime) -> String {
+    local(time).format("<%Y-%m-%d %a %H:%M>").to_string()
+}
+
+fn write_entry(out: &mut dyn Write, entry: &Entry, style: Style) -> io::Result<()> {
+    match (entry, style) {
+        (
+            Entry::Todo {
+                sub,
+                name,
+                deadline,
+                current,
+            },
+            Style::Org,
+        ) => {
+            writeln!(out, "** TODO {}", name)?;
+            // What's to be done now is scheduled for now
+            let scheduled = if *current {
+                format!("SCHEDULED: {} ", org_time(DateTime::now()))
+            } else {
+                String::new()
+            };
+            writeln!(out, "   {}DEADLINE: {}", scheduled, org_time(*deadline))?;
+            writeln!(out, "   :PROPERTIES:\n   :ID: {}\n   :END:", sub)
+        }
+        (
+            Entry::Todo {
+                sub,
+                name,
+                deadline,
+                current,
+            },
+            Style::Markdown,
+        ) => writeln!(
+            out,
+            "- [ ] {} (due {}{}) `{}`",
+            name,
+            local(*deadline).format("%H:%M"),
+            if *current { ", current" } else { "" },
+            sub
+        ),
+        (
+            Entry::Event {
+                id,
+                name,
+                start,
+                duration,
+            },
+            style,
+        ) => {
+            let end = local(DateTime(start.0 + duration.0));
+            let start = local(*start);
+            let same_day = start.naive_local().date() == end.naive_local().date();
+            match style {
+                Style::Org if same_day => writeln!(
+                    out,
+                    "** {}\n   {}",
+                    name,
+                    start.format(&format!("<%Y-%m-%d %a %H:%M-{}>", end.format("%H:%M")))
+                )?,
+                Style::Org => writeln!(
+                    out,
+                    "** {}\n   {}--{}",
+                    name,
+                    start.format("<%Y-%m-%d %a %H:%M>"),
+                    end.format("<%Y-%m-%d %a %H:%M>")
+                )?,
+                Style::Markdown if same_day => {
+                    writeln!(out, "- {}–{} {}", start.format("%H:%M"), end.format("%H:%M"), name)?
+                }
+                Style::Markdown => writeln!(
+                    out,
+                    "- {}–{} {}",
+                    start.format("%H:%M"),
+                    end.format("%Y-%m-%d %H:%M"),
+                    name
+                )?,
+            }
+            if style == Style::Org {
+                writeln!(out, "   :PROPERTIES:\n   :ID: {}\n   :END:", id)?;
+            }
+            Ok(())
+        }
+        (Entry::Log { id, time, typ, obj }, _) => {
+            let about = match obj {
+                Some((obj, name)) if name.is_empty() => format!(" {}", obj),
+                Some((obj, name)) => format!(" {} ({})", name, obj),
+                None => String::new(),
+            };
+            writeln!(out, "- {} {}{} `{}`", local(*time).format("%H:%M"), typ, about, id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration as ChronoDuration, TimeZone};
+
+    use super::*;
+    use crate::storage::{Every, Repeated, Stop};
+
+    fn time(d: u32, h: u32) -> DateTime {
+        Local.with_ymd_and_hms(2021, 1, d, h, 0, 0).unwrap().into()
+    }
+
+    #[test]
+    fn test_agenda() {
+        let store = Storage::in_memory().unwrap();
+        let daily = Repeated::new(
+            vec![time(4, 9)],
+            Every::Time(ChronoDuration::days(1).into()),
+            Stop::Nonstop,
+        )
+        .unwrap();
+        let task = store
+            .create_task("water".into(), None, None, OptRepeated::Repeat(daily), 0)
+            .unwrap();
+        let first = store.get_obj::<Task>(task).unwrap().inner.cache[0];
+        store.task_finish(first, time(4, 8)).unwrap();
+        let weekly = Repeated::new(
+            vec![time(1, 10)],
+            Every::Time(ChronoDuration::weeks(1).into()),
+            Stop::Nonstop,
+        )
+        .unwrap();
+        store
+            .create_event(
+                "standup".into(),
+                OptRepeated::Repeat(weekly),
+                ChronoDuration::minutes(15).into(),
+                None,
+                None,
+            )
+            .unwrap();
+
+        let agenda = Agenda::collect(&store, time(4, 0), time(6, 0)).unwrap();
+        let days = agenda
+            .days
+            .keys()
+            .map(|d| d.format("%d").to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(days, ["05"]);
+        let day = &agenda.days[&time(5, 0).0.naive_local().date()];
+        assert!(matches!(&day[0], Entry::Todo { name, .. } if name == "water"));
+        // The weekly event doesn't fall in the range, and the finished sub task isn't there
+        let events = agenda
+            .days
+            .values()
+            .flatten()
+            .filter(|e| matches!(e, Entry::Event { .. }));
+        assert_eq!(events.count(), 0);
+        assert!(agenda.days.values().flatten().all(|e| match e {
+            Entry::Todo { sub, .. } => *sub != first,
+            _ => true,
+        }));
+
+        // Still to be done from the day before
+        let agenda = Agenda::collect(&store, time(6, 0), time(7, 0)).unwrap();
+        let deadlines = agenda.days[&time(6, 0).0.naive_local().date()]
+            .iter()
+            .map(Entry::time)
+            .collect::<Vec<_>>();
+        assert_eq!(deadlines, [time(5, 9), time(6, 9)]);
+        assert_eq!(agenda.days.len(), 1);
+
+        let mut out = Vec::new();
+        Agenda::collect(&store, time(8, 0), time(9, 0))
+            .unwrap()
+            .write(&mut out, Style::Org)
+            .unwrap();
+        let org = String::from_utf8(out).unwrap();
+        assert!(org.contains("* Fri 2021-01-08\n"));
+        assert!(org.contains("** standup\n   <2021-01-08 Fri 10:00-10:15>\n"));
+        assert!(org.contains("** TODO water\n   DEADLINE: <2021-01-08 Fri 09:00>\n"));
+    }
+}
@@ -0,0 +1,1248 @@
+//! iCalendar (RFC 5545) output, so that the schedule shows up in calendar applications. Events become VEVENTs,
+//! tasks and their sub tasks VTODOs, and notifications VALARMs. A recurrence is written as an RRULE when it can be
+//! said in one, with the occurrences it skips or moves in EXDATE and RDATE, and as its occurrences in RDATE otherwise.
+//!
+//! Calendars can be read back in as well: VEVENTs become events and VTODOs tasks, with the UID kept in the
+//! `ics-uid` attr so that importing the same calendar again updates what it created the first time.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use thiserror::Error;
+
+use super::{by_attr, finish_due, same_time, set_deadline, ImportReport};
+use crate::storage::{
+    self,
+    api::*,
+    rule::{ByDay, Freq, Overflow, Rule},
+    time::{DateTime, Duration, Tz},
+    Every, OptRepeated, Repeated, Stop, Storage,
+};
+
+/// How far ahead a recurrence without an end is expanded when it can't be written as an RRULE
+const HORIZON_DAYS: i64 = 366;
+/// The most occurrences written for one recurrence when it's expanded
+const MAX_EXPANDED: usize = 1000;
+
+/// The objects that make up the calendar
+pub struct Calendar<'a> {
+    pub events: &'a [Obj<Event>],
+    pub tasks: &'a [Obj<Task>],
+    pub subs: &'a [Obj<SubTask>],
+}
+
+/// Writes properties as content lines, escaped and folded
+struct Lines<'a> {
+    out: &'a mut dyn Write,
+}
+
+impl Lines<'_> {
+    fn raw(&mut self, line: &str) -> io::Result<()> {
+        // Folded at 75 octets, without splitting a character
+        let mut start = 0;
+        let mut limit = 75;
+        for (i, c) in line.char_indices() {
+            if i + c.len_utf8() - start > limit {
+                write!(self.out, "{}\r\n ", &line[start..i])?;
+                start = i;
+                limit = 74;
+            }
+        }
+        write!(self.out, "{}\r\n", &line[start..])
+    }
+
+    fn text(&mut self, name: &str, text: &str) -> io::Result<()> {
+        let escaped = text
+            .replace('\\', "\\\\")
+            .replace(';', "\\;")
+            .replace(',', "\\,")
+            .replace("\r\n", "\\n")
+            .replace('\n', "\\n");
+        self.raw(&format!("{}:{}", name, escaped))
+    }
+}
+
+pub fn fmt_time(time: DateTime) -> String {
+    time.0.naive_utc().format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+pub fn fmt_duration(dur: ChronoDuration) -> String {
+    let sign = if dur < ChronoDuration::zero() { "-" } else { "" };
+    let secs = dur.num_seconds().abs();
+    if secs == 0 {
+        return "PT0S".into();
+    }
+    if secs % (7 * 86400) == 0 {
+        return format!("{}P{}W", sign, secs / (7 * 86400));
+    }
+    let (days, rest) = (secs / 86400, secs % 86400);
+    let mut s = format!("{}P", sign);
+    if days > 0 {
+        s += &format!("{}D", days);
+    }
+    if rest > 0 {
+        s += "T";
+        let (h, m, sec) = (rest / 3600, rest / 60 % 60, rest % 60);
+        if h > 0 {
+            s += &format!("{}H", h);
+        }
+        if m > 0 {
+            s += &format!("{}M", m);
+        }
+        if sec > 0 {
+            s += &format!("{}S", sec);
+        }
+    }
+    s
+}
+
+pub fn uid(id: ObjId) -> String {
+    format!("{}@sched", id)
+}
+
+/// The RRULE that gives the same occurrences as what's left of `repeat`, if there's one
+fn rrule(repeat: &Repeated) -> Option<String> {
+    if repeat.start().len() != 1 {
+        return None;
+    }
+    let every = match repeat.every() {
+        Every::Time(Duration(dur)) => {
+            let secs = dur.num_seconds();
+            let units = [
+                (7 * 86400, "WEEKLY"),
+                (86400, "DAILY"),
+                (3600, "HOURLY"),
+                (60, "MINUTELY"),
+                (1, "SECONDLY"),
+            ];
+            let &(unit, freq) = units.iter().find(|(unit, _)| secs > 0 && secs % unit == 0)?;
+            format!("FREQ={};INTERVAL={}", freq, secs / unit)
+        }
+        // Always a rule, `Every::Month` can't be expanded past a month without the day it's on
+        Every::Month(months) => format!(
+            "FREQ=MONTHLY;INTERVAL={}{}",
+            (*months).max(1),
+            overflow_parts(repeat, None)?
+        ),
+        Every::Rule(rule) => {
+            let on_start_day = rule.by_month_day.is_empty() && rule.by_day.is_empty();
+            let monthly = matches!(rule.freq, Freq::Monthly | Freq::Yearly) && on_start_day;
+            if monthly && !rule.by_set_pos.is_empty() {
+                return None;
+            }
+            let parts = if monthly {
+                overflow_parts(repeat, Some(rule))?
+            } else {
+                String::new()
+            };
+            format!("{}{}", rule_parts(rule), parts)
+        }
+    };
+    match repeat.stop() {
+        Stop::Nonstop => Some(every),
+        Stop::Count(count) => Some(format!("{};COUNT={}", every, count)),
+        Stop::After(until) => Some(format!("{};UNTIL={}", every, fmt_time(*until))),
+        Stop::Stopped => None,
+    }
+}
+
+/// The parts of an RRULE that make a recurrence by months from the day of the start of `repeat` do what its
+/// overflow says in months without that day. An RRULE leaves such months out, and can only pick the last day of
+/// the month instead, in the months of `rule` if it's a rule.
+fn overflow_parts(repeat: &Repeated, rule: Option<&Rule>) -> Option<String> {
+    let start = repeat.start()[0].wall(repeat.zone());
+    let day = start.day();
+    match repeat.overflow() {
+        _ if day <= 28 => Some(String::new()),
+        Overflow::Skip => Some(String::new()),
+        Overflow::Clamp => {
+            let days = (28..=day).map(|d| d.to_string()).collect::<Vec<_>>().join(",");
+            // Without months of its own, a yearly rule would pick from all of them
+            let month = match rule {
+                Some(rule) if rule.freq == Freq::Yearly && rule.by_month.is_empty() => {
+                    format!(";BYMONTH={}", start.month())
+                }
+                _ => String::new(),
+            };
+            Some(format!("{};BYMONTHDAY={};BYSETPOS=-1", month, days))
+        }
+        Overflow::Roll => None,
+    }
+}
+
+/// The parts of an RRULE for `rule`, without when it stops
+fn rule_parts(rule: &Rule) -> String {
+    let freq = match rule.freq {
+        Freq::Daily => "DAILY",
+        Freq::Weekly => "WEEKLY",
+        Freq::Monthly => "MONTHLY",
+        Freq::Yearly => "YEARLY",
+    };
+    let list = |ns: &[i32]| ns.iter().map(i32::to_string).collect::<Vec<_>>().join(",");
+    let mut s = format!("FREQ={};INTERVAL={}", freq, rule.interval.max(1));
+    if !rule.by_month.is_empty() {
+        s += &format!(
+            ";BYMONTH={}",
+            list(&rule.by_month.iter().map(|&m| m as i32).collect::<Vec<_>>())
+        );
+    }
+    if !rule.by_month_day.is_empty() {
+        s += &format!(";BYMONTHDAY={}", list(&rule.by_month_day));
+    }
+    if !rule.by_day.is_empty() {
+        let days = rule
+            .by_day
+            .iter()
+            .map(|b| {
+                let day = WEEKDAYS.iter().find(|(_, d)| *d == b.day).unwrap().0;
+                if b.nth == 0 {
+                    day.to_string()
+                } else {
+                    format!("{}{}", b.nth, day)
+                }
+            })
+            .collect::<Vec<_>>();
+        s += &format!(";BYDAY={}", days.join(","));
+    }
+    if !rule.by_set_pos.is_empty() {
+        s += &format!(";BYSETPOS={}", list(&rule.by_set_pos));
+    }
+    s
+}
+
+/// Writes when something happens: the first occurrence as `first` (DTSTART or DUE), and the rest of them as an RRULE
+/// or RDATE
+fn occurrences(lines: &mut Lines, first: &[&str], time: &OptRepeated, now: DateTime) -> io::Result<()> {
+    let repeat = match time {
+        OptRepeated::Single(time) => {
+            for name in first {
+                lines.raw(&format!("{}:{}", name, fmt_time(*time)))?;
+            }
+            return Ok(());
+        }
+        OptRepeated::Repeat(repeat) => repeat,
+    };
+    let rule = rrule(repeat);
+    let horizon = now.0 + ChronoDuration::days(HORIZON_DAYS);
+    let times = match rule {
+        // Where the rule starts, whether or not that one is skipped or moved
+        Some(_) => repeat.first().into_iter().collect::<Vec<_>>(),
+        None => repeat
+            .iter()
+            .take(MAX_EXPANDED)
+            .take_while(|t| matches!(repeat.stop(), Stop::Count(_) | Stop::After(_)) || t.0 <= horizon)
+            .collect(),
+    };
+    // Nothing to happen, it's still written where it starts
+    let start = times.first().copied().or_else(|| repeat.start().first().copied());
+    if let Some(start) = start {
+        for name in first {
+            match (repeat.zone(), &rule) {
+                // So that the rule steps on the wall clock there too. There's no VTIMEZONE for it, the IANA name is
+                // what's relied on.
+                (Some(zone), Some(_)) => lines.raw(&format!(
+                    "{};TZID={}:{}",
+                    name,
+                    zone.name(),
+                    start.wall(Some(zone)).format("%Y%m%dT%H%M%S")
+                ))?,
+                _ => lines.raw(&format!("{}:{}", name, fmt_time(start)))?,
+            }
+        }
+    }
+    match rule {
+        Some(rule) if !times.is_empty() => {
+            lines.raw(&format!("RRULE:{}", rule))?;
+            // A moved one is left out where it was, and added where it is
+            let exceptions = repeat.exceptions();
+            if !exceptions.is_empty() {
+                let exdates = exceptions.iter().map(|e| fmt_time(e.at)).collect::<Vec<_>>();
+                lines.raw(&format!("EXDATE:{}", exdates.join(",")))?;
+            }
+            let rdates = exceptions.iter().filter_map(|e| e.to).map(fmt_time).collect::<Vec<_>>();
+            if !rdates.is_empty() {
+                lines.raw(&format!("RDATE:{}", rdates.join(",")))?;
+            }
+        }
+        _ if times.len() > 1 => {
+            let rdates = times[1..].iter().map(|t| fmt_time(*t)).collect::<Vec<_>>();
+            lines.raw(&format!("RDATE:{}", rdates.join(",")))?
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The notifications of a VTODO, which are relative to when it's due
+fn alarms(lines: &mut Lines, name: &str, notifications: &[Duration]) -> io::Result<()> {
+    for notification in notifications {
+        lines.raw("BEGIN:VALARM")?;
+        lines.raw("ACTION:DISPLAY")?;
+        lines.raw(&format!("TRIGGER;RELATED=END:{}", fmt_duration(notification.0)))?;
+        lines.text("DESCRIPTION", name)?;
+        lines.raw("END:VALARM")?;
+    }
+    Ok(())
+}
+
+fn common<T: ApiObj>(lines: &mut Lines, obj: &Obj<T>, now: DateTime) -> io::Result<()> {
+    lines.raw(&format!("UID:{}", uid(obj.id)))?;
+    lines.raw(&format!("DTSTAMP:{}", fmt_time(now)))?;
+    lines.text("SUMMARY", &obj.name)?;
+    if let Some(desc) = &obj.desc {
+        lines.text("DESCRIPTION", desc)?;
+    }
+    Ok(())
+}
+
+/// Writes the whole calendar. `now` is the DTSTAMP, and where expanding recurrences without an end starts from.
+pub fn write(out: &mut dyn Write, cal: &Calendar, now: DateTime) -> io::Result<()> {
+    let lines = &mut Lines { out };
+    lines.raw("BEGIN:VCALENDAR")?;
+    lines.raw("VERSION:2.0")?;
+    lines.raw("PRODID:-//sched//sched//EN")?;
+    for event in cal.events {
+        lines.raw("BEGIN:VEVENT")?;
+        common(lines, event, now)?;
+        occurrences(lines, &["DTSTART"], &event.inner.start, now)?;
+        lines.raw(&format!("DURATION:{}", fmt_duration(event.inner.duration.0)))?;
+        lines.raw("END:VEVENT")?;
+    }
+    for task in cal.tasks {
+        lines.raw("BEGIN:VTODO")?;
+        common(lines, task, now)?;
+        // A recurrence is anchored on DTSTART, which is the deadline as well
+        match task.inner.deadline {
+            OptRepeated::Single(_) => occurrences(lines, &["DUE"], &task.inner.deadline, now)?,
+            OptRepeated::Repeat(_) => occurrences(lines, &["DTSTART", "DUE"], &task.inner.deadline, now)?,
+        }
+        alarms(lines, &task.name, &task.inner.notifications)?;
+        lines.raw("END:VTODO")?;
+    }
+    for sub in cal.subs {
+        lines.raw("BEGIN:VTODO")?;
+        common(lines, sub, now)?;
+        lines.raw(&format!("DUE:{}", fmt_time(sub.inner.deadline)))?;
+        lines.raw(&format!("RELATED-TO;RELTYPE=PARENT:{}", uid(sub.inner.task_id)))?;
+        match sub.inner.finished {
+            Some(finished) => {
+                lines.raw("STATUS:COMPLETED")?;
+                lines.raw(&format!("COMPLETED:{}", fmt_time(finished)))?;
+            }
+            None => lines.raw("STATUS:NEEDS-ACTION")?,
+        }
+        alarms(lines, &sub.name, &sub.inner.notifications)?;
+        lines.raw("END:VTODO")?;
+    }
+    lines.raw("END:VCALENDAR")
+}
+
+/// The attr an imported object keeps its UID in
+pub const UID_ATTR: &str = "ics-uid";
+
+#[derive(Debug, Error)]
+pub enum ReadError {
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+    #[error(transparent)]
+    Storage(#[from] storage::Error),
+}
+
+/// A property as it's written, e.g. `DTSTART;VALUE=DATE:20210104`
+#[derive(Clone, Debug)]
+pub struct Prop {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+impl Prop {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// A component and everything between its BEGIN and END
+#[derive(Clone, Debug, Default)]
+pub struct Component {
+    pub name: String,
+    pub props: Vec<Prop>,
+    pub subs: Vec<Component>,
+}
+
+impl Component {
+    pub fn prop(&self, name: &str) -> Option<&Prop> {
+        self.props.iter().find(|p| p.name == name)
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.prop(name).map(|p| unescape(&p.value))
+    }
+}
+
+/// Joins folded lines back together, along with the number of the line each starts on
+fn unfold(s: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (n, line) in s.lines().enumerate() {
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(rest), Some((_, last))) => *last += rest,
+            _ if line.trim().is_empty() => {}
+            _ => lines.push((n + 1, line.to_string())),
+        }
+    }
+    lines
+}
+
+fn content_line(line: &str) -> Option<Prop> {
+    let end = line.find([';', ':'])?;
+    let name = line[..end].to_ascii_uppercase();
+    let mut rest = &line[end..];
+    let mut params = Vec::new();
+    while let Some(param) = rest.strip_prefix(';') {
+        let eq = param.find('=')?;
+        let key = param[..eq].to_ascii_uppercase();
+        let mut val = &param[eq + 1..];
+        let mut value = String::new();
+        loop {
+            if let Some(quoted) = val.strip_prefix('"') {
+                let close = quoted.find('"')?;
+                value += &quoted[..close];
+                val = &quoted[close + 1..];
+            } else {
+                let stop = val.find([';', ':', ','])?;
+                value += &val[..stop];
+                val = &val[stop..];
+            }
+            match val.strip_prefix(',') {
+                Some(next) => {
+                    value.push(',');
+                    val = next;
+                }
+                None => break,
+            }
+        }
+        params.push((key, value));
+        rest = val;
+    }
+    let value = rest.strip_prefix(':')?.to_string();
+    Some(Prop { name, params, value })
+}
+
+/// The components at the top of a calendar file, usually a single VCALENDAR
+pub fn parse(s: &str) -> Result<Vec<Component>, ReadError> {
+    let mut top = Vec::new();
+    let mut open: Vec<Component> = Vec::new();
+    let mut last = 0;
+    for (n, line) in unfold(s) {
+        last = n;
+        let prop = content_line(&line).ok_or_else(|| ReadError::Syntax(n, "malformed content line".into()))?;
+        match prop.name.as_str() {
+            "BEGIN" => open.push(Component {
+                name: prop.value.to_ascii_uppercase(),
+                ..Component::default()
+            }),
+            "END" => {
+                let comp = open
+                    .pop()
+                    .filter(|c| c.name.eq_ignore_ascii_case(&prop.value))
+                    .ok_or_else(|| ReadError::Syntax(n, format!("END:{} without its BEGIN", prop.value)))?;
+                match open.last_mut() {
+                    Some(parent) => parent.subs.push(comp),
+                    None => top.push(comp),
+                }
+            }
+            _ => open
+                .last_mut()
+                .ok_or_else(|| ReadError::Syntax(n, "property outside of a component".into()))?
+                .props
+                .push(prop),
+        }
+    }
+    match open.last() {
+        Some(comp) => Err(ReadError::Syntax(last, format!("BEGIN:{} without its END", comp.name))),
+        None => Ok(top),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') | Some('N') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            (c, false) => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reads a DATE or DATE-TIME. Times that aren't in UTC are taken as local ones.
+pub fn parse_time(s: &str) -> Option<DateTime> {
+    if let Some(utc) = s.strip_suffix('Z') {
+        let naive = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some(Utc.from_utc_datetime(&naive).into());
+    }
+    Local
+        .from_local_datetime(&parse_naive(s)?)
+        .earliest()
+        .map(DateTime::from)
+}
+
+/// Reads a DATE or DATE-TIME that isn't in UTC
+fn parse_naive(s: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%S")
+        .ok()
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y%m%d").ok()?.and_hms_opt(0, 0, 0))
+}
+
+pub fn parse_duration(s: &str) -> Option<ChronoDuration> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let mut secs = 0i64;
+    let mut num: Option<i64> = None;
+    let (mut time, mut any) = (false, false);
+    for c in s.strip_prefix('P')?.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            num = Some(num.unwrap_or(0).checked_mul(10)?.checked_add(digit.into())?);
+            continue;
+        }
+        let unit = match (time, c) {
+            (false, 'T') if num.is_none() => {
+                time = true;
+                continue;
+            }
+            (false, 'W') => 7 * 86400,
+            (false, 'D') => 86400,
+            (true, 'H') => 3600,
+            (true, 'M') => 60,
+            (true, 'S') => 1,
+            _ => return None,
+        };
+        secs = secs.checked_add(num.take()?.checked_mul(unit)?)?;
+        any = true;
+    }
+    if num.is_some() || !any {
+        return None;
+    }
+    Some(ChronoDuration::seconds(if neg { -secs } else { secs }))
+}
+
+/// The zone of a DATE-TIME, if its TZID is the name of one in the IANA database
+fn zone_of(prop: &Prop) -> Option<Tz> {
+    prop.param("TZID")?.parse().ok()
+}
+
+/// A DATE-TIME of `prop`, read in the zone its TZID names if there's such a zone
+fn zoned_time(prop: &Prop, s: &str) -> Option<DateTime> {
+    match zone_of(prop) {
+        Some(zone) if !s.ends_with('Z') => parse_naive(s).and_then(|naive| DateTime::in_zone(naive, zone)),
+        _ => parse_time(s),
+    }
+}
+
+fn time_prop(comp: &Component, name: &str) -> Result<Option<DateTime>, String> {
+    comp.prop(name)
+        .map(|p| zoned_time(p, &p.value).ok_or_else(|| format!("invalid {} '{}'", name, p.value)))
+        .transpose()
+}
+
+/// All the times of a property that can be given more than once and lists times, like RDATE and EXDATE
+fn time_list(comp: &Component, name: &str) -> Result<Vec<DateTime>, String> {
+    comp.props
+        .iter()
+        .filter(|p| p.name == name)
+        .flat_map(|p| p.value.split(',').map(move |t| (p, t)))
+        .map(|(p, t)| zoned_time(p, t).ok_or_else(|| format!("invalid {} '{}'", name, t)))
+        .collect()
+}
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("MO", Weekday::Mon),
+    ("TU", Weekday::Tue),
+    ("WE", Weekday::Wed),
+    ("TH", Weekday::Thu),
+    ("FR", Weekday::Fri),
+    ("SA", Weekday::Sat),
+    ("SU", Weekday::Sun),
+];
+
+/// A BYDAY entry, e.g. `MO`, `2TU` or `-1FR`
+fn by_day(s: &str) -> Option<ByDay> {
+    let split = s.len().checked_sub(2)?;
+    let (nth, day) = (s.get(..split)?, s.get(split..)?);
+    let day = WEEKDAYS.iter().find(|(d, _)| *d == day).map(|&(_, day)| day)?;
+    let nth = match nth {
+        "" => 0,
+        nth => nth.parse::<i32>().ok().filter(|n| *n != 0 && n.abs() <= 53)?,
+    };
+    Some(ByDay { nth, day })
+}
+
+/// The recurrence an RRULE describes, starting at `start`
+fn recurrence(rule: &str, start: DateTime) -> Result<Repeated, String> {
+    let parts = rule
+        .split(';')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (key, val) = p
+                .split_once('=')
+                .ok_or_else(|| format!("malformed RRULE part '{}'", p))?;
+            Ok((key.to_ascii_uppercase(), val.to_ascii_uppercase()))
+        })
+        .collect::<Result<HashMap<_, _>, String>>()?;
+    let number = |key: &str| {
+        parts
+            .get(key)
+            .map(|n| {
+                n.parse::<u32>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or(format!("invalid {} '{}'", key, n))
+            })
+            .transpose()
+    };
+    let interval = number("INTERVAL")?.unwrap_or(1);
+    let freq = parts.get("FREQ").map(String::as_str).unwrap_or_default();
+    let rule_freq = match freq {
+        "DAILY" => Some(Freq::Daily),
+        "WEEKLY" => Some(Freq::Weekly),
+        "MONTHLY" => Some(Freq::Monthly),
+        "YEARLY" => Some(Freq::Yearly),
+        _ => None,
+    };
+    // Days or positions that are picked out make it a calendar rule, the rest a plain step
+    let mut rule = None;
+    for (key, val) in &parts {
+        let numbers = |range: std::ops::RangeInclusive<i32>| {
+            val.split(',')
+                .map(|n| n.parse::<i32>().ok().filter(|n| *n != 0 && range.contains(&n.abs())))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("invalid {} '{}'", key, val))
+        };
+        let rule = match (key.as_str(), rule_freq) {
+            ("FREQ", _) | ("INTERVAL", _) | ("COUNT", _) | ("UNTIL", _) | ("WKST", _) => continue,
+            ("BYMONTH", Some(f)) | ("BYMONTHDAY", Some(f)) | ("BYDAY", Some(f)) | ("BYSETPOS", Some(f)) => {
+                rule.get_or_insert_with(|| Rule::new(f, interval))
+            }
+            _ => return Err(format!("{}={} isn't supported", key, val)),
+        };
+        match key.as_str() {
+            // Months can't be counted from the end
+            "BYMONTH" if !val.contains('-') => {
+                rule.by_month = numbers(1..=12)?.into_iter().map(|m| m as u32).collect();
+            }
+            "BYMONTHDAY" => rule.by_month_day = numbers(1..=31)?,
+            "BYSETPOS" => rule.by_set_pos = numbers(1..=366)?,
+            "BYDAY" => {
+                rule.by_day = val
+                    .split(',')
+                    .map(by_day)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| format!("invalid BYDAY '{}'", val))?;
+            }
+            _ => return Err(format!("invalid {} '{}'", key, val)),
+        }
+    }
+    let secs = |unit: i64| Every::Time(ChronoDuration::seconds(unit * i64::from(interval)).into());
+    let every = match (freq, rule) {
+        (_, Some(rule)) => Every::Rule(rule),
+        ("SECONDLY", _) => secs(1),
+        ("MINUTELY", _) => secs(60),
+        ("HOURLY", _) => secs(3600),
+        ("DAILY", _) => secs(86400),
+        ("WEEKLY", _) => secs(7 * 86400),
+        ("MONTHLY", _) => Every::Month(interval),
+        ("YEARLY", _) => Every::Month(12 * interval),
+        _ => return Err(format!("FREQ={} isn't supported", freq)),
+    };
+    let stop = match (number("COUNT")?, parts.get("UNTIL")) {
+        (Some(count), _) => Stop::Count(count as i32),
+        (None, Some(until)) => Stop::After(parse_time(until).ok_or(format!("invalid UNTIL '{}'", until))?),
+        (None, None) => Stop::Nonstop,
+    };
+    // Dates that aren't there, e.g. the 31st of a shorter month, are left out
+    let repeat = Repeated::new(vec![start], every, stop).map_err(|e| e.to_string())?;
+    Ok(repeat.with_overflow(Overflow::Skip))
+}
+
+/// When something happens, from its DTSTART (or whatever stands for it), RRULE, RDATE and EXDATE
+fn occurrences_of(comp: &Component, start: DateTime, warnings: &mut Vec<String>) -> Result<OptRepeated, String> {
+    let rdates = time_list(comp, "RDATE")?;
+    let exdates = time_list(comp, "EXDATE")?;
+    if let Some(rule) = comp.prop("RRULE") {
+        if !rdates.is_empty() {
+            warnings.push("RDATE along with an RRULE isn't supported, only the RRULE is kept".into());
+        }
+        // Stepped on the wall clock of the zone it starts in
+        let zone = comp.prop("DTSTART").or_else(|| comp.prop("DUE")).and_then(zone_of);
+        return Ok(match recurrence(&rule.value, start) {
+            Ok(mut repeat) => {
+                for time in exdates {
+                    repeat.skip_occurrence(time);
+                }
+                OptRepeated::Repeat(repeat.with_zone(zone))
+            }
+            Err(e) => {
+                warnings.push(format!("{}, only the first time is kept", e));
+                OptRepeated::Single(start)
+            }
+        });
+    }
+    let mut times = rdates;
+    times.push(start);
+    times.sort();
+    times.dedup();
+    times.retain(|t| !exdates.contains(t));
+    match (times.first(), times.last()) {
+        (Some(&first), Some(&last)) if times.len() > 1 => {
+            // Happens once at each of the times, the next round is never reached
+            let every = Every::Time((last.0 - first.0 + ChronoDuration::seconds(1)).into());
+            let count = times.len() as i32;
+            let repeat = Repeated::new(times, every, Stop::Count(count)).map_err(|e| e.to_string())?;
+            Ok(OptRepeated::Repeat(repeat))
+        }
+        (Some(&only), _) => Ok(OptRepeated::Single(only)),
+        // With every time left out, it's kept where it started
+        _ => Ok(OptRepeated::Single(start)),
+    }
+}
+
+fn shifted(time: OptRepeated, by: ChronoDuration) -> OptRepeated {
+    match time {
+        OptRepeated::Single(t) => OptRepeated::Single(DateTime(t.0 + by)),
+        OptRepeated::Repeat(r) => OptRepeated::Repeat(r.shifted(by)),
+    }
+}
+
+/// An object read from a calendar
+#[derive(Clone, Debug)]
+pub struct Entry<T> {
+    pub uid: String,
+    pub name: String,
+    pub desc: Option<String>,
+    pub inner: T,
+}
+
+/// What a calendar has in it that objects can be made from
+#[derive(Clone, Debug, Default)]
+pub struct Read {
+    pub events: Vec<Entry<Event>>,
+    pub tasks: Vec<Entry<Task>>,
+    /// (UID of the task, when it was due, when it was completed) of what's been done
+    pub completed: Vec<(String, DateTime, DateTime)>,
+    /// What couldn't be read, or only partly
+    pub warnings: Vec<String>,
+}
+
+fn event(comp: &Component, warnings: &mut Vec<String>) -> Result<Event, String> {
+    let start = time_prop(comp, "DTSTART")?.ok_or("no DTSTART")?;
+    let duration = match (comp.prop("DURATION"), time_prop(comp, "DTEND")?) {
+        (Some(dur), _) => parse_duration(&dur.value).ok_or_else(|| format!("invalid DURATION '{}'", dur.value))?,
+        (None, Some(end)) => end.0 - start.0,
+        // A whole day when it's on a date, otherwise it takes no time
+        (None, None) if comp.prop("DTSTART").and_then(|p| p.param("VALUE")) == Some("DATE") => ChronoDuration::days(1),
+        (None, None) => ChronoDuration::zero(),
+    };
+    Ok(Event::new(occurrences_of(comp, start, warnings)?, duration.into()))
+}
+
+fn task(comp: &Component, warnings: &mut Vec<String>) -> Result<Task, String> {
+    let (start, due) = (time_prop(comp, "DTSTART")?, time_prop(comp, "DUE")?);
+    let due = due.or(start).ok_or("neither DUE nor DTSTART")?;
+    // A recurrence is anchored on DTSTART, and each occurrence is due as long after it as DUE is
+    let anchor = start.unwrap_or(due);
+    let deadline = shifted(occurrences_of(comp, anchor, warnings)?, due.0 - anchor.0);
+    let priority = match comp.prop("PRIORITY") {
+        Some(p) => p
+            .value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid PRIORITY '{}'", p.value))?,
+        None => 0,
+    };
+    let mut task = Task::new(deadline, priority, Vec::new());
+    for alarm in comp.subs.iter().filter(|c| c.name == "VALARM") {
+        let trigger = match alarm.prop("TRIGGER") {
+            Some(trigger) => trigger,
+            None => continue,
+        };
+        let before_due = if trigger.param("VALUE") == Some("DATE-TIME") {
+            parse_time(&trigger.value).map(|t| t.0 - due.0)
+        } else {
+            // Relative to the start unless said otherwise
+            let from_start = trigger.param("RELATED") != Some("END");
+            parse_duration(&trigger.value).map(|d| if from_start { d + (anchor.0 - due.0) } else { d })
+        };
+        match before_due {
+            Some(dur) => task.notifications.push(dur.into()),
+            None => warnings.push(format!("invalid TRIGGER '{}', the alarm is left out", trigger.value)),
+        }
+    }
+    Ok(task)
+}
+
+fn completed(comp: &Component) -> Option<DateTime> {
+    let done = comp
+        .prop("STATUS")
+        .is_some_and(|s| s.value.eq_ignore_ascii_case("COMPLETED"));
+    match time_prop(comp, "COMPLETED").ok().flatten() {
+        Some(time) => Some(time),
+        None if done => Some(DateTime::now()),
+        None => None,
+    }
+}
+
+/// Finds the events and tasks in a calendar. A VTODO related to another VTODO of the calendar is a sub task of it,
+/// which are made by the task itself, so only whether it has been completed is taken from it.
+pub fn read(s: &str) -> Result<Read, ReadError> {
+    let top = parse(s)?;
+    let comps = top
+        .iter()
+        .filter(|c| c.name == "VCALENDAR")
+        .flat_map(|c| &c.subs)
+        .filter(|c| c.name == "VEVENT" || c.name == "VTODO")
+        .collect::<Vec<_>>();
+    let todos = comps
+        .iter()
+        .filter(|c| c.name == "VTODO")
+        .filter_map(|c| c.text("UID"))
+        .collect::<Vec<_>>();
+    let mut read = Read::default();
+    for comp in comps {
+        let uid = match comp.text("UID") {
+            Some(uid) => uid,
+            None => {
+                read.warnings.push(format!("{} without a UID is left out", comp.name));
+                continue;
+            }
+        };
+        let mut warnings = Vec::new();
+        let parent = comp
+            .props
+            .iter()
+            .filter(|p| p.name == "RELATED-TO" && p.param("RELTYPE").is_none_or(|r| r == "PARENT"))
+            .map(|p| unescape(&p.value))
+            .find(|p| todos.contains(p));
+        if let (Some(parent), "VTODO") = (&parent, comp.name.as_str()) {
+            if let (Some(done), Ok(Some(due))) = (completed(comp), time_prop(comp, "DUE")) {
+                read.completed.push((parent.clone(), due, done));
+            }
+            continue;
+        }
+        let name = comp.text("SUMMARY").unwrap_or_default();
+        let desc = comp.text("DESCRIPTION");
+        let res = if comp.name == "VEVENT" {
+            event(comp, &mut warnings).map(|inner| {
+                read.events.push(Entry {
+                    uid: uid.clone(),
+                    name,
+                    desc,
+                    inner,
+                })
+            })
+        } else {
+            task(comp, &mut warnings).map(|inner| {
+                if let (Some(done), OptRepeated::Single(due)) = (completed(comp), &inner.deadline) {
+                    read.completed.push((uid.clone(), *due, done));
+                }
+                read.tasks.push(Entry {
+                    uid: uid.clone(),
+                    name,
+                    desc,
+                    inner,
+                })
+            })
+        };
+        if let Err(e) = res {
+            warnings.push(format!("{}, it's left out", e));
+        }
+        read.warnings
+            .extend(warnings.into_iter().map(|w| format!("{} {}: {}", comp.name, uid, w)));
+    }
+    Ok(read)
+}
+
+/// Updates what the new entry has to say about an object created from the same UID before
+fn update<O: ApiObj>(
+    store: &Storage,
+    old: &Obj<O>,
+    new: &Entry<O>,
+    inner: O,
+    report: &mut ImportReport,
+) -> storage::Result<()> {
+    store.set_obj(old.id, inner)?;
+    if old.desc != new.desc {
+        store.obj_set_desc(old.id, new.desc.clone())?;
+    }
+    if old.name != new.name {
+        report.warnings.push(format!(
+            "{} is now called '{}', names can't be changed",
+            old.id, new.name
+        ));
+    }
+    report.updated.push(old.id);
+    Ok(())
+}
+
+/// Creates the objects in a calendar, or updates them if they were imported before. The changes are grouped, so that
+/// the import is undone as one, and undone again if it fails halfway.
+pub fn import(store: &Storage, cal: &str) -> Result<ImportReport, ReadError> {
+    let read = read(cal)?;
+    let report = store.group("import calendar", || import_read(store, read))?;
+    store.flush()?;
+    Ok(report)
+}
+
+fn import_read(store: &Storage, read: Read) -> storage::Result<ImportReport> {
+    let mut report = ImportReport {
+        warnings: read.warnings,
+        ..ImportReport::default()
+    };
+    let attrs = |uid: &str| {
+        let mut attrs = Attrs::new();
+        attrs.insert(UID_ATTR.into(), uid.into());
+        Some(attrs)
+    };
+
+    let events = by_attr::<Event>(store, UID_ATTR)?;
+    for new in read.events {
+        match events.get(&new.uid) {
+            Some(old) => {
+                let mut event = new.inner.clone();
+                // Keeps what's been skipped or moved here if the recurrence hasn't changed
+                if same_time(&old.inner.start, &event.start) {
+                    event.start = old.inner.start.clone();
+                }
+                update(store, old, &new, event, &mut report)?;
+            }
+            None => {
+                let Event { start, duration } = new.inner;
+                let id = store.create_event(new.name, start, duration, new.desc, attrs(&new.uid))?;
+                report.created.push(id);
+            }
+        }
+    }
+
+    let mut tasks = by_attr::<Task>(store, UID_ATTR)?;
+    let mut task_ids = HashMap::new();
+    for new in read.tasks {
+        let id = match tasks.remove(&new.uid) {
+            Some(old) => {
+                let mut task = old.inner.clone();
+                task.priority = new.inner.priority;
+                set_deadline(store, old.id, &mut task, &new.inner.deadline, &mut report)?;
+                update(store, &old, &new, task, &mut report)?;
+                old.id
+            }
+            None => {
+                let Task { deadline, priority, .. } = new.inner.clone();
+                let id = store.create_task(new.name.clone(), new.desc.clone(), attrs(&new.uid), deadline, priority)?;
+                report.created.push(id);
+                id
+            }
+        };
+        if store.get_obj::<Task>(id)?.inner.notifications != new.inner.notifications {
+            store.task_set_notifications(id, new.inner.notifications)?;
+        }
+        task_ids.insert(new.uid, id);
+    }
+
+    // In order, as finishing one is what makes the next of a recurrence
+    let mut completed = read.completed;
+    completed.sort_by_key(|&(_, due, _)| due);
+    for (uid, due, done) in completed {
+        if let Some(&id) = task_ids.get(&uid) {
+            if !finish_due(store, id, due, done, &mut report)? {
+                report.warnings.push(format!(
+                    "VTODO {} was completed when due at {}, which {} has no sub task for",
+                    uid,
+                    fmt_time(due),
+                    id
+                ));
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn time(d: u32, h: u32) -> DateTime {
+        DateTime(Utc.with_ymd_and_hms(2021, 1, d, h, 0, 0).unwrap().into())
+    }
+
+    fn obj<T: ApiObj>(id: IdType, name: &str, inner: T) -> Obj<T> {
+        Obj {
+            id: ObjId(id),
+            inner,
+            name: name.into(),
+            desc: None,
+            attrs: None,
+        }
+    }
+
+    fn write_str(cal: &Calendar) -> String {
+        let mut out = Vec::new();
+        write(&mut out, cal, time(1, 0)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(fmt_duration(ChronoDuration::minutes(-15)), "-PT15M");
+        assert_eq!(fmt_duration(ChronoDuration::weeks(2)), "P2W");
+        assert_eq!(fmt_duration(ChronoDuration::seconds(90061)), "P1DT1H1M1S");
+    }
+
+    #[test]
+    fn test_write() {
+        let weekly = Repeated::new(
+            vec![time(4, 10)],
+            Every::Time(ChronoDuration::weeks(1).into()),
+            Stop::Count(3),
+        )
+        .unwrap();
+        let twice = Repeated::new(
+            vec![time(4, 10), time(6, 12)],
+            Every::Time(ChronoDuration::weeks(1).into()),
+            Stop::Count(3),
+        )
+        .unwrap();
+        let mut event = obj(
+            1,
+            "standup, daily",
+            Event::new(OptRepeated::Repeat(weekly), ChronoDuration::minutes(30).into()),
+        );
+        event.desc = Some("a long line ".repeat(10));
+        let events = [
+            event,
+            obj(
+                2,
+                "gym",
+                Event::new(OptRepeated::Repeat(twice), ChronoDuration::hours(1).into()),
+            ),
+        ];
+        let mut task = Task::new(OptRepeated::Single(time(8, 0)), 1, Vec::new());
+        task.notifications.push(ChronoDuration::hours(-1).into());
+        let tasks = [obj(3, "report", task)];
+        let mut sub = SubTask::new(ObjId(3), time(8, 0), Vec::new());
+        sub.finished = Some(time(7, 0));
+        let subs = [obj(4, "report", sub)];
+        let ics = write_str(&Calendar {
+            events: &events,
+            tasks: &tasks,
+            subs: &subs,
+        });
+        assert!(ics.contains("SUMMARY:standup\\, daily\r\n"));
+        assert!(ics.contains("DTSTART:20210104T100000Z\r\nRRULE:FREQ=WEEKLY;INTERVAL=1;COUNT=3\r\n"));
+        assert!(ics.contains("RDATE:20210106T120000Z,20210111T100000Z\r\n"));
+        assert!(ics.contains("TRIGGER;RELATED=END:-PT1H\r\n"));
+        assert!(ics.contains("RELATED-TO;RELTYPE=PARENT:O3@sched\r\nSTATUS:COMPLETED\r\n"));
+        assert!(ics.lines().all(|l| l.len() <= 76));
+        assert_eq!(ics.matches("BEGIN:VTODO").count(), 2);
+    }
+
+    #[test]
+    fn test_zone() {
+        let cal = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+UID:run@example.com\r
+DTSTART;TZID=Europe/Paris:20210327T080000\r
+RRULE:FREQ=DAILY;COUNT=3\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+        let read = read(cal).unwrap();
+        let start = read.events[0].inner.start.clone();
+        let times = match &start {
+            OptRepeated::Repeat(repeat) => repeat.iter().collect::<Vec<_>>(),
+            _ => panic!("not repeated"),
+        };
+        // At 8 on the wall clock, on either side of the clocks going forward
+        let utc = |d, h| DateTime(Utc.with_ymd_and_hms(2021, 3, d, h, 0, 0).unwrap().into());
+        assert_eq!(times, vec![utc(27, 7), utc(28, 6), utc(29, 6)]);
+        let events = [obj(1, "run", Event::new(start, ChronoDuration::hours(1).into()))];
+        let ics = write_str(&Calendar {
+            events: &events,
+            tasks: &[],
+            subs: &[],
+        });
+        assert!(ics.contains("DTSTART;TZID=Europe/Paris:20210327T080000\r\nRRULE:FREQ=DAILY;INTERVAL=1;COUNT=3\r\n"));
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let cal = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+UID:standup@example.com\r
+DTSTART:20210104T100000Z\r
+RRULE:FREQ=DAILY;COUNT=4\r
+EXDATE:20210105T100000Z\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+        let read = read(cal).unwrap();
+        assert!(read.warnings.is_empty());
+        let mut repeat = match &read.events[0].inner.start {
+            OptRepeated::Repeat(repeat) => repeat.clone(),
+            _ => panic!("not repeated"),
+        };
+        // Left out, but still counted
+        assert_eq!(
+            repeat.iter().collect::<Vec<_>>(),
+            vec![time(4, 10), time(6, 10), time(7, 10)]
+        );
+
+        repeat.move_occurrence(time(6, 10), time(6, 15));
+        let start = OptRepeated::Repeat(repeat);
+        let events = [obj(1, "standup", Event::new(start, ChronoDuration::minutes(15).into()))];
+        let ics = write_str(&Calendar {
+            events: &events,
+            tasks: &[],
+            subs: &[],
+        });
+        assert!(ics.contains("RRULE:FREQ=DAILY;INTERVAL=1;COUNT=4\r\n"));
+        assert!(ics.contains("EXDATE:20210105T100000Z,20210106T100000Z\r\nRDATE:20210106T150000Z\r\n"));
+    }
+
+    const CAL: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VEVENT\r
+UID:standup@example.com\r
+SUMMARY:standup\\, daily\r
+DESCRIPTION:first line\\nsecond\r
+  line\r
+DTSTART:20210104T100000Z\r
+DTEND:20210104T101500Z\r
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=4\r
+END:VEVENT\r
+BEGIN:VTODO\r
+UID:report@example.com\r
+SUMMARY:report\r
+DUE:20210108T000000Z\r
+PRIORITY:2\r
+BEGIN:VALARM\r
+TRIGGER;RELATED=END:-PT1H\r
+END:VALARM\r
+END:VTODO\r
+BEGIN:VTODO\r
+UID:report-done@example.com\r
+RELATED-TO;RELTYPE=PARENT:report@example.com\r
+DUE:20210108T000000Z\r
+STATUS:COMPLETED\r
+COMPLETED:20210107T120000Z\r
+END:VTODO\r
+BEGIN:VEVENT\r
+UID:odd@example.com\r
+DTSTART:20210104T100000Z\r
+RRULE:FREQ=DAILY;BYHOUR=9,17\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(parse_duration("-PT15M"), Some(ChronoDuration::minutes(-15)));
+        assert_eq!(parse_duration("P1DT1H1M1S"), Some(ChronoDuration::seconds(90061)));
+        assert_eq!(parse_duration("P2W"), Some(ChronoDuration::weeks(2)));
+        assert_eq!(parse_duration("P"), None);
+        assert_eq!(parse_duration("PT1D"), None);
+        assert_eq!(parse_time("20210104T100000Z"), Some(time(4, 10)));
+        let prop = content_line("ATTENDEE;ROLE=REQ;CN=\"Doe; John\":mailto:j@example.com").unwrap();
+        assert_eq!(prop.param("CN"), Some("Doe; John"));
+        assert_eq!(prop.value, "mailto:j@example.com");
+        assert!(matches!(parse("BEGIN:VCALENDAR\r\n"), Err(ReadError::Syntax(1, _))));
+
+        let rule = "FREQ=MONTHLY;INTERVAL=2;BYMONTHDAY=-1,15;BYDAY=+1MO,FR;BYSETPOS=-1";
+        let repeat = recurrence(rule, time(4, 10)).unwrap();
+        assert_eq!(
+            rrule(&repeat).as_deref(),
+            Some("FREQ=MONTHLY;INTERVAL=2;BYMONTHDAY=-1,15;BYDAY=1MO,FR;BYSETPOS=-1")
+        );
+        assert!(recurrence("FREQ=HOURLY;BYDAY=MO", time(4, 10)).is_err());
+        assert!(recurrence("FREQ=YEARLY;BYMONTH=-1", time(4, 10)).is_err());
+
+        // Months without the 31st are left out of an RRULE, and picking the last day of the month clamps
+        let repeat = recurrence("FREQ=MONTHLY", time(31, 9)).unwrap();
+        assert_eq!(repeat.overflow(), Overflow::Skip);
+        assert_eq!(rrule(&repeat).as_deref(), Some("FREQ=MONTHLY;INTERVAL=1"));
+        let repeat = Repeated::new(vec![time(31, 9)], Every::Month(1), Stop::Nonstop).unwrap();
+        assert_eq!(
+            rrule(&repeat).as_deref(),
+            Some("FREQ=MONTHLY;INTERVAL=1;BYMONTHDAY=28,29,30,31;BYSETPOS=-1")
+        );
+        assert_eq!(rrule(&repeat.with_overflow(Overflow::Roll)), None);
+    }
+
+    #[test]
+    fn test_read() {
+        let read = read(CAL).unwrap();
+        assert_eq!(read.events.len(), 2);
+        let standup = &read.events[0];
+        assert_eq!(standup.name, "standup, daily");
+        assert_eq!(standup.desc.as_deref(), Some("first line\nsecond line"));
+        assert_eq!(standup.inner.duration, ChronoDuration::minutes(15).into());
+        let times = match &standup.inner.start {
+            OptRepeated::Repeat(repeat) => repeat.iter().collect::<Vec<_>>(),
+            _ => panic!("not repeated"),
+        };
+        assert_eq!(times, vec![time(4, 10), time(6, 10), time(11, 10), time(13, 10)]);
+        // What can't be said is left as a single time
+        assert!(matches!(read.events[1].inner.start, OptRepeated::Single(_)));
+        assert!(read.warnings.iter().any(|w| w.contains("BYHOUR=9,17")));
+
+        assert_eq!(read.tasks.len(), 1);
+        let report = &read.tasks[0].inner;
+        assert_eq!(report.priority, 2);
+        assert_eq!(report.notifications, vec![ChronoDuration::hours(-1).into()]);
+        assert_eq!(
+            read.completed,
+            vec![("report@example.com".into(), time(8, 0), time(7, 12))]
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let store = Storage::in_memory().unwrap();
+        let report = import(&store, CAL).unwrap();
+        assert_eq!((report.created.len(), report.updated.len()), (3, 0));
+        assert_eq!(report.finished.len(), 1);
+        let sub: Obj<SubTask> = store.get_obj(report.finished[0]).unwrap();
+        assert_eq!(sub.inner.finished, Some(time(7, 12)));
+        assert_eq!(sub.inner.notifications, vec![ChronoDuration::hours(-1).into()]);
+
+        let changed = CAL.replace("PRIORITY:2", "PRIORITY:5");
+        let report = import(&store, &changed).unwrap();
+        assert_eq!((report.created.len(), report.updated.len()), (0, 3));
+        assert!(report.finished.is_empty());
+        let tasks = store.find_obj::<Task, _>(|_| true, None).unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].inner.priority, 5);
+        assert_eq!(store.find_obj::<Event, _>(|_| true, None).unwrap().len(), 2);
+
+        // Both imports are undone as one step each
+        store.undo(store.last_undoable(false).unwrap().unwrap()).unwrap();
+        assert_eq!(store.find_obj::<Task, _>(|_| true, None).unwrap()[0].inner.priority, 2);
+        let undo = store.undo(store.last_undoable(false).unwrap().unwrap()).unwrap();
+        assert!(store.find_obj::<Task, _>(|_| true, None).unwrap().is_empty());
+        assert!(store.find_obj::<SubTask, _>(|_| true, None).unwrap().is_empty());
+        assert!(store.find_obj::<Event, _>(|_| true, None).unwrap().is_empty());
+        store.redo(undo).unwrap();
+        assert_eq!(store.find_obj::<SubTask, _>(|_| true, None).unwrap().len(), 1);
+        assert_eq!(store.find_obj::<Event, _>(|_| true, None).unwrap().len(), 2);
+    }
+}
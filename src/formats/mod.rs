@@ -0,0 +1,100 @@
+//! Formats of other programs that the objects can be written out to or read in from
+
+pub mod agenda;
+pub mod ics;
+pub mod taskwarrior;
+
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+
+use crate::storage::{self, api::*, time::DateTime, OptRepeated, Storage};
+
+/// What importing from another program has done
+#[derive(Clone, Debug, Default)]
+pub struct ImportReport {
+    pub created: Vec<ObjId>,
+    pub updated: Vec<ObjId>,
+    /// The sub tasks finished because they were done in the other program
+    pub finished: Vec<ObjId>,
+    /// What couldn't be imported, or only partly
+    pub warnings: Vec<String>,
+}
+
+/// The objects of type `O` imported before, by the ID the other program knows them by, which is kept in `attr`
+fn by_attr<O: ApiObj + DeserializeOwned>(store: &Storage, attr: &str) -> storage::Result<HashMap<String, Obj<O>>> {
+    Ok(store
+        .find_obj_old::<O, _>(|_| true, None)?
+        .into_iter()
+        .filter_map(|o| {
+            let key = o.attrs.as_ref()?.get(attr)?.as_str()?.to_string();
+            Some((key, o))
+        })
+        .collect())
+}
+
+/// Finishes the sub task of `task` that's due at `due`, as it was done at `done` elsewhere. False if the task has no
+/// such sub task, e.g. when it's further along a recurrence than has been generated.
+fn finish_due(
+    store: &Storage,
+    task: ObjId,
+    due: DateTime,
+    done: DateTime,
+    report: &mut ImportReport,
+) -> storage::Result<bool> {
+    let mut found = false;
+    for sub_id in store.get_obj::<Task>(task)?.inner.cache {
+        let sub = store.get_obj::<SubTask>(sub_id)?.inner;
+        if sub.deadline == due {
+            found = true;
+            // Done already if it's been imported before
+            if sub.finished.is_none() {
+                store.task_finish(sub_id, done)?;
+                report.finished.push(sub_id);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Whether two times describe the same recurrence, whatever's been skipped or moved
+fn same_time(a: &OptRepeated, b: &OptRepeated) -> bool {
+    match (a, b) {
+        (OptRepeated::Single(a), OptRepeated::Single(b)) => a == b,
+        (OptRepeated::Repeat(a), OptRepeated::Repeat(b)) => {
+            a.start() == b.start() && a.every() == b.every() && a.stop() == b.stop()
+        }
+        _ => false,
+    }
+}
+
+/// Has `task`, the one stored as `id`, be due at `deadline` from now on if that's another time. The unfinished sub
+/// tasks of a task due once are moved along with it; those of a recurrence are kept where they are.
+fn set_deadline(
+    store: &Storage,
+    id: ObjId,
+    task: &mut Task,
+    deadline: &OptRepeated,
+    report: &mut ImportReport,
+) -> storage::Result<()> {
+    if same_time(&task.deadline, deadline) {
+        return Ok(());
+    }
+    if let (OptRepeated::Repeat(_), false) = (&task.deadline, task.cache.is_empty()) {
+        report.warnings.push(format!(
+            "{} is due at other times now, its sub tasks so far are kept",
+            id
+        ));
+    }
+    task.deadline = deadline.clone();
+    if let OptRepeated::Single(due) = task.deadline {
+        for &sub_id in &task.cache {
+            let mut sub = store.get_obj::<SubTask>(sub_id)?.inner;
+            if sub.finished.is_none() {
+                sub.deadline = due;
+                store.set_obj(sub_id, sub)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,386 @@
+//! Taskwarrior's `task export`, for moving a task list over. Each task becomes a `Task`, with its tags,
+//! annotations and project in attrs along with its UUID, so that importing a later export again only updates it.
+//! The instances of a recurring task are left to the task it becomes, only when they were completed is kept.
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::Duration as ChronoDuration;
+use thiserror::Error;
+
+use super::{by_attr, finish_due, ics, set_deadline, ImportReport};
+use crate::storage::{self, api::*, rule::Overflow, time::DateTime, Every, OptRepeated, Repeated, Stop, Storage};
+
+/// The attr an imported task keeps its UUID in
+pub const UUID_ATTR: &str = "taskwarrior-uuid";
+
+#[derive(Debug, Error)]
+pub enum ReadError {
+    #[error("not a Taskwarrior export: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Storage(#[from] storage::Error),
+}
+
+/// A task as `task export` writes it, with only what's imported
+#[derive(Clone, Debug, Deserialize)]
+pub struct TwTask {
+    pub uuid: String,
+    pub description: String,
+    /// `pending`, `waiting`, `completed`, `deleted` or `recurring`
+    pub status: String,
+    pub entry: Option<String>,
+    pub due: Option<String>,
+    pub end: Option<String>,
+    pub until: Option<String>,
+    pub recur: Option<String>,
+    /// The recurring task this is an instance of
+    pub parent: Option<String>,
+    /// `H`, `M` or `L`
+    pub priority: Option<String>,
+    pub project: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Annotation {
+    pub entry: String,
+    pub description: String,
+}
+
+/// Reads the tasks of an export, either one JSON array or one task per line like older versions write them
+pub fn read(s: &str) -> Result<Vec<TwTask>, ReadError> {
+    let mut tasks = Vec::new();
+    for value in serde_json::Deserializer::from_str(s).into_iter::<AttrValue>() {
+        match value? {
+            AttrValue::Array(values) => {
+                for value in values {
+                    tasks.push(serde_json::from_value(value)?);
+                }
+            }
+            value => tasks.push(serde_json::from_value(value)?),
+        }
+    }
+    Ok(tasks)
+}
+
+fn time(name: &str, s: &Option<String>) -> Result<Option<DateTime>, String> {
+    s.as_ref()
+        .map(|t| ics::parse_time(t).ok_or_else(|| format!("invalid {} '{}'", name, t)))
+        .transpose()
+}
+
+/// A `recur` value, like `weekly`, `2w` or `P3D`
+fn every(recur: &str) -> Option<Every> {
+    let days = |n: u32| Every::Time(ChronoDuration::days(n.into()).into());
+    let named = match recur {
+        "daily" | "day" => Some(days(1)),
+        "weekly" | "week" => Some(days(7)),
+        "biweekly" | "fortnight" => Some(days(14)),
+        "monthly" | "month" => Some(Every::Month(1)),
+        "bimonthly" => Some(Every::Month(2)),
+        "quarterly" => Some(Every::Month(3)),
+        "semiannual" => Some(Every::Month(6)),
+        "annual" | "yearly" | "year" => Some(Every::Month(12)),
+        "biannual" | "biyearly" => Some(Every::Month(24)),
+        _ => None,
+    };
+    if named.is_some() {
+        return named;
+    }
+    if recur.starts_with('P') {
+        return ics::parse_duration(recur)
+            .filter(|d| *d > ChronoDuration::zero())
+            .map(|d| Every::Time(d.into()));
+    }
+    let split = recur.find(|c: char| !c.is_ascii_digit())?;
+    let n = match &recur[..split] {
+        "" => 1,
+        n => n.parse::<u32>().ok().filter(|&n| n > 0)?,
+    };
+    let secs = |unit: i64| Every::Time(ChronoDuration::seconds(unit * i64::from(n)).into());
+    match &recur[split..] {
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(secs(1)),
+        "min" | "mins" | "minute" | "minutes" => Some(secs(60)),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(secs(3600)),
+        "d" | "day" | "days" => Some(days(n)),
+        "w" | "wk" | "wks" | "week" | "weeks" => Some(days(7 * n)),
+        "mo" | "mos" | "month" | "months" => Some(Every::Month(n)),
+        "q" | "qtr" | "qtrs" | "quarter" | "quarters" => Some(Every::Month(3 * n)),
+        "y" | "yr" | "yrs" | "year" | "years" => Some(Every::Month(12 * n)),
+        _ => None,
+    }
+}
+
+/// Ordered like iCalendar's PRIORITY, from 1 for the highest to 9 for the lowest, and 0 for none
+fn priority(priority: &Option<String>) -> Result<u32, String> {
+    match priority.as_deref() {
+        None | Some("") => Ok(0),
+        Some("H") => Ok(1),
+        Some("M") => Ok(5),
+        Some("L") => Ok(9),
+        Some(p) => Err(format!("invalid priority '{}'", p)),
+    }
+}
+
+fn attrs(tw: &TwTask) -> Attrs {
+    let mut attrs = Attrs::new();
+    attrs.insert(UUID_ATTR.into(), tw.uuid.clone().into());
+    if let Some(project) = &tw.project {
+        attrs.insert("project".into(), project.clone().into());
+    }
+    if !tw.tags.is_empty() {
+        attrs.insert("tags".into(), tw.tags.clone().into());
+    }
+    if !tw.annotations.is_empty() {
+        let annotations = tw
+            .annotations
+            .iter()
+            .map(|a| {
+                // In the same form as the times of the store when it can be read
+                let entry = ics::parse_time(&a.entry)
+                    .and_then(|t| serde_json::to_value(t).ok())
+                    .unwrap_or_else(|| a.entry.clone().into());
+                serde_json::json!({ "entry": entry, "description": a.description })
+            })
+            .collect::<Vec<_>>();
+        attrs.insert("annotations".into(), annotations.into());
+    }
+    attrs
+}
+
+/// The task `tw` becomes. Without a due date it's due from when it was entered.
+fn task(tw: &TwTask, warnings: &mut Vec<String>) -> Result<Task, String> {
+    let due = match (time("due", &tw.due)?, time("entry", &tw.entry)?) {
+        (Some(due), _) | (None, Some(due)) => due,
+        (None, None) => DateTime::now(),
+    };
+    let deadline = match &tw.recur {
+        Some(recur) if tw.due.is_some() => {
+            let stop = time("until", &tw.until)?.map_or(Stop::Nonstop, Stop::After);
+            match every(recur).map(|every| Repeated::new(vec![due], every, stop)) {
+                // Taskwarrior puts what's due past the end of a month on its last day
+                Some(Ok(repeat)) => OptRepeated::Repeat(repeat.with_overflow(Overflow::Clamp)),
+                Some(Err(e)) => {
+                    warnings.push(format!("recurrence '{}' can't be kept: {}", recur, e));
+                    OptRepeated::Single(due)
+                }
+                None => {
+                    warnings.push(format!("recurrence '{}' isn't supported", recur));
+                    OptRepeated::Single(due)
+                }
+            }
+        }
+        _ => OptRepeated::Single(due),
+    };
+    Ok(Task::new(deadline, priority(&tw.priority)?, Vec::new()))
+}
+
+/// When `tw` was completed, if it has been
+fn completed(tw: &TwTask) -> Result<Option<DateTime>, String> {
+    if tw.status != "completed" {
+        return Ok(None);
+    }
+    Ok(Some(time("end", &tw.end)?.unwrap_or_else(DateTime::now)))
+}
+
+/// Updates a task imported before with what's changed since
+fn update(store: &Storage, old: &Obj<Task>, tw: &TwTask, new: Task, report: &mut ImportReport) -> storage::Result<()> {
+    let mut task = old.inner.clone();
+    task.priority = new.priority;
+    set_deadline(store, old.id, &mut task, &new.deadline, report)?;
+    store.set_obj(old.id, task)?;
+    let (old_attrs, new_attrs) = (old.attrs.clone().unwrap_or_default(), attrs(tw));
+    for (key, val) in &new_attrs {
+        if old_attrs.get(key) != Some(val) {
+            store.obj_set_attr(old.id, key.clone(), val.clone())?;
+        }
+    }
+    for key in ["project", "tags", "annotations"].iter() {
+        if old_attrs.contains_key(*key) && !new_attrs.contains_key(*key) {
+            store.obj_del_attr(old.id, key.to_string())?;
+        }
+    }
+    if old.name != tw.description {
+        report.warnings.push(format!(
+            "{} is now called '{}', names can't be changed",
+            old.id, tw.description
+        ));
+    }
+    report.updated.push(old.id);
+    Ok(())
+}
+
+/// Creates the tasks of an export, or updates them if they were imported before
+pub fn import(store: &Storage, export: &str) -> Result<ImportReport, ReadError> {
+    let tws = read(export)?;
+    let mut report = ImportReport::default();
+    let uuids = tws.iter().map(|tw| tw.uuid.clone()).collect::<HashSet<_>>();
+    let (instances, tws): (Vec<_>, Vec<_>) = tws
+        .into_iter()
+        .partition(|tw| tw.parent.as_ref().is_some_and(|p| uuids.contains(p)));
+
+    let mut existing = by_attr::<Task>(store, UUID_ATTR)?;
+    let mut ids = HashMap::new();
+    // (UUID of the task, when it was due, when it was completed)
+    let mut completed = Vec::new();
+    for tw in tws.iter().filter(|tw| tw.status != "deleted") {
+        let mut warnings = Vec::new();
+        let res = task(tw, &mut warnings).and_then(|task| Ok((task, self::completed(tw)?)));
+        let (task, done) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                warnings.push(format!("{}, it's left out", e));
+                report
+                    .warnings
+                    .extend(warnings.into_iter().map(|w| format!("{}: {}", tw.uuid, w)));
+                continue;
+            }
+        };
+        report
+            .warnings
+            .extend(warnings.into_iter().map(|w| format!("{}: {}", tw.uuid, w)));
+        if let (Some(done), OptRepeated::Single(due)) = (done, &task.deadline) {
+            completed.push((tw.uuid.clone(), *due, done));
+        }
+        let id = match existing.remove(&tw.uuid) {
+            Some(old) => {
+                update(store, &old, tw, task, &mut report)?;
+                old.id
+            }
+            None => {
+                let Task { deadline, priority, .. } = task;
+                let id = store.create_task(tw.description.clone(), None, Some(attrs(tw)), deadline, priority)?;
+                report.created.push(id);
+                id
+            }
+        };
+        ids.insert(tw.uuid.clone(), id);
+    }
+    for tw in &instances {
+        let due = time("due", &tw.due).ok().flatten();
+        if let (Some(parent), Some(due), Ok(Some(done))) = (&tw.parent, due, self::completed(tw)) {
+            completed.push((parent.clone(), due, done));
+        }
+    }
+
+    // In order, as finishing one is what makes the next of a recurrence
+    completed.sort_by_key(|&(_, due, _)| due);
+    for (uuid, due, done) in completed {
+        if let Some(&id) = ids.get(&uuid) {
+            if !finish_due(store, id, due, done, &mut report)? {
+                report.warnings.push(format!(
+                    "{} was completed when due at {}, which {} has no sub task for",
+                    uuid,
+                    ics::fmt_time(due),
+                    id
+                ));
+            }
+        }
+    }
+    store.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    const EXPORT: &str = r#"[
+{"id":0,"description":"pay rent","due":"20210201T000000Z","end":"20210130T101010Z","entry":"20210101T000000Z","status":"completed","uuid":"a","priority":"H","project":"home","tags":["money","home"],"annotations":[{"entry":"20210102T000000Z","description":"paid online"}]},
+{"id":1,"description":"water plants","due":"20210104T090000Z","entry":"20210101T000000Z","recur":"2d","status":"recurring","uuid":"b","until":"20210110T090000Z"},
+{"id":0,"description":"water plants","due":"20210104T090000Z","end":"20210104T080000Z","parent":"b","recur":"2d","status":"completed","uuid":"c"},
+{"id":2,"description":"water plants","due":"20210106T090000Z","parent":"b","recur":"2d","status":"pending","uuid":"d"},
+{"id":0,"description":"old","entry":"20201201T000000Z","status":"deleted","uuid":"e"},
+{"id":3,"description":"someday","entry":"20201201T000000Z","status":"pending","uuid":"f","priority":"L"}
+]"#;
+
+    fn time(d: u32, h: u32) -> DateTime {
+        DateTime(Utc.with_ymd_and_hms(2021, 1, d, h, 0, 0).unwrap().into())
+    }
+
+    #[test]
+    fn test_every() {
+        assert_eq!(every("weekly"), Some(Every::Time(ChronoDuration::weeks(1).into())));
+        assert_eq!(every("3mo"), Some(Every::Month(3)));
+        assert_eq!(every("P2D"), Some(Every::Time(ChronoDuration::days(2).into())));
+        assert_eq!(every("weekdays"), None);
+        assert_eq!(every("0d"), None);
+    }
+
+    #[test]
+    fn test_import() {
+        let store = Storage::in_memory().unwrap();
+        let report = import(&store, EXPORT).unwrap();
+        assert_eq!(report.created.len(), 3);
+        assert_eq!(report.finished.len(), 2);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+
+        let rent: Obj<Task> = store.get_obj(report.created[0]).unwrap();
+        assert_eq!(rent.inner.priority, 1);
+        let attrs = rent.attrs.unwrap();
+        assert_eq!(attrs["tags"], serde_json::json!(["money", "home"]));
+        assert_eq!(attrs["annotations"][0]["description"], "paid online");
+        let finish = store
+            .find_log(|l| l.typ == TaskFinish::LOG_TYPE, None, false)
+            .unwrap()
+            .iter()
+            .map(|l| serde_json::from_value::<DateTime>(l.props["finished"].clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert!(finish.contains(&DateTime(Utc.with_ymd_and_hms(2021, 1, 30, 10, 10, 10).unwrap().into())));
+        assert!(finish.contains(&time(4, 8)));
+
+        let plants: Obj<Task> = store.get_obj(report.created[1]).unwrap();
+        let subs = plants
+            .inner
+            .cache
+            .iter()
+            .map(|&id| store.get_obj::<SubTask>(id).unwrap().inner)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            subs.iter().map(|s| s.deadline).collect::<Vec<_>>(),
+            vec![time(4, 9), time(6, 9), time(8, 9), time(10, 9)]
+        );
+        assert_eq!(subs[0].finished, Some(time(4, 8)));
+
+        // Nothing new the second time around
+        let report = import(&store, EXPORT).unwrap();
+        assert_eq!((report.created.len(), report.updated.len()), (0, 3));
+        assert!(report.finished.is_empty());
+    }
+
+    #[test]
+    fn test_reimport_due() {
+        let store = Storage::in_memory().unwrap();
+        let report = import(&store, EXPORT).unwrap();
+        let someday = report.created[2];
+
+        // Due now, and the plants every 3 days
+        let export = EXPORT
+            .replace(
+                r#""entry":"20201201T000000Z","status":"pending""#,
+                r#""due":"20210105T000000Z","entry":"20201201T000000Z","status":"pending""#,
+            )
+            .replace(
+                r#""recur":"2d","status":"recurring""#,
+                r#""recur":"3d","status":"recurring""#,
+            );
+        let report = import(&store, &export).unwrap();
+        assert_eq!(report.updated.len(), 3);
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+
+        let task: Obj<Task> = store.get_obj(someday).unwrap();
+        assert!(matches!(task.inner.deadline, OptRepeated::Single(due) if due == time(5, 0)));
+        let sub = store.get_obj::<SubTask>(task.inner.cache[0]).unwrap().inner;
+        assert_eq!(sub.deadline, time(5, 0));
+        let plants: Obj<Task> = store.get_obj(report.updated[1]).unwrap();
+        match plants.inner.deadline {
+            OptRepeated::Repeat(repeat) => assert_eq!(repeat.iter().nth(1), Some(time(7, 9))),
+            OptRepeated::Single(_) => panic!("the plants should still repeat"),
+        }
+    }
+}
@@ -11,6 +11,8 @@ extern crate thiserror;
 #[macro_use]
 extern crate derive_new;
 
+mod backup;
+mod formats;
 mod handler;
 mod notify;
 #[cfg(features = "repl")]
@@ -25,39 +27,196 @@ mod util;
 mod repl;
 
 use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use dirs::config_dir;
 use tokio::sync::Notify;
 
-use storage::STORE;
+use storage::{
+    api::{Event, SubTask, Task},
+    time::DateTime,
+    STORE,
+};
 
 #[tokio::main(threaded_scheduler)]
 async fn main() {
-    let config_dir = config_dir().unwrap().join("sched");
-    if !config_dir.is_dir() {
-        fs::create_dir_all(&config_dir).unwrap();
+    let config_dir = match config_dir() {
+        Some(dir) => dir.join("sched"),
+        None => {
+            eprintln!("Can't find the config directory");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = fs::create_dir_all(&config_dir) {
+        eprintln!("Can't create {}: {}", config_dir.display(), e);
+        std::process::exit(1);
     }
     let matches = App::new("sched")
         .arg(Arg::with_name("init-file").required(false))
-        .subcommand(App::new("export").arg(Arg::with_name("file").required(true)))
-        .subcommand(App::new("import").arg(Arg::with_name("file").required(true)))
+        .subcommand(
+            App::new("export")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("Where to write to, `-` for stdout"),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("range")
+                        .long("range")
+                        .takes_value(true)
+                        .validator(|s| {
+                            parse_range(&s).map(|_| ()).ok_or_else(|| {
+                                "expected a range like 2021.01.04..2021.01.10 or \
+                                 2021.01.04..09.00.00..2021.01.04..17.00.00"
+                                    .into()
+                            })
+                        })
+                        .help("The days of the agenda, today if it's left out"),
+                )
+                .arg(time_arg("since").help("Only the logs from this time on"))
+                .arg(time_arg("until").help("Only the logs before this time"))
+                .arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .takes_value(true)
+                        .help("Only the objects and logs whose type matches this glob, e.g. `bio.*`"),
+                )
+                .arg(Arg::with_name("objects").long("objects").help("Only the objects"))
+                .arg(
+                    Arg::with_name("logs")
+                        .long("logs")
+                        .conflicts_with("objects")
+                        .help("Only the logs"),
+                ),
+        )
+        .subcommand(
+            App::new("import")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("Where to read from, `-` for stdin"),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix what can be fixed in an inconsistent dump instead of refusing it"),
+                )
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .help("Overwrite a database that already has data in it"),
+                ),
+        )
+        .subcommand(
+            App::new("sync")
+                .about("Merge in another store that diverged from this one")
+                .arg(
+                    Arg::with_name("other")
+                        .required(true)
+                        .help("A dump from `export`, or another database"),
+                ),
+        )
+        .subcommand(
+            App::new("device")
+                .about("Show the device this store hands out IDs on, or move it to another one")
+                .arg(Arg::with_name("device").validator(|s| {
+                    s.parse::<u8>()
+                        .ok()
+                        .filter(|&d| d != 0)
+                        .map(|_| ())
+                        .ok_or_else(|| "expected a device from 1 to 255".into())
+                })),
+        )
+        .subcommand(
+            App::new("rebuild").about("Regenerate the objects from the logs").arg(
+                Arg::with_name("dry-run")
+                    .long("dry-run")
+                    .help("Only report the differences"),
+            ),
+        )
+        .subcommand(
+            App::new("migrate")
+                .about("Upgrade the database to the current schema, or copy it to another backend")
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .possible_values(&["sqlite"]),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .conflicts_with("to")
+                        .help("Only report the records that would be rewritten"),
+                ),
+        )
+        .subcommand(
+            App::new("archive")
+                .about("Move the logs past their retention to the archive")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Only report the logs that would be archived"),
+                ),
+        )
+        .subcommand(
+            App::new("backup")
+                .about("The snapshots taken once a day while sched runs")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(App::new("list").about("List the snapshots, newest first"))
+                .subcommand(
+                    App::new("restore")
+                        .about("Replace everything with a snapshot, after taking one of the current state")
+                        .arg(Arg::with_name("snapshot").required(true)),
+                ),
+        )
+        .subcommand(
+            App::new("rekey")
+                .about("Encrypt the database with a new passphrase, or with a keyfile")
+                .arg(
+                    Arg::with_name("keyfile")
+                        .long("keyfile")
+                        .takes_value(true)
+                        .help("Derive the key from this file instead of asking for a passphrase"),
+                )
+                .arg(
+                    Arg::with_name("decrypt")
+                        .long("decrypt")
+                        .conflicts_with("keyfile")
+                        .help("Leave the database unencrypted"),
+                ),
+        )
         .get_matches();
-    // FIXME handle IO errors
-    match matches.subcommand() {
-        ("export", Some(m)) => {
-            let file = m.value_of("file").unwrap();
-            let data = STORE.export();
-            let mut file = File::create(file).unwrap();
-            serde_json::to_writer_pretty(&mut file, &data).unwrap();
-        }
-        ("import", Some(m)) => {
-            let file = m.value_of("file").unwrap();
-            let content = fs::read_to_string(file).unwrap();
-            STORE.import(&content);
+    let res = match matches.subcommand() {
+        ("export", Some(m)) => export(m),
+        ("import", Some(m)) => import(
+            m.value_of("file").unwrap(),
+            m.value_of("format").unwrap(),
+            m.is_present("repair"),
+            m.is_present("replace"),
+        ),
+        ("sync", Some(m)) => sync(Path::new(m.value_of("other").unwrap())),
+        ("device", Some(m)) => device(m.value_of("device").map(|d| d.parse().unwrap())),
+        ("rebuild", Some(m)) => rebuild(m.is_present("dry-run")),
+        ("migrate", Some(m)) => {
+            if m.is_present("to") {
+                migrate_to_sqlite(&config_dir)
+            } else {
+                migrate_schema(m.is_present("dry-run"))
+            }
         }
+        ("backup", Some(m)) => match m.subcommand() {
+            ("list", _) => backup_list(),
+            ("restore", Some(m)) => backup_restore(m.value_of("snapshot").unwrap()),
+            _ => unreachable!(),
+        },
+        ("archive", Some(m)) => archive(m.is_present("dry-run")),
+        ("rekey", Some(m)) => rekey(m.value_of("keyfile").map(Path::new), m.is_present("decrypt")),
         ("", _) => {
             #[cfg(features = "scripting")]
             let init_file: PathBuf = matches
@@ -65,23 +224,438 @@ async fn main() {
                 .map_or_else(|| config_dir.join("init.glu"), |s| s.into());
             let quit_sig = Arc::new(Notify::new());
             let notify_task = tokio::spawn(notify_loop(quit_sig.clone()));
+            // Each loop needs its own, a notification only wakes one of them
+            let backup_quit_sig = Arc::new(Notify::new());
+            let backup_task = tokio::spawn(backup_loop(
+                config_dir.join(backup::CONFIG_FILE),
+                backup_quit_sig.clone(),
+            ));
             tokio::task::block_in_place(move || {
                 repl_loop(
                     #[cfg(features = "scripting")]
                     &init_file,
                 );
                 quit_sig.notify();
+                backup_quit_sig.notify();
             });
             notify_task.await.unwrap();
+            backup_task.await.unwrap();
+            Ok(())
         }
         _ => unreachable!(),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["json", "jsonl", "ics", "taskwarrior", "org", "md"])
+        .default_value("json")
+        .help(
+            "One JSON document, JSON Lines with one record per line, an iCalendar file, `task export` JSON, or an \
+             agenda in Org mode or Markdown",
+        )
+}
+
+/// A range like `2021.01.04..2021.01.10`, where the times are like `time_arg`'s. A day it ends on is included.
+fn parse_range(s: &str) -> Option<(DateTime, DateTime)> {
+    let mut found = s.match_indices("..").filter_map(|(i, _)| {
+        let (from, to) = (&s[..i], &s[i + 2..]);
+        let mut to_time = DateTime::parse_local(to)?;
+        if !to.contains("..") {
+            to_time = DateTime(to_time.0 + chrono::Duration::days(1));
+        }
+        Some((DateTime::parse_local(from)?, to_time))
+    });
+    match (found.next(), found.next()) {
+        (Some((from, to)), None) if from < to => Some((from, to)),
+        _ => None,
+    }
+}
+
+fn time_arg(name: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name).long(name).takes_value(true).validator(|s| {
+        DateTime::parse_local(&s)
+            .map(|_| ())
+            .ok_or_else(|| "expected a time like 2021.01.31 or 2021.01.31..12.00.00".into())
+    })
+}
+
+fn export(m: &ArgMatches) -> anyhow::Result<()> {
+    let filter = storage::dump::Filter {
+        since: m.value_of("since").and_then(DateTime::parse_local),
+        until: m.value_of("until").and_then(DateTime::parse_local),
+        typ: m.value_of("type").map(String::from),
+        objs: !m.is_present("logs"),
+        logs: !m.is_present("objects"),
+    };
+    let format = m.value_of("format").unwrap();
+    if m.is_present("range") && format != "org" && format != "md" {
+        anyhow::bail!("Only agendas have a range, use --format org or md");
+    }
+    let filtered = ["since", "until", "type", "objects", "logs"]
+        .iter()
+        .any(|f| m.is_present(f));
+    let out: Box<dyn Write> = match m.value_of("file").unwrap() {
+        "-" => Box::new(io::stdout()),
+        file => Box::new(File::create(file)?),
+    };
+    let mut out = BufWriter::new(out);
+    match format {
+        "jsonl" => {
+            STORE.export_lines(&mut out, &filter)?;
+        }
+        "taskwarrior" => anyhow::bail!("Taskwarrior exports can only be imported"),
+        _ if filtered => anyhow::bail!("Only JSON Lines exports can be filtered, use --format jsonl"),
+        "ics" => {
+            // Oldest first, so that the same store always gives the same calendar
+            let events = STORE.find_obj_old::<Event, _>(|_| true, None)?;
+            let tasks = STORE.find_obj_old::<Task, _>(|_| true, None)?;
+            let subs = STORE.find_obj_old::<SubTask, _>(|_| true, None)?;
+            let cal = formats::ics::Calendar {
+                events: &events,
+                tasks: &tasks,
+                subs: &subs,
+            };
+            formats::ics::write(&mut out, &cal, DateTime::now())?;
+        }
+        "org" | "md" => {
+            // Today if it's not said
+            let today = chrono::Local::now().format("%Y.%m.%d").to_string();
+            let range = m
+                .value_of("range")
+                .map_or_else(|| format!("{0}..{0}", today), String::from);
+            let (from, to) = parse_range(&range).unwrap();
+            let style = match format {
+                "org" => formats::agenda::Style::Org,
+                _ => formats::agenda::Style::Markdown,
+            };
+            formats::agenda::Agenda::collect(&STORE, from, to)?.write(&mut out, style)?;
+        }
+        _ => serde_json::to_writer_pretty(&mut out, &STORE.export()?)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn import(file: &str, format: &str, repair: bool, replace: bool) -> anyhow::Result<()> {
+    let mut input: Box<dyn BufRead> = match file {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        file => Box::new(BufReader::new(File::open(file)?)),
+    };
+    let dump = match format {
+        "jsonl" => storage::dump::Dump::read_lines(input)?,
+        "org" | "md" => anyhow::bail!("Agendas can only be exported"),
+        "ics" | "taskwarrior" => {
+            let mut s = String::new();
+            input.read_to_string(&mut s)?;
+            let report = match format {
+                "ics" => formats::ics::import(&STORE, &s)?,
+                _ => formats::taskwarrior::import(&STORE, &s)?,
+            };
+            print_import(&report);
+            return Ok(());
+        }
+        _ => {
+            let mut s = String::new();
+            input.read_to_string(&mut s)?;
+            storage::dump::Dump::parse(&s)?
+        }
+    };
+    let repaired = match STORE.import(dump, repair, replace) {
+        Err(storage::Error::NotEmpty) => {
+            anyhow::bail!("The database already has data in it, pass --replace to overwrite it")
+        }
+        res => res?,
+    };
+    for p in &repaired {
+        println!("Repaired: {}", p);
+    }
+    Ok(())
+}
+
+/// Calendars and task lists are added to what's there rather than replacing it, and update what they were imported
+/// as before
+fn print_import(report: &formats::ImportReport) {
+    for w in &report.warnings {
+        println!("Warning: {}", w);
+    }
+    println!(
+        "Created {} object(s), updated {}, finished {} sub task(s)",
+        report.created.len(),
+        report.updated.len(),
+        report.finished.len()
+    );
+}
+
+fn sync(other: &Path) -> anyhow::Result<()> {
+    let dump = if other.is_dir() {
+        storage::Storage::open(other)?.export()?
+    } else if other.extension().map_or(false, |ext| ext == "json") {
+        storage::dump::Dump::parse(&fs::read_to_string(other)?)?
+    } else {
+        open_sqlite_dump(other)?
+    };
+    let report = STORE.sync(dump)?;
+    for c in &report.conflicts {
+        println!("Conflict: {}", c);
+    }
+    for p in &report.repaired {
+        println!("Repaired: {}", p);
+    }
+    if let Some(device) = report.moved_to {
+        println!("Moved this store's records to device {}", device);
+    }
+    println!(
+        "Pulled {} log(s), {} conflict(s)",
+        report.pulled.len(),
+        report.conflicts.len()
+    );
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite_dump(path: &Path) -> anyhow::Result<storage::dump::Dump> {
+    Ok(storage::Storage::open_sqlite(path)?.export()?)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite_dump(path: &Path) -> anyhow::Result<storage::dump::Dump> {
+    anyhow::bail!(
+        "{} is neither a dump nor a sled database, and sched has been built without the `sqlite` feature",
+        path.display()
+    )
+}
+
+fn device(device: Option<u8>) -> anyhow::Result<()> {
+    if let Some(device) = device {
+        STORE.set_device(device)?;
+    }
+    println!("Device {}", STORE.device()?);
+    Ok(())
+}
+
+fn rebuild(dry_run: bool) -> anyhow::Result<()> {
+    let divergences = STORE.rebuild_from_logs(dry_run)?;
+    for d in &divergences {
+        println!("{}", d);
+    }
+    println!("{} difference(s) from the logs", divergences.len());
+    Ok(())
+}
+
+fn backup_list() -> anyhow::Result<()> {
+    let dir = backup::dir()?;
+    let snapshots = backup::list(&dir)?;
+    for snapshot in &snapshots {
+        let size = fs::metadata(&snapshot.path)?.len();
+        println!("{}  {:>6} KiB", snapshot.name, size.div_ceil(1024));
+    }
+    if snapshots.is_empty() {
+        println!(
+            "No snapshots in {} yet, one is taken each day sched runs",
+            dir.display()
+        );
     }
+    Ok(())
+}
+
+fn backup_restore(name: &str) -> anyhow::Result<()> {
+    let dir = backup::dir()?;
+    let snapshot = backup::find(&dir, name)?;
+    let current = backup::take(&STORE, &dir)?;
+    println!("Took snapshot {} of the current state", current.name);
+    backup::restore(&STORE, &snapshot)?;
+    println!("Restored snapshot {}", snapshot.name);
+    Ok(())
+}
+
+fn archive(dry_run: bool) -> anyhow::Result<()> {
+    let ids = STORE.archive_logs(DateTime::now(), dry_run)?;
+    if STORE.retention().is_empty() {
+        println!(
+            "There are no retention rules, they're set in {}",
+            storage::Storage::user_dir()?
+                .join(storage::archive::RETENTION_FILE)
+                .display()
+        );
+    } else if dry_run {
+        println!("{} log(s) would be archived", ids.len());
+    } else {
+        println!("{} log(s) archived", ids.len());
+    }
+    Ok(())
+}
+
+/// Runs the pending schema migrations on the store of the user, or only reports them with `dry_run`
+fn migrate_schema(dry_run: bool) -> anyhow::Result<()> {
+    // Opened without going through `STORE`, which would migrate it right away
+    let store = storage::Storage::unmigrated(storage::Storage::user_backend()?)?;
+    let version = store.schema_version()?;
+    let reports = store.migrate(dry_run)?;
+    if reports.is_empty() {
+        println!("Schema version {} is up to date", version);
+        return Ok(());
+    }
+    println!("Schema version {} -> {}", version, storage::SCHEMA_VERSION);
+    let mut total = 0;
+    for report in &reports {
+        println!("v{}: {}", report.version, report.desc);
+        for id in &report.rewritten {
+            println!("    {}", id);
+        }
+        total += report.rewritten.len();
+    }
+    if dry_run {
+        println!("{} record(s) would be rewritten", total);
+    } else {
+        println!("{} record(s) rewritten", total);
+    }
+    Ok(())
+}
+
+/// Copies the sled database over to `sched.sqlite`, which `Storage::new` picks up from then on
+#[cfg(feature = "sqlite")]
+fn migrate_to_sqlite(config_dir: &Path) -> anyhow::Result<()> {
+    let path = config_dir.join("sched.sqlite");
+    if path.exists() {
+        anyhow::bail!("{} already exists", path.display());
+    }
+    let store: &storage::Storage = &STORE;
+    // Copied aside first, so that a failed copy doesn't leave behind a database that would be picked up
+    let tmp = config_dir.join("sched.sqlite.tmp");
+    if tmp.exists() {
+        fs::remove_file(&tmp)?;
+    }
+    // Sealed the same as the sled one
+    let sqlite = storage::Storage::sealed_from_env(Box::new(storage::sqlite::SqliteBackend::open(&tmp)?))?;
+    store.copy_to(&*sqlite)?;
+    fs::rename(&tmp, &path)?;
+    println!("Copied the database to {}, which is used from now on", path.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn migrate_to_sqlite(_config_dir: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("sched has been built without the `sqlite` feature")
+}
+
+/// Seals the database of the user with a key from a new secret, unsealing it with the one in the environment first
+#[cfg(feature = "encryption")]
+fn rekey(keyfile: Option<&Path>, decrypt: bool) -> anyhow::Result<()> {
+    use storage::{
+        archive::{Archive, ARCHIVE_FILE},
+        sealed::{self, Secret},
+        Storage,
+    };
+
+    let new = match keyfile {
+        _ if decrypt => None,
+        Some(path) => Some(Secret::Keyfile(path.into())),
+        None => {
+            let pass = rpassword::prompt_password("New passphrase: ")?;
+            if pass.is_empty() {
+                anyhow::bail!("The passphrase can't be empty");
+            }
+            if rpassword::prompt_password("Again: ")? != pass {
+                anyhow::bail!("The passphrases don't match");
+            }
+            Some(Secret::Passphrase(pass))
+        }
+    };
+    // The archive and the backups are sealed with the key as well, so they're read with the old one and written
+    // anew with the new one
+    let archive_path = Storage::user_dir()?.join(ARCHIVE_FILE);
+    let snapshots = backup::list(&backup::dir()?)?;
+    let (archived, unsealed) = {
+        let db = Storage::sealed_from_env(Storage::user_db()?)?;
+        let unsealed = snapshots
+            .iter()
+            .map(|s| Ok(db.unseal_blob(fs::read(&s.path)?)?))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        (Archive::new(&archive_path).read(&*db)?, unsealed)
+    };
+    // Opened without going through `STORE`, which would unseal it
+    let db = Storage::user_db()?;
+    let rekey = sealed::Rekey::new(&*db, Secret::from_env().as_ref(), new.as_ref())?;
+    // Sealed with the new key next to what they replace first, and only renamed over it once the database has the
+    // new key: a crash before then leaves everything as it was, and one after leaves the new copies beside the old
+    let mut staged = Vec::new();
+    let res = (|| {
+        if let Some(tmp) = Archive::new(&archive_path).stage(&archived, |blob| rekey.seal_blob(blob))? {
+            staged.push((tmp, archive_path.clone()));
+        }
+        for (snapshot, blob) in snapshots.iter().zip(unsealed) {
+            staged.push((
+                backup::stage(&snapshot.path, &rekey.seal_blob(blob)?)?,
+                snapshot.path.clone(),
+            ));
+        }
+        rekey.apply(&*db)?;
+        Ok::<_, anyhow::Error>(())
+    })();
+    if let Err(e) = res {
+        for (tmp, _) in &staged {
+            let _ = fs::remove_file(tmp);
+        }
+        return Err(e);
+    }
+    for (tmp, path) in &staged {
+        fs::rename(tmp, path)?;
+    }
+    match new {
+        Some(Secret::Keyfile(path)) => println!(
+            "Encrypted the database, set SCHED_KEYFILE to {} from now on",
+            path.display()
+        ),
+        Some(Secret::Passphrase(_)) => println!("Encrypted the database, set SCHED_PASSPHRASE to it from now on"),
+        None => println!("Decrypted the database, unset SCHED_PASSPHRASE and SCHED_KEYFILE from now on"),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "encryption"))]
+fn rekey(_keyfile: Option<&Path>, _decrypt: bool) -> anyhow::Result<()> {
+    anyhow::bail!("sched has been built without the `encryption` feature")
 }
 
 async fn notify_loop(quit_sig: Arc<Notify>) {
     let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(3000));
+    let mut archived: Option<std::time::Instant> = None;
+    loop {
+        // A bad record shouldn't stop the notifications for good, it's reported and retried next time
+        if let Err(e) = notify::notify() {
+            eprintln!("Can't send notifications: {}", e);
+        }
+        // Retention is in days, going through the logs once an hour is plenty
+        if archived.is_none_or(|t| t.elapsed() >= std::time::Duration::from_secs(3600)) {
+            if let Err(e) = STORE.archive_logs(DateTime::now(), false) {
+                eprintln!("Can't archive logs: {}", e);
+            }
+            archived = Some(std::time::Instant::now());
+        }
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = quit_sig.notified() => {
+                break;
+            },
+        }
+    }
+}
+
+async fn backup_loop(config: PathBuf, quit_sig: Arc<Notify>) {
+    // A snapshot is taken once a day, checking every hour is enough to not miss a day
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3600));
     loop {
-        notify::notify();
+        let res = backup::dir().and_then(|dir| backup::backup_if_due(&STORE, &dir, &config));
+        if let Err(e) = res {
+            eprintln!("Can't back up the database: {}", e);
+        }
         tokio::select! {
             _ = interval.tick() => (),
             _ = quit_sig.notified() => {
@@ -92,7 +666,9 @@ async fn notify_loop(quit_sig: Arc<Notify>) {
 }
 
 #[cfg(not(features = "scripting"))]
-fn repl_loop() {}
+fn repl_loop() {
+    repl::cmd_repl();
+}
 
 #[cfg(features = "scripting")]
 fn repl_loop(init_file: &Path) {
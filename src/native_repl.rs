@@ -1,9 +1,140 @@
-use clap::{App, AppSettings::*};
+use anyhow::bail;
+use chrono::Local;
+use clap::{App, AppSettings::*, Arg, ArgMatches};
 use rustyline::{error::ReadlineError, Editor};
 
+use crate::storage::{
+    api::{EitherId, LogId, ObjId},
+    time::DateTime,
+    STORE,
+};
+
+fn parse_log_id(s: &str) -> anyhow::Result<LogId> {
+    match s.parse()? {
+        EitherId::Log(id) => Ok(id),
+        EitherId::Obj(id) => bail!("{} is not a log", id),
+    }
+}
+
+fn parse_obj_id(s: &str) -> anyhow::Result<ObjId> {
+    match s.parse()? {
+        EitherId::Obj(id) => Ok(id),
+        EitherId::Log(id) => bail!("{} is not an object", id),
+    }
+}
+
+fn show_time(time: DateTime) -> String {
+    time.0.with_timezone(&Local).format("%Y.%m.%d..%H.%M.%S").to_string()
+}
+
+fn history(m: &ArgMatches) -> anyhow::Result<()> {
+    let id = parse_obj_id(m.value_of("id").unwrap())?;
+    match m.value_of("at") {
+        Some(at) => {
+            let at = match DateTime::parse_local(at) {
+                Some(at) => at,
+                None => bail!("Can't parse time `{}`", at),
+            };
+            match STORE.get_obj_at(id, at)? {
+                Some(obj) => {
+                    println!("{} {}: {}", obj.id, obj.typ, obj.name);
+                    if let Some(desc) = obj.desc {
+                        println!("  {}", desc);
+                    }
+                    println!("  {}", serde_json::to_string(&obj.props)?);
+                    if let Some(attrs) = obj.attrs {
+                        println!("  attrs: {}", serde_json::to_string(&attrs)?);
+                    }
+                }
+                None => println!("{} didn't exist at {}", id, show_time(at)),
+            }
+        }
+        None => {
+            for log in STORE.obj_history(id)? {
+                let props = serde_json::to_string(&log.props)?;
+                println!("{} {} {} {}", log.id, show_time(log.time), log.typ, props);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn skip(m: &ArgMatches) -> anyhow::Result<()> {
+    let id = parse_obj_id(m.value_of("id").unwrap())?;
+    println!("Skipped {} as {}", id, STORE.task_skip(id)?);
+    Ok(())
+}
+
+fn move_to(m: &ArgMatches) -> anyhow::Result<()> {
+    let id = parse_obj_id(m.value_of("id").unwrap())?;
+    let time = m.value_of("time").unwrap();
+    let time = match DateTime::parse_local(time) {
+        Some(time) => time,
+        None => bail!("Can't parse time `{}`", time),
+    };
+    println!("Moved {} to {} as {}", id, show_time(time), STORE.task_move(id, time)?);
+    Ok(())
+}
+
+fn undo(m: &ArgMatches, redo: bool) -> anyhow::Result<()> {
+    let id = match m.value_of("id") {
+        Some(id) => parse_log_id(id)?,
+        None => match STORE.last_undoable(redo)? {
+            Some(id) => id,
+            None => {
+                println!("Nothing to {}", if redo { "redo" } else { "undo" });
+                return Ok(());
+            }
+        },
+    };
+    if redo {
+        println!("Redid {} as {}", id, STORE.redo(id)?);
+    } else {
+        println!("Undid {} as {}", id, STORE.undo(id)?);
+    }
+    Ok(())
+}
+
 pub fn cmd_repl() {
     let mut editor = Editor::<()>::new();
-    let mut cmds = App::new("cmd").settings(&[NoBinaryName]);
+    let mut cmds = App::new("cmd")
+        .settings(&[NoBinaryName])
+        .subcommand(
+            App::new("history")
+                .about("Show the changes made to an object, or the object as it was at some time")
+                .arg(Arg::with_name("id").required(true).help("Object to show"))
+                .arg(
+                    Arg::with_name("at")
+                        .long("at")
+                        .takes_value(true)
+                        .help("Local time as `%Y.%m.%d..%H.%M.%S`, or `%Y.%m.%d` for midnight"),
+                ),
+        )
+        .subcommand(
+            App::new("skip")
+                .about("Leave out the occurrence of a repeated task a sub task is for")
+                .arg(Arg::with_name("id").required(true).help("Sub task to skip")),
+        )
+        .subcommand(
+            App::new("move")
+                .about("Have the occurrence of a task a sub task is for happen at another time")
+                .arg(Arg::with_name("id").required(true).help("Sub task to move"))
+                .arg(
+                    Arg::with_name("time")
+                        .required(true)
+                        .help("Local time as `%Y.%m.%d..%H.%M.%S`, or `%Y.%m.%d` for midnight"),
+                ),
+        )
+        .subcommand(
+            App::new("undo")
+                .about("Revert a change by logging the opposite one")
+                .arg(Arg::with_name("id").help("Log to undo, the latest change if not given")),
+        )
+        .subcommand(
+            App::new("redo")
+                .about("Apply an undone change again")
+                .arg(Arg::with_name("id").help("Undo log to revert, the latest undo if not given")),
+        );
     loop {
         match editor.readline(">=> ") {
             Ok(line) => {
@@ -13,9 +144,19 @@ pub fn cmd_repl() {
                 }
                 let args = line.split_ascii_whitespace();
                 match cmds.get_matches_from_safe_borrow(args) {
-                    Ok(matches) => match matches.subcommand() {
-                        (_, _) => panic!(),
-                    },
+                    Ok(matches) => {
+                        let res = match matches.subcommand() {
+                            ("history", Some(m)) => history(m),
+                            ("skip", Some(m)) => skip(m),
+                            ("move", Some(m)) => move_to(m),
+                            ("undo", Some(m)) => undo(m, false),
+                            ("redo", Some(m)) => undo(m, true),
+                            _ => Ok(()),
+                        };
+                        if let Err(e) = res {
+                            eprintln!("{}", e);
+                        }
+                    }
                     Err(e) => {
                         eprintln!("{}", e.message);
                     }
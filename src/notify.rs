@@ -2,13 +2,13 @@
 
 use chrono::Utc;
 
-use crate::storage::{api::*, time::DateTime};
+use crate::storage::{api::*, time::DateTime, Result as StorageResult};
 use crate::STORE;
 
-pub fn notify() {
-    let sub_tasks = STORE.find_obj(|o: &Obj<SubTask>| o.inner.finished.is_none(), None);
+pub fn notify() -> StorageResult<()> {
+    let sub_tasks = STORE.find_obj(|o: &Obj<SubTask>| o.inner.finished.is_none(), None)?;
     let now = Utc::now();
-    let mut state = STORE.get_state().unwrap();
+    let mut state = STORE.get_state()?;
     let last_notified_time: Option<DateTime> = state.last_notified;
     let mut notified = false;
     for sub in sub_tasks.into_iter() {
@@ -27,17 +27,21 @@ pub fn notify() {
                 } else {
                     format!("T{:+}", diff_time.0)
                 };
-                notify_rust::Notification::new()
+                let shown = notify_rust::Notification::new()
                     .appname("sched")
                     .summary(&format!("Sched: {}", &sub.name))
                     .body(&body)
-                    .show()
-                    .unwrap();
+                    .show();
+                // Not being able to show one shouldn't hold back the rest
+                if let Err(e) = shown {
+                    eprintln!("Can't show the notification for {}: {}", sub.id, e);
+                }
             }
         }
     }
     if notified {
         state.last_notified = Some(now.into());
-        STORE.set_state(state).unwrap();
+        STORE.set_state(state)?;
     }
+    Ok(())
 }
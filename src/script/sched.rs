@@ -15,6 +15,8 @@ use crate::{
     },
     storage::{
         api::{AttrValue, Attrs, ProtoLog},
+        rule::{Freq, Overflow, Rule},
+        time::TimeZone,
         Error, Every, OptRepeated, Repeated, Result as StorageResult, Stop, Storage,
     },
 };
@@ -30,7 +32,9 @@ fn ralign(s: &str, n: usize) -> String {
 mod log {
     use super::{lalign, ralign};
     use crate::storage::{
-        api::{AttrValue, Attrs, ProtoLog},
+        api::{AttrValue, Attrs, LogId, ProtoLog, ScriptLog},
+        archive::Retention,
+        time::DateTime,
         Result as StorageResult, STORE,
     };
     use gluon::vm::api::{FunctionRef, IO};
@@ -43,7 +47,23 @@ mod log {
     }
 
     fn find(filter: FunctionRef<fn(ProtoLog) -> bool>, limit: Option<usize>) -> StorageResult<Vec<ProtoLog>> {
-        Ok(STORE.find_log(|l| filter.clone().call(l.clone()).unwrap(), limit))
+        STORE.find_log(|l| filter.clone().call(l.clone()).unwrap(), limit, false)
+    }
+
+    pub fn find_archived(
+        filter: FunctionRef<fn(ProtoLog) -> bool>,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ProtoLog>> {
+        STORE.find_log(|l| filter.clone().call(l.clone()).unwrap(), limit, true)
+    }
+
+    /// Archives the logs of the types matching `types` once they're older than `days`, in place of the rule there
+    /// was for `types`
+    pub fn retain(types: String, days: u32) {
+        let mut rules = STORE.retention();
+        rules.retain(|r| r.types != types);
+        rules.push(Retention { types, days });
+        STORE.set_retention(rules);
     }
 
     fn find_from(
@@ -51,11 +71,11 @@ mod log {
         filter: FunctionRef<fn(ProtoLog) -> bool>,
         limit: Option<usize>,
     ) -> StorageResult<Vec<ProtoLog>> {
-        Ok(STORE.find_log_from(id, |l| filter.clone().call(l.clone()).unwrap(), limit))
+        STORE.find_log_from(id, |l| filter.clone().call(l.clone()).unwrap(), limit)
     }
 
     fn find_old(filter: FunctionRef<fn(ProtoLog) -> bool>, limit: Option<usize>) -> StorageResult<Vec<ProtoLog>> {
-        Ok(STORE.find_log_old(|l| filter.clone().call(l.clone()).unwrap(), limit))
+        STORE.find_log_old(|l| filter.clone().call(l.clone()).unwrap(), limit)
     }
 
     fn find_old_from(
@@ -63,12 +83,31 @@ mod log {
         filter: FunctionRef<fn(ProtoLog) -> bool>,
         limit: Option<usize>,
     ) -> StorageResult<Vec<ProtoLog>> {
-        Ok(STORE.find_log_old_from(id, |l| filter.clone().call(l.clone()).unwrap(), limit))
+        STORE.find_log_old_from(id, |l| filter.clone().call(l.clone()).unwrap(), limit)
+    }
+
+    pub fn range(from: DateTime, to: DateTime, type_glob: String) -> StorageResult<Vec<ScriptLog>> {
+        STORE.logs_between(from, to, &type_glob)
+    }
+
+    pub fn undo(id: LogId) -> StorageResult<LogId> {
+        STORE.undo(id)
+    }
+
+    pub fn redo(id: LogId) -> StorageResult<LogId> {
+        STORE.redo(id)
+    }
+
+    pub fn last_undoable(redo: bool) -> StorageResult<Option<LogId>> {
+        STORE.last_undoable(redo)
     }
 
     fn list(num: usize) -> IO<()> {
         // TODO fix this table rendering
-        let logs = STORE.find_log(|_l| true, Some(num));
+        let logs = match STORE.find_log(|_l| true, Some(num), false) {
+            Ok(logs) => logs,
+            Err(e) => return IO::Exception(e.to_string()),
+        };
         let header = (
             "id".to_string(),
             "typ".to_string(),
@@ -147,7 +186,8 @@ mod obj {
     use crate::{
         script::sched::STORE,
         storage::{
-            api::{AttrValue, Attrs, ProtoObj},
+            api::{AttrValue, Attrs, ObjId, ProtoObj, ScriptLog, ScriptObj},
+            time::DateTime,
             Result as StorageResult,
         },
     };
@@ -179,16 +219,32 @@ mod obj {
         STORE.obj_del_attr(obj.id, attr)
     }
 
+    pub fn find_by_type(
+        typ: String,
+        filter: FunctionRef<fn(ScriptObj) -> bool>,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        STORE.find_obj_by_type(&typ, |o| filter.clone().call(o.clone()).unwrap(), limit)
+    }
+
+    pub fn history(id: ObjId) -> StorageResult<Vec<ScriptLog>> {
+        STORE.obj_history(id)
+    }
+
+    pub fn get_at(id: ObjId, time: DateTime) -> StorageResult<Option<ScriptObj>> {
+        STORE.get_obj_at(id, time)
+    }
+
     fn find(filter: FunctionRef<fn(Object) -> bool>, limit: Option<usize>) -> StorageResult<Vec<Object>> {
-        Ok(STORE.find_obj(|o| filter.clone().call(o.clone()).unwrap(), limit))
+        STORE.find_obj(|o| filter.clone().call(o.clone()).unwrap(), limit)
     }
 
     fn find_from(id: u32, filter: FunctionRef<fn(Object) -> bool>, limit: Option<usize>) -> StorageResult<Vec<Object>> {
-        Ok(STORE.find_obj_from(id, |o| filter.clone().call(o.clone()).unwrap(), limit))
+        STORE.find_obj_from(id, |o| filter.clone().call(o.clone()).unwrap(), limit)
     }
 
     fn find_old(filter: FunctionRef<fn(Object) -> bool>, limit: Option<usize>) -> StorageResult<Vec<Object>> {
-        Ok(STORE.find_obj_old(|o| filter.clone().call(o.clone()).unwrap(), limit))
+        STORE.find_obj_old(|o| filter.clone().call(o.clone()).unwrap(), limit)
     }
 
     fn find_old_from(
@@ -196,7 +252,50 @@ mod obj {
         filter: FunctionRef<fn(Object) -> bool>,
         limit: Option<usize>,
     ) -> StorageResult<Vec<Object>> {
-        Ok(STORE.find_obj_old_from(id, |o| filter.clone().call(o.clone()).unwrap(), limit))
+        STORE.find_obj_old_from(id, |o| filter.clone().call(o.clone()).unwrap(), limit)
+    }
+}
+
+mod rule {
+    use chrono::Weekday;
+
+    use crate::storage::rule::{ByDay, Freq, Rule};
+
+    pub fn new(freq: Freq, interval: u32) -> Rule {
+        Rule::new(freq, interval)
+    }
+
+    pub fn by_month(mut rule: Rule, months: Vec<u32>) -> Rule {
+        rule.by_month = months;
+        rule
+    }
+
+    pub fn by_month_day(mut rule: Rule, days: Vec<i32>) -> Rule {
+        rule.by_month_day = days;
+        rule
+    }
+
+    /// Adds the `nth` `day` of the week, 0 being Monday. `nth` is 0 for every one of them.
+    pub fn on_day(mut rule: Rule, nth: i32, day: u32) -> Rule {
+        let days = [
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ];
+        rule.by_day.push(ByDay {
+            nth,
+            day: days[day as usize % 7],
+        });
+        rule
+    }
+
+    pub fn by_set_pos(mut rule: Rule, positions: Vec<i32>) -> Rule {
+        rule.by_set_pos = positions;
+        rule
     }
 }
 
@@ -205,6 +304,9 @@ pub fn load(thread: &Thread) -> Result<ExternModule, gluon::vm::Error> {
         thread,
         record! {
             type Every => Every,
+            type Freq => Freq,
+            type Overflow => Overflow,
+            type Rule => Rule,
             type Stop => Stop,
             type Repeated => Repeated,
             type OptRepeated => OptRepeated,
@@ -215,10 +317,16 @@ pub fn load(thread: &Thread) -> Result<ExternModule, gluon::vm::Error> {
                 get => primitive!(1, Log::get),
                 set_attr => primitive!(3, Log::set_attr),
                 find => primitive!(2, Log::find),
+                find_archived => primitive!(2, log::find_archived),
+                retain => primitive!(2, log::retain),
                 find_from => primitive!(3, Log::find_from),
                 find_old => primitive!(2, Log::find_old),
                 find_old_from => primitive!(3, Log::find_old_from),
                 list => primitive!(1, Log::list),
+                range => primitive!(3, log::range),
+                undo => primitive!(1, log::undo),
+                redo => primitive!(1, log::redo),
+                last_undoable => primitive!(1, log::last_undoable),
             },
 
             obj => record! {
@@ -232,6 +340,9 @@ pub fn load(thread: &Thread) -> Result<ExternModule, gluon::vm::Error> {
                 find_from => primitive!(3, Object::find_from),
                 find_old => primitive!(2, Object::find_old),
                 find_old_from => primitive!(3, Object::find_old_from),
+                find_by_type => primitive!(3, obj::find_by_type),
+                history => primitive!(1, obj::history),
+                get_at => primitive!(2, obj::get_at),
             },
 
             task => record! {
@@ -252,8 +363,23 @@ pub fn load(thread: &Thread) -> Result<ExternModule, gluon::vm::Error> {
                 STORE.add_gluon(pat, func)
             }),
             repeat => primitive!(3, |start, every, stop| {
-                Repeated::new(start, every, stop)
+                Repeated::new(start, every, stop).map_err(|e| e.to_string())
             }),
+            with_overflow => primitive!(2, Repeated::with_overflow),
+            // Fixed offsets are what the start times have already
+            with_zone => primitive!(2, |repeat: Repeated, zone: TimeZone| {
+                repeat.with_zone(match zone {
+                    TimeZone::Named(zone) => Some(zone),
+                    TimeZone::Fixed(_) => None,
+                })
+            }),
+            rule => record! {
+                new => primitive!(2, rule::new),
+                by_month => primitive!(2, rule::by_month),
+                by_month_day => primitive!(2, rule::by_month_day),
+                on_day => primitive!(3, rule::on_day),
+                by_set_pos => primitive!(2, rule::by_set_pos),
+            },
         },
     )
 }
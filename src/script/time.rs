@@ -75,7 +75,7 @@ impl DateTime {
     }
 
     fn with_timezone(&self, tz: &TimeZone) -> DateTime {
-        DateTime(self.0.with_timezone(&tz.0))
+        tz.convert(*self)
     }
 
     fn to_local(&self) -> DateTime {
@@ -188,11 +188,35 @@ impl Duration {
 
 impl TimeZone {
     fn east(secs: i32) -> TimeZone {
-        TimeZone(FixedOffset::east(secs))
+        TimeZone::Fixed(FixedOffset::east(secs))
     }
 
     fn west(secs: i32) -> TimeZone {
-        TimeZone(FixedOffset::west(secs))
+        TimeZone::Fixed(FixedOffset::west(secs))
+    }
+
+    /// The zone called `name` in the IANA database, e.g. `Europe/Paris`
+    fn named(name: String) -> Option<TimeZone> {
+        name.parse().ok().map(TimeZone::Named)
+    }
+
+    /// `time` in this zone, in the offset it's in then
+    fn convert(&self, time: DateTime) -> DateTime {
+        match self {
+            TimeZone::Fixed(offset) => DateTime(time.0.with_timezone(offset)),
+            TimeZone::Named(zone) => {
+                let time = time.0.with_timezone(zone);
+                DateTime(time.with_timezone(&time.offset().fix()))
+            }
+        }
+    }
+
+    /// The IANA name of the zone, if it's a named one
+    fn name(&self) -> Option<String> {
+        match self {
+            TimeZone::Fixed(_) => None,
+            TimeZone::Named(zone) => Some(zone.name().to_string()),
+        }
     }
 }
 
@@ -205,10 +229,12 @@ pub fn load(thread: &Thread) -> Result<ExternModule, gluon::vm::Error> {
         record! {
             timezone => record! {
                 type TimeZone => TimeZone,
-                utc => TimeZone(Utc.fix()),
-                local => TimeZone(*Local::now().offset()),
+                utc => TimeZone::Fixed(Utc.fix()),
+                local => TimeZone::Fixed(*Local::now().offset()),
                 east => primitive!(1, TimeZone::east),
                 west => primitive!(1, TimeZone::west),
+                named => primitive!(1, TimeZone::named),
+                name => primitive!(1, TimeZone::name),
             },
             datetime => record! {
                 type DateTime => DateTime,
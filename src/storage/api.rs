@@ -13,28 +13,40 @@ use crate::{
     storage_objs,
 };
 
-pub trait ApiLog: Serialize {
+pub trait ApiLog: Serialize + Clone {
     const LOG_TYPE: &'static str;
 }
 
-pub trait ApiObj: Serialize {
+pub trait ApiObj: Serialize + Clone {
     const OBJ_TYPE: &'static str;
 }
 
+/// Log and object IDs are unique across the stores that are synced with each other: the top byte is the device
+/// the record was created on, and the rest counts up on that device. Everything from before stores had devices is on
+/// device 0, so its IDs read the same as before.
 pub type IdType = u32;
+pub type Device = u8;
+const SEQ_BITS: u32 = 24;
 pub type ApiVec<T> = Vec<T>; //smallvec::SmallVec<[T; 16]>;
 pub type ApiMap<K, V> = std::collections::BTreeMap<K, V>;
 pub use serde_json::Value as AttrValue;
 pub type Attrs = ApiMap<String, AttrValue>;
 
 #[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata))]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct LogId(pub IdType);
 
+impl LogId {
+    /// The device the record was created on
+    pub fn device(self) -> Device {
+        device_of(self.0)
+    }
+}
+
 impl fmt::Display for LogId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "L{}", self.0)
+        write!(f, "L{}", fmt_id(self.0))
     }
 }
 
@@ -43,12 +55,63 @@ impl fmt::Display for LogId {
 #[serde(transparent)]
 pub struct ObjId(pub IdType);
 
+impl ObjId {
+    /// The device the record was created on
+    pub fn device(self) -> Device {
+        device_of(self.0)
+    }
+}
+
 impl fmt::Display for ObjId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "O{}", self.0)
+        write!(f, "O{}", fmt_id(self.0))
+    }
+}
+
+/// The device an ID was handed out on
+pub fn device_of(id: IdType) -> Device {
+    (id >> SEQ_BITS) as Device
+}
+
+/// The first ID handed out on `device`
+pub fn first_id(device: Device) -> IdType {
+    (device as IdType) << SEQ_BITS | 1
+}
+
+/// The ID to hand out next on `device`, given the ones in use
+pub fn next_id(device: Device, used: impl IntoIterator<Item = IdType>) -> IdType {
+    used.into_iter()
+        .filter(|&id| device_of(id) == device)
+        .max()
+        .map_or(first_id(device), |id| id + 1)
+}
+
+/// `12` on device 0, `3:12` on device 3
+fn fmt_id(id: IdType) -> String {
+    match device_of(id) {
+        0 => id.to_string(),
+        device => format!("{}:{}", device, id & ((1 << SEQ_BITS) - 1)),
     }
 }
 
+fn parse_id(s: &str) -> Result<IdType, EitherIdParseError> {
+    let (device, seq) = match s.find(':') {
+        Some(i) => (
+            s[..i].parse::<Device>().map_err(EitherIdParseError::ParseInt)?,
+            &s[i + 1..],
+        ),
+        None => (0, s),
+    };
+    let seq = seq.parse::<IdType>().map_err(EitherIdParseError::ParseInt)?;
+    if seq >> SEQ_BITS != 0 {
+        return Err(EitherIdParseError::Message(format!(
+            "{} is out of range for a device",
+            seq
+        )));
+    }
+    Ok((device as IdType) << SEQ_BITS | seq)
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum EitherId {
     Obj(ObjId),
@@ -67,7 +130,7 @@ impl fmt::Display for EitherId {
 impl FromStr for EitherId {
     type Err = EitherIdParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let id = s[1..].parse::<IdType>().map_err(EitherIdParseError::ParseInt)?;
+        let id = parse_id(s.get(1..).unwrap_or_default())?;
         match s.chars().next() {
             Some('O') => Ok(EitherId::Obj(ObjId(id))),
             Some('L') => Ok(EitherId::Log(LogId(id))),
@@ -212,6 +275,11 @@ api_objs! {
         #[serde(default)] #[new(default)]
         #[serde(skip_serializing_if = "MinimizedSerde::min_able")]
         notifications: ApiVec<Duration>,
+        /// When the recurrence has the occurrence the last sub task was generated for, which generating more goes
+        /// on after
+        #[serde(default)] #[new(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        generated: Option<DateTime>,
         /// A fixed-size FIFO cache of the daughter task ids with user configurable size
         cache: ApiVec<ObjId>,
     }
@@ -239,10 +307,43 @@ pub enum Diff<T> {
     Diff(T, T),
 }
 
+impl<T> Diff<T> {
+    /// The value before the change
+    pub fn before(&self) -> Option<&T> {
+        match self {
+            Diff::New(_) => None,
+            Diff::Del(o) | Diff::Diff(o, _) => Some(o),
+        }
+    }
+
+    /// The value after the change
+    pub fn after(&self) -> Option<&T> {
+        match self {
+            Diff::Del(_) => None,
+            Diff::New(n) | Diff::Diff(_, n) => Some(n),
+        }
+    }
+
+    /// The change that reverts this one
+    pub fn inverse(self) -> Diff<T> {
+        match self {
+            Diff::New(n) => Diff::Del(n),
+            Diff::Del(o) => Diff::New(o),
+            Diff::Diff(o, n) => Diff::Diff(n, o),
+        }
+    }
+}
+
 api_logs! {
     CreateObj "obj.create" {
         id: ObjId,
+        // Would otherwise clash with the log's own `typ` once flattened
+        #[serde(rename = "obj-typ")]
         typ: String,
+        /// The whole object as it was first stored, so that it can be recreated from the logs
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        obj: Option<AttrValue>,
     }
 
     ObjSetDesc "obj.set_desc" {
@@ -256,7 +357,79 @@ api_logs! {
         diff: Diff<AttrValue>,
     }
 
+    ObjSetProps "obj.set_props" {
+        id: ObjId,
+        props: ApiMap<String, Diff<AttrValue>>,
+    }
+
+    ObjDelete "obj.delete" {
+        id: ObjId,
+        /// The whole object as it was before deletion, so that it can be brought back
+        obj: AttrValue,
+    }
+
     TaskFinish "task.finish" {
         id: ObjId,
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        finished: Option<DateTime>,
+        /// Logs for the other changes made when finishing the task, which are undone along with it
+        #[serde(default)]
+        #[serde(skip_serializing_if = "MinimizedSerde::min_able")]
+        caused: ApiVec<LogId>,
+    }
+
+    TaskSkip "task.skip" {
+        /// The sub task for the occurrence that's left out
+        id: ObjId,
+        /// Logs for the changes made to leave it out, which are undone along with it
+        caused: ApiVec<LogId>,
+    }
+
+    TaskMove "task.move" {
+        /// The sub task for the occurrence that's moved
+        id: ObjId,
+        to: DateTime,
+        /// Logs for the changes made to move it, which are undone along with it
+        caused: ApiVec<LogId>,
+    }
+
+    LogGroup "log.group" {
+        /// What the changes were made for, e.g. importing a file
+        desc: String,
+        /// Logs for the changes, which are undone along with it
+        caused: ApiVec<LogId>,
+    }
+
+    LogUndo "log.undo" {
+        /// The log being undone
+        id: LogId,
+        /// Logs for the compensating changes
+        logs: ApiVec<LogId>,
+    }
+
+    LogRedo "log.redo" {
+        /// The `log.undo` log being reverted
+        id: LogId,
+        /// Logs for the changes made again
+        logs: ApiVec<LogId>,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_device_ids() {
+        assert_eq!(LogId(12).to_string(), "L12");
+        let id = ObjId(first_id(3) + 11);
+        assert_eq!((id.device(), id.to_string()), (3, "O3:12".into()));
+        assert!(matches!("O3:12".parse(), Ok(EitherId::Obj(parsed)) if parsed == id));
+        assert!(matches!("L12".parse(), Ok(EitherId::Log(LogId(12)))));
+        assert!("L3:99999999".parse::<EitherId>().is_err());
+        assert!("L".parse::<EitherId>().is_err());
+        assert_eq!(next_id(3, vec![1, 5, id.0]), id.0 + 1);
+        assert_eq!(next_id(4, vec![1, 5, id.0]), first_id(4));
     }
 }
@@ -0,0 +1,242 @@
+//! Old logs moved out of the database. Logs of some types, e.g. the ones handlers write every few minutes, only
+//! matter for a while: retention rules say for how long, and `Storage::archive_logs` moves the older ones into an
+//! append-only archive file next to the database, where `find_log` can still get at them.
+//!
+//! The archive is a run of frames, each a big endian `u32` length followed by a gzipped batch of `DumpLog`s as JSON
+//! Lines, sealed by the backend if it seals its values.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::storage::{api::LogId, backend::Backend, dump::DumpLog, Error, Result};
+
+/// The archive of the store of the user, in the config directory
+pub const ARCHIVE_FILE: &str = "logs.archive";
+/// The retention rules of the store of the user, in the config directory
+pub const RETENTION_FILE: &str = "retention.json";
+
+/// Logs of the types matching `types` are archived once they're older than `days`. The logs on objects are what
+/// their history and `rebuild` go through, so those are best kept.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Retention {
+    /// A glob over log types, e.g. `handler.*`
+    pub types: String,
+    pub days: u32,
+}
+
+impl Retention {
+    /// The rules in the JSON array at `path`, none if there's no such file. The first rule that matches a type is
+    /// the one that applies to it.
+    pub fn load(path: &Path) -> Result<Vec<Retention>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// What's left in the database of the logs that have been archived
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// The highest ID archived on each device, so that it's never handed out again
+    pub last: Vec<LogId>,
+    /// How many logs of each type have been archived
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl Tombstone {
+    pub fn add(&mut self, id: LogId, typ: &str) {
+        match self.last.iter_mut().find(|last| last.device() == id.device()) {
+            Some(last) => *last = (*last).max(id),
+            None => self.last.push(id),
+        }
+        *self.counts.entry(typ.to_string()).or_default() += 1;
+    }
+
+    /// Whether the log `id` is no newer than the last archived on its device. A log that old which isn't in the
+    /// database has been archived, as its device had made it before the one archived last.
+    pub fn covers(&self, id: LogId) -> bool {
+        self.last.iter().any(|last| last.device() == id.device() && id <= *last)
+    }
+}
+
+/// The archive file
+#[derive(Clone, Debug)]
+pub struct Archive {
+    path: PathBuf,
+}
+
+impl Archive {
+    pub fn new(path: impl Into<PathBuf>) -> Archive {
+        Archive { path: path.into() }
+    }
+
+    /// One frame of `logs`, sealed by `seal`, with its length in front
+    fn frame(logs: &[DumpLog], seal: impl FnOnce(Vec<u8>) -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        for log in logs {
+            serde_json::to_writer(&mut gz, log)?;
+            gz.write_all(b"\n")?;
+        }
+        let frame = seal(gz.finish()?)?;
+        let mut bytes = (frame.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(frame);
+        Ok(bytes)
+    }
+
+    /// Appends `logs` as one frame, sealed by `backend`
+    pub fn append(&self, backend: &dyn Backend, logs: &[DumpLog]) -> Result<()> {
+        if logs.is_empty() {
+            return Ok(());
+        }
+        let bytes = Archive::frame(logs, |blob| backend.seal_blob(blob))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.path)?;
+        // A frame cut short would otherwise hide every one after it
+        let end = Archive::complete_len(&mut file)?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Where the last complete frame in `file` ends. One cut short by going down while appending it may follow,
+    /// whose logs are still in the database, as they're only removed once they've been archived.
+    fn complete_len(file: &mut fs::File) -> Result<u64> {
+        let total = file.metadata()?.len();
+        let mut end = 0;
+        let mut len = [0; 4];
+        while end + 4 <= total {
+            file.seek(SeekFrom::Start(end))?;
+            file.read_exact(&mut len)?;
+            let next = end + 4 + u64::from(u32::from_be_bytes(len));
+            if next > total {
+                break;
+            }
+            end = next;
+        }
+        Ok(end)
+    }
+
+    /// All the logs in the archive, each once, in the order they were archived. Nothing's there if there's no file
+    /// yet.
+    pub fn read(&self, backend: &dyn Backend) -> Result<Vec<DumpLog>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut logs = Vec::new();
+        // A batch is archived again if the database wasn't written after it was archived the first time
+        let mut seen = BTreeSet::new();
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let frame = rest
+                .get(..4)
+                .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+                .and_then(|len| rest.get(4..4 + len));
+            // Its logs are still in the database, and the next append writes over it
+            let frame = match frame {
+                Some(frame) => frame,
+                None => {
+                    eprintln!("The last frame of {} is cut short, it's left out", self.path.display());
+                    break;
+                }
+            };
+            let len = frame.len();
+            let frame = backend.unseal_blob(frame.to_vec())?;
+            for line in BufReader::new(GzDecoder::new(&frame[..])).lines() {
+                let log: DumpLog =
+                    serde_json::from_str(&line?).map_err(|e| Error::corrupt(e).at(self.path.display()))?;
+                if seen.insert(log.id) {
+                    logs.push(log);
+                }
+            }
+            rest = &rest[4 + len..];
+        }
+        Ok(logs)
+    }
+
+    /// Writes the archive anew with only `logs`, sealed by `backend`, e.g. once it has another key
+    pub fn rewrite(&self, backend: &dyn Backend, logs: &[DumpLog]) -> Result<()> {
+        if let Some(tmp) = self.stage(logs, |blob| backend.seal_blob(blob))? {
+            fs::rename(&tmp, &self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes what `rewrite` would, sealed by `seal`, to a file next to the archive and returns it, for renaming over
+    /// the archive once everything else it goes along with is in place. `None` if there's nothing to write.
+    pub fn stage(&self, logs: &[DumpLog], seal: impl FnOnce(Vec<u8>) -> Result<Vec<u8>>) -> Result<Option<PathBuf>> {
+        let tmp = self.path.with_extension("tmp");
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
+        }
+        if logs.is_empty() && !self.path.exists() {
+            return Ok(None);
+        }
+        // Left empty rather than missing if there's nothing, so that it's clear it's been rewritten
+        let bytes = if logs.is_empty() {
+            Vec::new()
+        } else {
+            Archive::frame(logs, seal)?
+        };
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        Ok(Some(tmp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::storage::backend::MemoryBackend;
+
+    #[test]
+    fn test_archive() {
+        let path = std::env::temp_dir().join(format!("sched-test-archive-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let archive = Archive::new(&path);
+        let backend = MemoryBackend::new();
+        assert!(archive.read(&backend).unwrap().is_empty());
+        let log = |id| DumpLog {
+            id: LogId(id),
+            log: json!({ "typ": "bio.sleep", "time": "2021-01-01T00:00:00Z", "hours": id }),
+        };
+        archive.append(&backend, &[log(1), log(2)]).unwrap();
+        archive.append(&backend, &[log(2), log(3)]).unwrap();
+        let ids = |logs: Vec<DumpLog>| logs.iter().map(|l| l.id.0).collect::<Vec<_>>();
+        assert_eq!(ids(archive.read(&backend).unwrap()), [1, 2, 3]);
+
+        archive.rewrite(&backend, &[log(3)]).unwrap();
+        assert_eq!(ids(archive.read(&backend).unwrap()), [3]);
+        // Going down while appending leaves a frame cut short, which is left out and then written over
+        let torn = |len: &[u8]| {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(len).unwrap();
+        };
+        torn(&[0, 0]);
+        assert_eq!(ids(archive.read(&backend).unwrap()), [3]);
+        archive.append(&backend, &[log(4)]).unwrap();
+        assert_eq!(ids(archive.read(&backend).unwrap()), [3, 4]);
+        torn(&[0, 0, 1, 0, 7]);
+        assert_eq!(ids(archive.read(&backend).unwrap()), [3, 4]);
+        archive.append(&backend, &[log(5)]).unwrap();
+        assert_eq!(ids(archive.read(&backend).unwrap()), [3, 4, 5]);
+        fs::remove_file(&path).unwrap();
+    }
+}
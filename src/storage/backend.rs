@@ -0,0 +1,343 @@
+//! Where the storage keeps its bytes. `Storage` only sees a handful of ordered key-value trees through the
+//! `Backend` trait, so that it can run on top of sled on disk or entirely in memory.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Mutex;
+
+use sled::{transaction::TransactionalTree, Transactional};
+
+use crate::storage::{Error, Result};
+
+pub type TxResult<T> = sled::transaction::ConflictableTransactionResult<T, Error>;
+pub type KvPair = (Vec<u8>, Vec<u8>);
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+pub type Iter<'a> = Box<dyn DoubleEndedIterator<Item = Result<KvPair>> + 'a>;
+
+/// The trees the storage is made of
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tree {
+    Meta,
+    Objs,
+    Logs,
+    /// Index of the objects by type, keyed by `typ \0 id` with nothing in the values
+    ObjsByType,
+    /// Index of the logs by time, keyed by `time id`
+    LogsByTime,
+    /// Index of the logs by type and then time, keyed by `typ \0 time id`
+    LogsByType,
+}
+
+impl Tree {
+    pub const ALL: [Tree; 6] = [
+        Tree::Meta,
+        Tree::Objs,
+        Tree::Logs,
+        Tree::ObjsByType,
+        Tree::LogsByTime,
+        Tree::LogsByType,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Tree::Meta => "meta",
+            Tree::Objs => "objs",
+            Tree::Logs => "logs",
+            Tree::ObjsByType => "objs_by_type",
+            Tree::LogsByTime => "logs_by_time",
+            Tree::LogsByType => "logs_by_type",
+        }
+    }
+}
+
+/// The view of the trees from inside a transaction
+pub trait BackendTxn {
+    fn get(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>>;
+    fn insert(&self, tree: Tree, key: &[u8], value: &[u8]) -> TxResult<Option<Vec<u8>>>;
+    fn remove(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>>;
+}
+
+pub trait Backend: Send + Sync {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// The entries with keys in `range`, in key order
+    fn range(&self, tree: Tree, range: KeyRange) -> Iter<'_>;
+
+    /// Runs `f` atomically over all the trees. `f` may be run again if the transaction conflicts with another one.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn BackendTxn) -> TxResult<()>) -> Result<()>;
+
+    fn flush(&self) -> Result<()>;
+
+    /// Seals what's kept outside of the trees, e.g. the log archive, as the values in them are. Nothing is sealed by
+    /// default.
+    fn seal_blob(&self, blob: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(blob)
+    }
+
+    fn unseal_blob(&self, blob: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(blob)
+    }
+
+    fn iter(&self, tree: Tree) -> Iter<'_> {
+        self.range(tree, (Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> Iter<'_> {
+        // The first key after all the ones with the prefix is the prefix with its last non-0xff byte bumped
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+        let end = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        self.range(tree, (Bound::Included(prefix.to_vec()), end))
+    }
+
+    fn get_gt(&self, tree: Tree, key: &[u8]) -> Result<Option<KvPair>> {
+        self.range(tree, (Bound::Excluded(key.to_vec()), Bound::Unbounded))
+            .next()
+            .transpose()
+    }
+
+    fn is_empty(&self, tree: Tree) -> Result<bool> {
+        Ok(self.iter(tree).next().transpose()?.is_none())
+    }
+}
+
+/// The on-disk backend
+pub struct SledBackend {
+    db: sled::Db,
+    /// Indexed by `Tree`
+    trees: Vec<sled::Tree>,
+}
+
+impl SledBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<SledBackend> {
+        let path = path.as_ref();
+        let db = sled::open(path).map_err(|e| match e {
+            // Only one process can have the database open at a time
+            sled::Error::Io(ref io) if io.to_string().contains("could not acquire lock") => {
+                Error::Locked(path.to_owned())
+            }
+            e => e.into(),
+        })?;
+        let trees = Tree::ALL
+            .iter()
+            .map(|t| db.open_tree(t.name()))
+            .collect::<sled::Result<_>>()?;
+        Ok(SledBackend { db, trees })
+    }
+}
+
+struct SledTxn<'a>(&'a [TransactionalTree]);
+
+impl BackendTxn for SledTxn<'_> {
+    fn get(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(self.0[tree as usize].get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, tree: Tree, key: &[u8], value: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(self.0[tree as usize].insert(key, value)?.map(|v| v.to_vec()))
+    }
+
+    fn remove(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(self.0[tree as usize].remove(key)?.map(|v| v.to_vec()))
+    }
+}
+
+impl Backend for SledBackend {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.trees[tree as usize].get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn range(&self, tree: Tree, range: KeyRange) -> Iter<'_> {
+        Box::new(
+            self.trees[tree as usize]
+                .range(range)
+                .map(|res| Ok(res.map(|(k, v)| (k.to_vec(), v.to_vec()))?)),
+        )
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn BackendTxn) -> TxResult<()>) -> Result<()> {
+        // sled wants a `Fn`
+        let f = RefCell::new(f);
+        Ok(self
+            .trees
+            .as_slice()
+            .transaction(|trees| (f.borrow_mut())(&SledTxn(trees)))?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+type MemoryTree = BTreeMap<Vec<u8>, Vec<u8>>;
+type MemoryWrites = BTreeMap<(Tree, Vec<u8>), Option<Vec<u8>>>;
+
+/// A backend that keeps everything in memory and is gone once dropped, for tests and throwaway stores
+#[derive(Default)]
+pub struct MemoryBackend {
+    trees: Mutex<BTreeMap<Tree, MemoryTree>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        Default::default()
+    }
+}
+
+/// Writes made in a transaction, only applied to the trees once it succeeds. `None` is a removal.
+struct MemoryTxn<'a> {
+    trees: &'a BTreeMap<Tree, MemoryTree>,
+    writes: RefCell<MemoryWrites>,
+}
+
+impl MemoryTxn<'_> {
+    fn write(&self, tree: Tree, key: &[u8], value: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        let old = self.get_current(tree, key);
+        self.writes.borrow_mut().insert((tree, key.to_vec()), value);
+        old
+    }
+
+    fn get_current(&self, tree: Tree, key: &[u8]) -> Option<Vec<u8>> {
+        match self.writes.borrow().get(&(tree, key.to_vec())) {
+            Some(written) => written.clone(),
+            None => self.trees.get(&tree).and_then(|t| t.get(key)).cloned(),
+        }
+    }
+}
+
+impl BackendTxn for MemoryTxn<'_> {
+    fn get(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(self.get_current(tree, key))
+    }
+
+    fn insert(&self, tree: Tree, key: &[u8], value: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(self.write(tree, key, Some(value.to_vec())))
+    }
+
+    fn remove(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(self.write(tree, key, None))
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.lock().unwrap();
+        Ok(trees.get(&tree).and_then(|t| t.get(key)).cloned())
+    }
+
+    fn range(&self, tree: Tree, range: KeyRange) -> Iter<'_> {
+        let trees = self.trees.lock().unwrap();
+        // `BTreeMap::range` panics on these instead of returning nothing
+        let empty = match (range.start_bound(), range.end_bound()) {
+            (Bound::Included(s), Bound::Excluded(e)) | (Bound::Excluded(s), Bound::Included(e)) => s >= e,
+            (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            _ => false,
+        };
+        let entries = match trees.get(&tree) {
+            Some(t) if !empty => t.range(range).map(|(k, v)| Ok((k.clone(), v.clone()))).collect(),
+            _ => Vec::new(),
+        };
+        Box::new(entries.into_iter())
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn BackendTxn) -> TxResult<()>) -> Result<()> {
+        // Holding the lock throughout means there's never a conflict to retry
+        let mut trees = self.trees.lock().unwrap();
+        let tx = MemoryTxn {
+            trees: &trees,
+            writes: RefCell::new(BTreeMap::new()),
+        };
+        match f(&tx) {
+            Ok(()) => {}
+            Err(sled::transaction::ConflictableTransactionError::Abort(e)) => return Err(e),
+            Err(sled::transaction::ConflictableTransactionError::Storage(e)) => return Err(e.into()),
+            Err(_) => unreachable!("conflict in an in-memory transaction"),
+        }
+        let writes = tx.writes.into_inner();
+        for ((tree, key), value) in writes {
+            let tree = trees.entry(tree).or_default();
+            match value {
+                Some(value) => tree.insert(key, value),
+                None => tree.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_backend(backend: &dyn Backend) {
+        backend
+            .transaction(&mut |tx| {
+                tx.insert(Tree::ObjsByType, b"a\0x", b"1")?;
+                tx.insert(Tree::ObjsByType, b"a\0y", b"2")?;
+                tx.insert(Tree::ObjsByType, b"b\0x", b"3")?;
+                assert_eq!(tx.get(Tree::ObjsByType, b"a\0y")?, Some(b"2".to_vec()));
+                assert_eq!(tx.get(Tree::LogsByTime, b"a\0y")?, None);
+                Ok(())
+            })
+            .unwrap();
+        let keys = |iter: Iter| iter.map(|res| res.unwrap().0).collect::<Vec<_>>();
+        let rev_keys = |iter: Iter| iter.rev().map(|res| res.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(
+            keys(backend.scan_prefix(Tree::ObjsByType, b"a\0")),
+            vec![b"a\0x".to_vec(), b"a\0y".to_vec()]
+        );
+        assert_eq!(rev_keys(backend.iter(Tree::ObjsByType))[0], b"b\0x".to_vec());
+        assert_eq!(
+            backend.get_gt(Tree::ObjsByType, b"a\0y").unwrap().unwrap().0,
+            b"b\0x".to_vec()
+        );
+        assert!(backend.is_empty(Tree::LogsByTime).unwrap());
+
+        // Nothing from an aborted transaction is kept
+        let res = backend.transaction(&mut |tx| {
+            tx.remove(Tree::ObjsByType, b"a\0x")?;
+            tx.insert(Tree::LogsByTime, b"l", b"4")?;
+            Err(Error::Deadlock.into())
+        });
+        assert!(matches!(res, Err(Error::Deadlock)));
+        assert_eq!(backend.get(Tree::ObjsByType, b"a\0x").unwrap(), Some(b"1".to_vec()));
+        assert!(backend.is_empty(Tree::LogsByTime).unwrap());
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        check_backend(&MemoryBackend::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_backend() {
+        check_backend(&crate::storage::sqlite::SqliteBackend::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn test_sled_backend() {
+        let path = std::env::temp_dir().join(format!("sched-test-{}", std::process::id()));
+        let backend = SledBackend::open(&path).unwrap();
+        check_backend(&backend);
+        assert!(matches!(SledBackend::open(&path), Err(Error::Locked(_))));
+        drop(backend);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,477 @@
+//! The dump written by `sched export` and read back by `sched import`. Every record keeps its ID, so that the
+//! references between them (`Task.cache`, `SubTask.task_id`, `CreateObj.id`, ...) survive a round trip.
+//!
+//! The JSON Lines form is a `Header` line followed by one `DumpObj` or `DumpLog` per line, so that it can be written
+//! and read one record at a time, and picked apart by other tools.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::BufRead;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::storage::{
+    api::*,
+    migrate::{MIGRATIONS, SCHEMA_VERSION},
+    time::DateTime,
+    Error, Result,
+};
+
+/// The format version written by this build
+pub const DUMP_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dump {
+    pub version: u32,
+    /// The schema the records are in, they are migrated once imported if it's older
+    pub schema_version: u32,
+    /// The meta counters, i.e. the next IDs to be handed out
+    pub logs_id: LogId,
+    pub objs_id: ObjId,
+    pub objs: Vec<DumpObj>,
+    pub logs: Vec<DumpLog>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpObj {
+    pub id: ObjId,
+    pub obj: AttrValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DumpLog {
+    pub id: LogId,
+    pub log: AttrValue,
+}
+
+/// The first line of a JSON Lines dump, i.e. a `Dump` without the records
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub schema_version: u32,
+    pub logs_id: LogId,
+    pub objs_id: ObjId,
+}
+
+/// A record line of a JSON Lines dump, told apart by whether it has `obj` or `log`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Line {
+    Obj(DumpObj),
+    Log(DumpLog),
+}
+
+/// Which records `Storage::export_lines` writes
+#[derive(Clone, Debug)]
+pub struct Filter {
+    /// Logs from this time on
+    pub since: Option<DateTime>,
+    /// Logs up to but not including this time
+    pub until: Option<DateTime>,
+    /// A glob over the types of objects and logs, e.g. `bio.*`
+    pub typ: Option<String>,
+    pub objs: bool,
+    pub logs: bool,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            since: None,
+            until: None,
+            typ: None,
+            objs: true,
+            logs: true,
+        }
+    }
+}
+
+/// What `export` wrote before dumps were versioned: the records in ID order, without their IDs
+#[derive(Deserialize)]
+struct LegacyDump {
+    logs: Vec<AttrValue>,
+    objs: Vec<AttrValue>,
+}
+
+/// Something wrong with a dump
+#[derive(Clone, Debug)]
+pub enum Problem {
+    DuplicateObj(ObjId),
+    DuplicateLog(LogId),
+    /// The record can't be read as an object or a log of its type
+    Malformed(EitherId, String),
+    /// The state object is missing
+    MissingState,
+    /// A task caches a sub task that doesn't exist
+    DanglingCache {
+        task: ObjId,
+        sub: ObjId,
+    },
+    /// A sub task belongs to a task that doesn't exist
+    OrphanSubTask {
+        sub: ObjId,
+        task: ObjId,
+    },
+    /// A log refers to a log that doesn't exist
+    DanglingLog {
+        log: LogId,
+        refers: LogId,
+    },
+    /// A counter would hand out an ID that's already taken
+    Counter {
+        key: &'static str,
+        current: IdType,
+        min: IdType,
+    },
+}
+
+impl Problem {
+    /// Whether `check` can fix it when asked to
+    pub fn repairable(&self) -> bool {
+        !matches!(
+            self,
+            Problem::DuplicateObj(_) | Problem::DuplicateLog(_) | Problem::Malformed(..)
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::DuplicateObj(id) => write!(f, "{} appears more than once", id),
+            Problem::DuplicateLog(id) => write!(f, "{} appears more than once", id),
+            Problem::Malformed(id, e) => write!(f, "{} is malformed: {}", id, e),
+            Problem::MissingState => write!(f, "the state object {} is missing", State::ID),
+            Problem::DanglingCache { task, sub } => write!(f, "{} caches the missing sub task {}", task, sub),
+            Problem::OrphanSubTask { sub, task } => write!(f, "{} belongs to the missing task {}", sub, task),
+            Problem::DanglingLog { log, refers } => write!(f, "{} refers to the missing log {}", log, refers),
+            Problem::Counter { key, current, min } => {
+                write!(f, "meta '{}' is {}, but IDs up to {} are taken", key, current, min - 1)
+            }
+        }
+    }
+}
+
+fn typed<T: DeserializeOwned>(val: &Value) -> std::result::Result<T, String> {
+    serde_json::from_value(val.clone()).map_err(|e| e.to_string())
+}
+
+/// Keeps the IDs in the array `key` of `val` that pass `keep`
+fn retain_ids<I: DeserializeOwned>(val: &mut Value, key: &str, keep: impl Fn(&I) -> bool) {
+    if let Some(ids) = val.get_mut(key).and_then(Value::as_array_mut) {
+        ids.retain(|id| serde_json::from_value(id.clone()).map_or(true, |id| keep(&id)));
+    }
+}
+
+impl Dump {
+    /// Reads a dump of any version
+    pub fn parse(s: &str) -> Result<Dump> {
+        let val: Value = serde_json::from_str(s)?;
+        match val.get("version").map(Value::as_u64) {
+            None => Ok(Dump::from_legacy(serde_json::from_value(val)?)),
+            Some(Some(version)) if version <= DUMP_VERSION as u64 => Ok(serde_json::from_value(val)?),
+            Some(version) => Err(Error::DumpTooNew(version.unwrap_or_default(), DUMP_VERSION)),
+        }
+    }
+
+    /// Reads a JSON Lines dump one line at a time
+    pub fn read_lines(input: impl BufRead) -> Result<Dump> {
+        let mut lines = input.lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).map_err(|e| Error::corrupt(e).at("line 1"))?,
+            None => return Err(Error::corrupt("the dump is empty")),
+        };
+        if header.version > DUMP_VERSION {
+            return Err(Error::DumpTooNew(header.version as u64, DUMP_VERSION));
+        }
+        let mut dump = Dump {
+            version: header.version,
+            schema_version: header.schema_version,
+            logs_id: header.logs_id,
+            objs_id: header.objs_id,
+            objs: Vec::new(),
+            logs: Vec::new(),
+        };
+        for (n, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line).map_err(|e| Error::corrupt(e).at(format!("line {}", n + 2)))? {
+                Line::Obj(obj) => dump.objs.push(obj),
+                Line::Log(log) => dump.logs.push(log),
+            }
+        }
+        Ok(dump)
+    }
+
+    /// Older dumps were read back by position, so that's what their IDs are taken to be
+    fn from_legacy(legacy: LegacyDump) -> Dump {
+        let objs = (0..)
+            .map(ObjId)
+            .zip(legacy.objs)
+            .map(|(id, obj)| DumpObj { id, obj })
+            .collect::<Vec<_>>();
+        let logs = (1..)
+            .map(LogId)
+            .zip(legacy.logs)
+            .map(|(id, log)| DumpLog { id, log })
+            .collect::<Vec<_>>();
+        Dump {
+            version: 0,
+            // From before the schema was versioned
+            schema_version: 0,
+            logs_id: LogId(logs.len() as IdType + 1),
+            objs_id: ObjId(objs.len() as IdType),
+            objs,
+            logs,
+        }
+    }
+
+    /// Brings the records to the current schema, like opening the store they're from would
+    pub fn upgrade(&mut self) -> Result<()> {
+        if self.schema_version > SCHEMA_VERSION {
+            return Err(Error::SchemaTooNew(self.schema_version, SCHEMA_VERSION));
+        }
+        let version = self.schema_version;
+        for m in MIGRATIONS.iter().filter(|m| m.version > version) {
+            for DumpObj { id, obj } in &mut self.objs {
+                (m.obj)(obj).map_err(|e| e.at(EitherId::Obj(*id)))?;
+            }
+            for DumpLog { id, log } in &mut self.logs {
+                (m.log)(log).map_err(|e| e.at(EitherId::Log(*id)))?;
+            }
+        }
+        self.schema_version = SCHEMA_VERSION;
+        Ok(())
+    }
+
+    /// Looks for records that are malformed or refer to ones that don't exist. With `repair`, the problems that
+    /// can be are fixed: dangling references are dropped, orphaned sub tasks removed, the counters raised past the
+    /// IDs in use and a missing state recreated.
+    pub fn check(&mut self, repair: bool) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut typs = BTreeMap::new();
+        for DumpObj { id, obj } in &self.objs {
+            match typed::<ProtoObj>(obj) {
+                Ok(proto) => {
+                    if typs.insert(*id, proto.typ).is_some() {
+                        problems.push(Problem::DuplicateObj(*id));
+                    }
+                }
+                Err(e) => problems.push(Problem::Malformed(EitherId::Obj(*id), e)),
+            }
+        }
+        let mut log_ids = BTreeSet::new();
+        for DumpLog { id, log } in &self.logs {
+            if let Err(e) = typed::<ProtoLog>(log) {
+                problems.push(Problem::Malformed(EitherId::Log(*id), e));
+            }
+            if !log_ids.insert(*id) {
+                problems.push(Problem::DuplicateLog(*id));
+            }
+        }
+
+        if !typs.contains_key(&State::ID) {
+            problems.push(Problem::MissingState);
+            if repair {
+                let state = RawObj {
+                    inner: State::new(),
+                    typ: State::OBJ_TYPE.into(),
+                    name: "state".into(),
+                    desc: None,
+                    attrs: None,
+                };
+                self.objs.insert(
+                    0,
+                    DumpObj {
+                        id: State::ID,
+                        obj: serde_json::to_value(state).unwrap(),
+                    },
+                );
+            }
+        }
+
+        let mut orphans = BTreeSet::new();
+        for DumpObj { id, obj } in &mut self.objs {
+            match typs.get(id).map(String::as_str) {
+                Some(Task::OBJ_TYPE) => {
+                    let task = match typed::<RawObj<Task>>(obj) {
+                        Ok(task) => task.inner,
+                        Err(e) => {
+                            problems.push(Problem::Malformed(EitherId::Obj(*id), e));
+                            continue;
+                        }
+                    };
+                    let is_sub = |sub: &ObjId| typs.get(sub).map(String::as_str) == Some(SubTask::OBJ_TYPE);
+                    for sub in task.cache.iter().filter(|sub| !is_sub(sub)) {
+                        problems.push(Problem::DanglingCache { task: *id, sub: *sub });
+                    }
+                    if repair {
+                        retain_ids(obj, "cache", is_sub);
+                    }
+                }
+                Some(SubTask::OBJ_TYPE) => match typed::<RawObj<SubTask>>(obj) {
+                    Ok(sub) if typs.get(&sub.inner.task_id).map(String::as_str) != Some(Task::OBJ_TYPE) => {
+                        problems.push(Problem::OrphanSubTask {
+                            sub: *id,
+                            task: sub.inner.task_id,
+                        });
+                        orphans.insert(*id);
+                    }
+                    Ok(_) => {}
+                    Err(e) => problems.push(Problem::Malformed(EitherId::Obj(*id), e)),
+                },
+                _ => {}
+            }
+        }
+
+        for DumpLog { id, log } in &mut self.logs {
+            let typ = log.get("typ").and_then(Value::as_str).unwrap_or_default();
+            // A missing log an undo or redo is about only makes redoing it fail, it's the listed ones that matter
+            let (listed, key) = match typ {
+                TaskFinish::LOG_TYPE => (typed::<RawLog<TaskFinish>>(log).map(|l| l.inner.caused), "caused"),
+                TaskSkip::LOG_TYPE => (typed::<RawLog<TaskSkip>>(log).map(|l| l.inner.caused), "caused"),
+                TaskMove::LOG_TYPE => (typed::<RawLog<TaskMove>>(log).map(|l| l.inner.caused), "caused"),
+                LogGroup::LOG_TYPE => (typed::<RawLog<LogGroup>>(log).map(|l| l.inner.caused), "caused"),
+                LogUndo::LOG_TYPE => (typed::<RawLog<LogUndo>>(log).map(|l| l.inner.logs), "logs"),
+                LogRedo::LOG_TYPE => (typed::<RawLog<LogRedo>>(log).map(|l| l.inner.logs), "logs"),
+                _ => continue,
+            };
+            let listed = match listed {
+                Ok(listed) => listed,
+                Err(e) => {
+                    problems.push(Problem::Malformed(EitherId::Log(*id), e));
+                    continue;
+                }
+            };
+            for refers in listed.into_iter().filter(|l| !log_ids.contains(l)) {
+                problems.push(Problem::DanglingLog { log: *id, refers });
+            }
+            if repair {
+                retain_ids(log, key, |l| log_ids.contains(l));
+            }
+        }
+
+        // IDs from other devices can't be handed out by this one anyway
+        let next_obj = next_id(self.objs_id.device(), self.objs.iter().map(|o| o.id.0));
+        if self.objs_id.0 < next_obj {
+            problems.push(Problem::Counter {
+                key: "objs_id",
+                current: self.objs_id.0,
+                min: next_obj,
+            });
+            if repair {
+                self.objs_id = ObjId(next_obj);
+            }
+        }
+        let next_log = next_id(self.logs_id.device(), self.logs.iter().map(|l| l.id.0));
+        if self.logs_id.0 < next_log {
+            problems.push(Problem::Counter {
+                key: "logs_id",
+                current: self.logs_id.0,
+                min: next_log,
+            });
+            if repair {
+                self.logs_id = LogId(next_log);
+            }
+        }
+        // Only now, so that the IDs of the removed sub tasks aren't handed out again
+        if repair {
+            self.objs.retain(|o| !orphans.contains(&o.id));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn obj(id: IdType, obj: Value) -> DumpObj {
+        DumpObj { id: ObjId(id), obj }
+    }
+
+    fn log(id: IdType, log: Value) -> DumpLog {
+        DumpLog { id: LogId(id), log }
+    }
+
+    #[test]
+    fn test_check() {
+        let time = "2021-01-01T00:00:00Z";
+        let mut dump = Dump {
+            version: DUMP_VERSION,
+            schema_version: 1,
+            logs_id: LogId(3),
+            objs_id: ObjId(3),
+            objs: vec![
+                obj(0, json!({ "typ": "sys.state", "name": "state" })),
+                obj(
+                    2,
+                    json!({ "typ": "task", "name": "t", "deadline": { "single": time }, "priority": 1, "cache": [3, 5] }),
+                ),
+                obj(
+                    3,
+                    json!({ "typ": "task.sub", "name": "s", "task-id": 2, "deadline": time }),
+                ),
+                obj(
+                    4,
+                    json!({ "typ": "task.sub", "name": "s", "task-id": 1, "deadline": time }),
+                ),
+            ],
+            logs: vec![log(
+                1,
+                json!({ "typ": "task.finish", "time": time, "id": 3, "caused": [1, 7] }),
+            )],
+        };
+        let problems = dump.check(false).iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "O2 caches the missing sub task O5",
+                "O4 belongs to the missing task O1",
+                "L1 refers to the missing log L7",
+                "meta 'objs_id' is 3, but IDs up to 4 are taken",
+            ]
+        );
+        assert_eq!(dump.objs.len(), 4);
+
+        assert!(dump.check(true).iter().all(Problem::repairable));
+        assert!(dump.check(false).is_empty());
+        assert_eq!(dump.objs[1].obj["cache"], json!([3]));
+        assert_eq!(dump.objs.len(), 3);
+        assert_eq!(dump.logs[0].log["caused"], json!([1]));
+        assert_eq!(dump.objs_id, ObjId(5));
+    }
+
+    #[test]
+    fn test_read_lines() {
+        let lines = r#"{ "version": 1, "schema_version": 1, "logs_id": 2, "objs_id": 2 }
+{ "id": 1, "obj": { "typ": "event" } }
+
+{ "id": 1, "log": { "typ": "obj.create" } }
+"#;
+        let dump = Dump::read_lines(lines.as_bytes()).unwrap();
+        assert_eq!((dump.objs.len(), dump.logs.len()), (1, 1));
+        assert_eq!(dump.logs[0].id, LogId(1));
+        assert!(matches!(
+            Dump::read_lines(&b"{ \"version\": 1, \"schema_version\": 1, \"logs_id\": 1, \"objs_id\": 1 }\n{}"[..]),
+            Err(Error::Corrupt { key: Some(ref k), .. }) if k == "line 2"
+        ));
+    }
+
+    #[test]
+    fn test_legacy() {
+        let dump = Dump::parse(r#"{ "logs": [{}, {}], "objs": [{}] }"#).unwrap();
+        assert_eq!((dump.version, dump.schema_version), (0, 0));
+        assert_eq!((dump.logs_id, dump.objs_id), (LogId(3), ObjId(1)));
+        assert_eq!(dump.logs[1].id, LogId(2));
+        assert!(matches!(
+            Dump::parse(r#"{ "version": 99 }"#),
+            Err(Error::DumpTooNew(99, DUMP_VERSION))
+        ));
+    }
+}
@@ -1,41 +1,51 @@
 use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashSet};
 use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
-use chrono::{TimeZone, Utc};
+use chrono::Utc;
+use regex::Regex;
 use serde::de::{Deserialize, DeserializeOwned};
 use serde_json::json;
-use sled::{Db, Tree};
 
+#[cfg(feature = "encryption")]
+use crate::storage::sealed::{SealedBackend, Secret};
+#[cfg(feature = "sqlite")]
+use crate::storage::sqlite::SqliteBackend;
 use crate::{
-    attrs,
     handler::{LogHandler, LogHandlers},
     storage::time::{DateTime, Duration},
-    storage::{api::*, Error, Error as StorageError, OptRepeated, Result as StorageResult},
+    storage::{
+        api::*,
+        archive::{Archive, Retention, Tombstone, ARCHIVE_FILE, RETENTION_FILE},
+        backend::{Backend, BackendTxn, KeyRange, KvPair, MemoryBackend, SledBackend, Tree, TxResult},
+        dump::{Dump, DumpLog, DumpObj, Filter, Header, Line, Problem, DUMP_VERSION},
+        migrate::{Migration, MigrationReport, MIGRATIONS, SCHEMA_VERSION},
+        replay::{changed_obj, diff_props, replay, Divergence, ObjMap},
+        sync::{self, SyncReport},
+        Error, OptRepeated, Result as StorageResult,
+    },
 };
 
 pub struct Storage {
-    db: Db,
-    meta: Tree,
-    logs: Tree,
-    objs: Tree,
+    backend: Box<dyn Backend>,
     handlers: Mutex<LogHandlers>,
-}
-
-fn ser_obj<S: Into<impl serde::Serialize> + ApiObj>(obj: S) -> Vec<u8> {
-    serde_json::to_vec(&obj.into()).unwrap()
-}
-
-fn ser_log<S: Into<impl serde::Serialize> + ApiLog>(log: S) -> Vec<u8> {
-    serde_json::to_vec(&log.into()).unwrap()
+    /// Where `archive_logs` moves the logs past their retention
+    archive: Option<Archive>,
+    retention: Mutex<Vec<Retention>>,
 }
 
 fn ser<S: ?Sized + serde::Serialize>(obj: &S) -> Vec<u8> {
     serde_json::to_vec(obj).unwrap()
 }
 
-fn deser<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> T {
-    serde_json::from_slice(bytes).unwrap()
+fn deser<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> StorageResult<T> {
+    serde_json::from_slice(bytes).map_err(Error::corrupt)
 }
 
 fn deser_obj<'de, T: ApiObj + Deserialize<'de>>(bytes: &'de [u8]) -> StorageResult<RawObj<T>> {
@@ -62,10 +72,6 @@ fn deser_log<'de, T: ApiLog + Deserialize<'de>>(bytes: &'de [u8]) -> StorageResu
     serde_json::from_slice(bytes).map_err(Error::Serde)
 }
 
-fn try_deser<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> serde_json::Result<T> {
-    serde_json::from_slice(bytes)
-}
-
 fn ser_obj_id(id: ObjId) -> Vec<u8> {
     id.0.to_be_bytes().to_vec()
 }
@@ -74,72 +80,157 @@ fn ser_log_id(id: LogId) -> Vec<u8> {
     id.0.to_be_bytes().to_vec()
 }
 
-fn deser_log_id(bytes: &[u8]) -> LogId {
-    LogId(u32::from_be_bytes(bytes.try_into().expect("malformed log id in db")))
+fn deser_u32(bytes: &[u8]) -> StorageResult<u32> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::corrupt(format!("malformed ID {:?}", bytes)))?;
+    Ok(u32::from_be_bytes(bytes))
 }
 
-fn deser_obj_id(bytes: &[u8]) -> ObjId {
-    ObjId(u32::from_be_bytes(bytes.try_into().expect("malformed obj id in db")))
+fn deser_log_id(bytes: &[u8]) -> StorageResult<LogId> {
+    deser_u32(bytes).map(LogId)
 }
 
-// We don't need meta cuz the ids are just the lengths of the arrays
-#[derive(Debug, Serialize, Deserialize)]
-struct DbData {
-    logs: Vec<serde_json::Value>,
-    objs: Vec<serde_json::Value>,
+fn deser_obj_id(bytes: &[u8]) -> StorageResult<ObjId> {
+    deser_u32(bytes).map(ObjId)
 }
 
-impl Storage {
-    pub fn new() -> Storage {
-        let config_dir = dirs::config_dir().unwrap().join("sched"); // FIXME
-        let db = sled::open(config_dir.join("sched.db")).unwrap();
-        let meta = db.open_tree("meta").unwrap();
-        if !meta.contains_key("logs_id").unwrap() {
-            meta.insert("logs_id", ser_log_id(LogId(1))).unwrap();
-        }
-        if !meta.contains_key("objs_id").unwrap() {
-            meta.insert("objs_id", ser_obj_id(ObjId(1))).unwrap();
-        }
-        let objs = db.open_tree("objs").unwrap();
-        let has_valid_state = objs
-            .get(&ser_obj_id(State::ID))
-            .unwrap()
-            .map(|o| {
-                let state: Result<RawObj<State>, _> = deser_obj(&o);
-                state.is_ok()
-            })
-            .unwrap_or(false);
-        if !has_valid_state {
-            objs.insert(ser_obj_id(State::ID), ser(&State::new())).unwrap();
-        }
-        Storage {
-            logs: db.open_tree("logs").unwrap(),
-            db,
-            meta,
-            objs,
-            handlers: Mutex::new(LogHandlers::new()),
-        }
+/// A log as read from `logs`
+fn deser_log_entry((k, v): KvPair) -> StorageResult<ScriptLog> {
+    let id = deser_log_id(&k)?;
+    Ok(deser::<ProtoLog>(&v).map_err(|e| e.at(id))?.with_id(id))
+}
+
+/// An object as read from `objs`, along with its ID
+fn deser_obj_entry<T: DeserializeOwned>((k, v): KvPair) -> StorageResult<(ObjId, T)> {
+    let id = deser_obj_id(&k)?;
+    Ok((id, deser(&v).map_err(|e| e.at(id))?))
+}
+
+fn type_prefix(typ: &str) -> Vec<u8> {
+    let mut key = typ.as_bytes().to_vec();
+    key.push(0);
+    key
+}
+
+fn type_key(typ: &str, id: ObjId) -> Vec<u8> {
+    let mut key = type_prefix(typ);
+    key.extend(ser_obj_id(id));
+    key
+}
+
+/// Just enough of an object to index it
+#[derive(Deserialize)]
+struct ObjTyp {
+    typ: String,
+}
+
+/// Just enough of a log to index it
+#[derive(Deserialize)]
+struct LogTyp {
+    typ: String,
+    time: DateTime,
+}
+
+const TIME_LEN: usize = 12;
+
+/// How many times `snapshot` exports before giving up on the store ever standing still
+const SNAPSHOT_TRIES: usize = 5;
+
+/// The write generation in the meta tree, 0 before anything has been written
+fn deser_generation(bytes: Option<Vec<u8>>) -> StorageResult<u64> {
+    match bytes {
+        Some(bytes) => Ok(u64::from_be_bytes(
+            bytes[..]
+                .try_into()
+                .map_err(|_| Error::corrupt("malformed generation in meta"))?,
+        )),
+        None => Ok(0),
     }
+}
 
-    #[cfg(features = "scripting")]
-    pub fn add_gluon<T: ApiLog>(&self, pat: &str, f: LogHandler) -> StorageResult<()> {
-        self.handlers.lock().unwrap().add_gluon(pat, f)
+/// Serializes a time so that the bytes sort the same way as the times
+fn ser_time(time: DateTime) -> Vec<u8> {
+    let mut key = ((time.0.timestamp() as u64) ^ (1 << 63)).to_be_bytes().to_vec();
+    key.extend(&time.0.timestamp_subsec_nanos().to_be_bytes());
+    key
+}
+
+/// The keys of a log in `logs_by_time` and `logs_by_type`
+fn log_index_keys(id: LogId, log: &[u8]) -> StorageResult<(Vec<u8>, Vec<u8>)> {
+    let LogTyp { typ, time } = deser(log).map_err(|e| e.at(id))?;
+    let mut by_time = ser_time(time);
+    by_time.extend(ser_log_id(id));
+    let mut by_type = type_prefix(&typ);
+    by_type.extend(&by_time);
+    Ok((by_time, by_type))
+}
+
+/// The logs for the changes the log `bytes` of type `typ` made along with its own, which are undone with it
+fn caused_logs(typ: &str, bytes: &[u8]) -> StorageResult<Vec<LogId>> {
+    Ok(match typ {
+        TaskFinish::LOG_TYPE => deser_log::<TaskFinish>(bytes)?.inner.caused,
+        TaskSkip::LOG_TYPE => deser_log::<TaskSkip>(bytes)?.inner.caused,
+        TaskMove::LOG_TYPE => deser_log::<TaskMove>(bytes)?.inner.caused,
+        LogGroup::LOG_TYPE => deser_log::<LogGroup>(bytes)?.inner.caused,
+        _ => Vec::new(),
+    })
+}
+
+/// A device none of the `ids` are from. It's picked at random, so that two stores are unlikely to pick the same one
+/// before they're ever synced; `sync` moves one of them to another if they do.
+fn free_device(ids: impl Iterator<Item = IdType>) -> Device {
+    let used = ids.map(device_of).collect::<BTreeSet<_>>();
+    let free = (1..=Device::MAX).filter(|d| !used.contains(d)).collect::<Vec<_>>();
+    let seed = RandomState::new().build_hasher().finish() as usize;
+    if free.is_empty() {
+        (seed % Device::MAX as usize) as Device + 1
+    } else {
+        free[seed % free.len()]
     }
+}
 
-    fn get_log_id(&self) -> LogId {
-        deser_log_id(
-            &self
-                .meta
-                .fetch_and_update("logs_id", |old| {
-                    Some(ser_log_id(LogId(deser_log_id(old.unwrap()).0 + 1)))
-                })
-                .unwrap()
-                .unwrap(),
-        )
+/// Turns a glob over log types (`*` for any run of characters, `?` for one) into a regex
+fn glob_regex(glob: &str) -> StorageResult<Regex> {
+    let pat = regex::escape(glob).replace("\\*", ".*").replace("\\?", ".");
+    Regex::new(&format!("^{}$", pat)).map_err(|_| Error::Regex(glob.to_string()))
+}
+
+/// The part of a glob before any wildcard, which every type it matches starts with
+fn glob_prefix(glob: &str) -> &str {
+    &glob[..glob.find(|c| c == '*' || c == '?').unwrap_or(glob.len())]
+}
+
+/// The trees as seen from inside one backend transaction. Logs appended through it are only queued here;
+/// `Storage::transaction` hands them to the log handlers once everything has been committed.
+struct Txn<'a> {
+    trees: &'a dyn BackendTxn,
+    appended: Vec<ScriptLog>,
+}
+
+impl Txn<'_> {
+    fn get_log_id(&self) -> TxResult<LogId> {
+        let id = match self.trees.get(Tree::Meta, b"logs_id")? {
+            Some(id) => deser_log_id(&id)?,
+            None => return Err(Error::corrupt("missing logs_id in meta").into()),
+        };
+        self.trees
+            .insert(Tree::Meta, b"logs_id", &ser_log_id(LogId(id.0 + 1)))?;
+        Ok(id)
+    }
+
+    fn get_obj_id(&self) -> TxResult<ObjId> {
+        let id = match self.trees.get(Tree::Meta, b"objs_id")? {
+            Some(id) => deser_obj_id(&id)?,
+            None => return Err(Error::corrupt("missing objs_id in meta").into()),
+        };
+        self.trees
+            .insert(Tree::Meta, b"objs_id", &ser_obj_id(ObjId(id.0 + 1)))?;
+        Ok(id)
     }
 
-    fn append_log_raw<L: ApiLog>(&self, log: L, attrs: Option<Attrs>) -> StorageResult<LogId> {
-        let id = self.get_log_id();
+    fn append_log_raw<L: ApiLog>(&mut self, log: L, attrs: Option<Attrs>) -> TxResult<LogId> {
+        let id = self.get_log_id()?;
         let raw = RawLog {
             attrs,
             time: DateTime::now(),
@@ -147,110 +238,84 @@ impl Storage {
             inner: log,
         };
         let serialized = ser(&raw);
-        let proto: ProtoLog = serde_json::from_slice(&serialized).unwrap();
-        self.logs.insert(ser_log_id(id), serialized).unwrap();
-        let log = proto.with_id(id);
-        self.handlers.lock().unwrap().handle(&log);
+        let proto: ProtoLog = deser(&serialized)?;
+        self.put_log(id, serialized)?;
+        self.appended.push(proto.with_id(id));
         Ok(id)
     }
 
-    pub fn append_log<L: ApiLog>(&self, log: L) -> StorageResult<LogId> {
-        Storage::append_log_raw(&self, log, None)
-    }
-
-    pub fn append_log_attr<L: ApiLog>(&self, log: L, attrs: Attrs) -> StorageResult<LogId> {
-        Storage::append_log_raw(&self, log, Some(attrs))
-    }
-
-    pub fn get_log<L: ApiLog + DeserializeOwned>(&self, id: LogId) -> StorageResult<Log<L>> {
-        self.logs
-            .get(ser_log_id(id))
-            .unwrap()
-            .map(|l| deser_log(&l).map(|r| r.with_id(id)))
-            .unwrap_or(Err(Error::InvalidLogID(id)))
-    }
-
-    fn filter_log_by<F: Fn(&ScriptLog) -> bool>(
-        iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<ScriptLog> {
-        let iter = iter
-            .map(|res| res.unwrap())
-            .map(|(k, v)| deser::<ProtoLog>(&v).with_id(deser_log_id(&k)))
-            .filter(filter);
-        if let Some(limit) = limit {
-            iter.take(limit).collect()
-        } else {
-            iter.collect()
+    /// Stores a log along with its entries in the time and type indexes
+    fn put_log(&self, id: LogId, log: Vec<u8>) -> TxResult<()> {
+        let (by_time, by_type) = log_index_keys(id, &log)?;
+        if let Some(old) = self.trees.insert(Tree::Logs, &ser_log_id(id), &log)? {
+            let (old_by_time, old_by_type) = log_index_keys(id, &old)?;
+            self.trees.remove(Tree::LogsByTime, &old_by_time)?;
+            self.trees.remove(Tree::LogsByType, &old_by_type)?;
         }
+        self.trees.insert(Tree::LogsByTime, &by_time, &[])?;
+        self.trees.insert(Tree::LogsByType, &by_type, &[])?;
+        Ok(())
     }
 
-    pub fn find_log<F: Fn(&ScriptLog) -> bool>(&self, filter: F, limit: Option<usize>) -> Vec<ScriptLog> {
-        Storage::filter_log_by(self.logs.iter().rev(), filter, limit)
-    }
-
-    pub fn find_log_old<F: Fn(&ScriptLog) -> bool>(&self, filter: F, limit: Option<usize>) -> Vec<ScriptLog> {
-        Storage::filter_log_by(self.logs.iter(), filter, limit)
+    fn append_log<L: ApiLog>(&mut self, log: L) -> TxResult<LogId> {
+        self.append_log_raw(log, None)
     }
 
-    pub fn find_log_from<F: Fn(&ScriptLog) -> bool>(
-        &self,
-        id: LogId,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<ScriptLog> {
-        Storage::filter_log_by(self.logs.range(..ser_log_id(id)).rev(), filter, limit)
+    /// Stores an object, keeping the type index in sync
+    fn put_obj(&self, id: ObjId, obj: Vec<u8>) -> TxResult<()> {
+        let typ = deser::<ObjTyp>(&obj).map_err(|e| e.at(id))?.typ;
+        if let Some(old) = self.trees.insert(Tree::Objs, &ser_obj_id(id), &obj)? {
+            let old_typ = deser::<ObjTyp>(&old).map_err(|e| e.at(id))?.typ;
+            self.trees.remove(Tree::ObjsByType, &type_key(&old_typ, id))?;
+        }
+        self.trees.insert(Tree::ObjsByType, &type_key(&typ, id), &[])?;
+        Ok(())
     }
 
-    pub fn find_log_old_from<F: Fn(&ScriptLog) -> bool>(
-        &self,
-        id: LogId,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<ScriptLog> {
-        Storage::filter_log_by(self.logs.range(ser_log_id(id)..), filter, limit)
+    /// Removes a log along with its entries in the time and type indexes
+    fn remove_log(&self, id: LogId) -> TxResult<()> {
+        if let Some(old) = self.trees.remove(Tree::Logs, &ser_log_id(id))? {
+            let (by_time, by_type) = log_index_keys(id, &old)?;
+            self.trees.remove(Tree::LogsByTime, &by_time)?;
+            self.trees.remove(Tree::LogsByType, &by_type)?;
+        }
+        Ok(())
     }
 
-    // Object stuff
-    fn get_obj_id(&self) -> ObjId {
-        deser_obj_id(
-            &self
-                .meta
-                .fetch_and_update("objs_id", |old| {
-                    Some(ser_obj_id(ObjId(deser_obj_id(old.unwrap()).0 + 1)))
-                })
-                .unwrap()
-                .unwrap(),
-        )
+    /// Removes an object along with its entry in the type index
+    fn remove_obj(&self, id: ObjId) -> TxResult<Option<Vec<u8>>> {
+        let old = self.trees.remove(Tree::Objs, &ser_obj_id(id))?;
+        if let Some(ref old) = old {
+            let old_typ = deser::<ObjTyp>(old).map_err(|e| e.at(id))?.typ;
+            self.trees.remove(Tree::ObjsByType, &type_key(&old_typ, id))?;
+        }
+        Ok(old)
     }
 
-    pub fn get_state(&self) -> StorageResult<State> {
-        Ok(self.get_obj(State::ID)?.inner)
+    fn get_proto_obj(&self, id: ObjId) -> TxResult<ProtoObj> {
+        let bytes = self
+            .trees
+            .get(Tree::Objs, &ser_obj_id(id))?
+            .ok_or(Error::InvalidObjID(id))?;
+        Ok(deser(&bytes).map_err(|e| e.at(id))?)
     }
 
-    pub fn set_state(&self, state: State) -> StorageResult<()> {
-        // TODO diff state and log?
-        self.set_obj(State::ID, state)
+    fn get_obj<O: ApiObj + DeserializeOwned>(&self, id: ObjId) -> TxResult<RawObj<O>> {
+        let bytes = self
+            .trees
+            .get(Tree::Objs, &ser_obj_id(id))?
+            .ok_or(Error::InvalidObjID(id))?;
+        Ok(deser_obj(&bytes)?)
     }
 
-    // pub fn state_get(&self, attr: &str) -> Option<AttrValue> {
-    //     let state: Option<Obj<State>> = self.get_obj(State::ID).ok();
-    //     state.map(|s| s.attrs.get(attr).cloned()).flatten()
-    // }
-
-    // pub fn state_set(&self, attr: &str, val: AttrValue) {
-    //     self.obj_set_attr(0, attr.to_owned(), val).unwrap();
-    // }
-
     fn create_obj_with_id<O: ApiObj>(
-        &self,
+        &mut self,
         id: ObjId,
         obj: O,
         name: String,
         desc: Option<String>,
         attrs: Option<Attrs>,
-    ) -> StorageResult<ObjId> {
+    ) -> TxResult<ObjId> {
         let obj = RawObj {
             inner: obj,
             name,
@@ -258,47 +323,65 @@ impl Storage {
             desc,
             attrs,
         };
-        self.objs.insert(ser_obj_id(id), ser(&obj)).unwrap();
+        let serialized = ser(&obj);
+        let created = deser(&serialized)?;
+        self.put_obj(id, serialized)?;
         self.append_log(CreateObj {
             id,
             typ: O::OBJ_TYPE.into(),
+            obj: Some(created),
         })?;
         Ok(id)
     }
 
-    pub fn create_obj<O: ApiObj>(
-        &self,
+    fn create_obj<O: ApiObj>(
+        &mut self,
         obj: O,
         name: String,
         desc: Option<String>,
         attrs: Option<Attrs>,
-    ) -> StorageResult<ObjId> {
-        let id = self.get_obj_id();
+    ) -> TxResult<ObjId> {
+        let id = self.get_obj_id()?;
         self.create_obj_with_id(id, obj, name, desc, attrs)
     }
 
-    pub fn create_obj_with<O: ApiObj>(
-        &self,
-        name: String,
-        desc: Option<String>,
-        attrs: Option<Attrs>,
-        f: impl FnOnce(ObjId) -> StorageResult<O>,
-    ) -> StorageResult<ObjId> {
-        let id = self.get_obj_id();
-        let obj = f(id)?;
-        self.create_obj_with_id(id, obj, name, desc, attrs)
+    /// Replaces the typed part of an object, keeping its name, description and attributes
+    fn set_obj<O: ApiObj>(&mut self, id: ObjId, obj: O) -> TxResult<()> {
+        let old = self.get_proto_obj(id)?;
+        if old.typ != O::OBJ_TYPE {
+            return Err(Error::TypeMismatch {
+                expected: O::OBJ_TYPE.into(),
+                actual: old.typ,
+            }
+            .into());
+        }
+        let obj = RawObj {
+            inner: obj,
+            name: old.name,
+            typ: old.typ,
+            desc: old.desc,
+            attrs: old.attrs,
+        };
+        let serialized = ser(&obj);
+        let new: ProtoObj = deser(&serialized)?;
+        self.put_obj(id, serialized)?;
+        let props = diff_props(&old.props, &new.props);
+        if !props.is_empty() {
+            self.append_log(ObjSetProps { id, props })?;
+        }
+        Ok(())
     }
 
-    pub fn obj_set_desc(&self, id: ObjId, desc: Option<String>) -> StorageResult<()> {
+    fn obj_set_desc(&mut self, id: ObjId, desc: Option<String>) -> TxResult<()> {
         let new_desc = desc.clone();
-        let mut obj: ProtoObj = deser(&self.objs.get(ser_obj_id(id))?.ok_or(StorageError::InvalidObjID(id))?);
+        let mut obj = self.get_proto_obj(id)?;
         if obj.desc.is_none() && desc.is_none() {
             // Simply skip cuz no change needs to be done
             return Ok(());
         }
         let old_desc = obj.desc.take();
         obj.desc = desc;
-        self.objs.insert(ser_obj_id(id), ser(&obj))?;
+        self.put_obj(id, ser(&obj))?;
         let diff = match (old_desc, new_desc) {
             (Some(o), Some(n)) => Diff::Diff(o, n),
             (None, Some(n)) => Diff::New(n),
@@ -309,21 +392,21 @@ impl Storage {
         Ok(())
     }
 
-    fn obj_set_attr_raw(&self, id: ObjId, attr: String, val: Option<AttrValue>) -> StorageResult<()> {
+    fn obj_set_attr(&mut self, id: ObjId, attr: String, val: Option<AttrValue>) -> TxResult<()> {
         let new_val = val.clone();
-        let mut obj: ProtoObj = deser(&self.objs.get(ser_obj_id(id))?.ok_or(StorageError::InvalidObjID(id))?);
-        let old_val = if let Some(ref mut attrs) = obj.attrs {
-            if !attrs.contains_key(&attr) && val.is_none() {
-                return Err(StorageError::DelNonExistent(id, attr));
-            }
-            match val {
-                Some(val) => attrs.insert(attr.clone(), val),
-                None => attrs.remove(&attr),
-            }
-        } else {
-            None
+        let mut obj = self.get_proto_obj(id)?;
+        let attrs = obj.attrs.get_or_insert_with(Attrs::new);
+        if !attrs.contains_key(&attr) && val.is_none() {
+            return Err(Error::DelNonExistent(id, attr).into());
+        }
+        let old_val = match val {
+            Some(val) => attrs.insert(attr.clone(), val),
+            None => attrs.remove(&attr),
         };
-        self.objs.insert(ser_obj_id(id), ser(&obj))?;
+        if attrs.is_empty() {
+            obj.attrs = None;
+        }
+        self.put_obj(id, ser(&obj))?;
         let diff = match (old_val, new_val) {
             (Some(o), Some(n)) => Diff::Diff(o, n),
             (None, Some(n)) => Diff::New(n),
@@ -334,183 +417,1123 @@ impl Storage {
         Ok(())
     }
 
-    pub fn obj_set_attr(&self, id: ObjId, attr: String, val: AttrValue) -> StorageResult<()> {
-        self.obj_set_attr_raw(id, attr, Some(val))
+    /// Recreates an object from its stored form, e.g. one that has been deleted
+    fn create_raw_obj(&mut self, id: ObjId, obj: AttrValue) -> TxResult<()> {
+        let typ = obj
+            .get("typ")
+            .and_then(AttrValue::as_str)
+            .unwrap_or_default()
+            .to_string();
+        self.put_obj(id, ser(&obj))?;
+        self.append_log(CreateObj {
+            id,
+            typ,
+            obj: Some(obj),
+        })?;
+        Ok(())
     }
 
-    pub fn obj_set_attrs(&self, id: ObjId, attrs: Attrs) -> StorageResult<()> {
-        attrs
-            .into_iter()
-            .map(|(key, val)| self.obj_set_attr(id, key, val))
-            .collect()
+    /// Reverts the changes recorded by the log `id`, appending logs for the compensating changes. Fails if the
+    /// object has changed since, so that nothing newer is silently overwritten.
+    fn undo_log(&mut self, id: LogId) -> TxResult<()> {
+        let bytes = self
+            .trees
+            .get(Tree::Logs, &ser_log_id(id))?
+            .ok_or(Error::InvalidLogID(id))?;
+        let proto: ProtoLog = deser(&bytes).map_err(|e| e.at(id))?;
+        let conflict = || Error::UndoConflict(id);
+        match proto.typ.as_str() {
+            CreateObj::LOG_TYPE => {
+                let create: CreateObj = deser_log(&bytes)?.inner;
+                let obj = match self.remove_obj(create.id)? {
+                    Some(obj) => obj,
+                    // A sub task deleted along with its task earlier in the same undo
+                    None if self
+                        .appended
+                        .iter()
+                        .any(|l| l.typ == ObjDelete::LOG_TYPE && changed_obj(l) == Some(create.id)) =>
+                    {
+                        return Ok(())
+                    }
+                    None => return Err(conflict().into()),
+                };
+                // The sub tasks of a task go along with it, rather than being left to one that doesn't exist
+                if create.typ == Task::OBJ_TYPE {
+                    let task: Task = deser_obj(&obj).map_err(|e| e.at(create.id))?.inner;
+                    for sub in task.cache {
+                        if self.trees.get(Tree::Objs, &ser_obj_id(sub))?.is_some() {
+                            self.delete_obj(sub)?;
+                        }
+                    }
+                }
+                self.append_log(ObjDelete {
+                    id: create.id,
+                    obj: deser(&obj).map_err(|e| e.at(create.id))?,
+                })?;
+            }
+            ObjDelete::LOG_TYPE => {
+                let delete: ObjDelete = deser_log(&bytes)?.inner;
+                if self.trees.get(Tree::Objs, &ser_obj_id(delete.id))?.is_some() {
+                    return Err(conflict().into());
+                }
+                self.create_raw_obj(delete.id, delete.obj)?;
+            }
+            ObjSetDesc::LOG_TYPE => {
+                let set: ObjSetDesc = deser_log(&bytes)?.inner;
+                if self.get_proto_obj(set.id)?.desc.as_ref() != set.diff.after() {
+                    return Err(conflict().into());
+                }
+                self.obj_set_desc(set.id, set.diff.before().cloned())?;
+            }
+            ObjSetAttr::LOG_TYPE => {
+                let set: ObjSetAttr = deser_log(&bytes)?.inner;
+                let obj = self.get_proto_obj(set.id)?;
+                if obj.attrs.as_ref().and_then(|attrs| attrs.get(&set.attr)) != set.diff.after() {
+                    return Err(conflict().into());
+                }
+                self.obj_set_attr(set.id, set.attr, set.diff.before().cloned())?;
+            }
+            ObjSetProps::LOG_TYPE => {
+                let set: ObjSetProps = deser_log(&bytes)?.inner;
+                let mut obj = self.get_proto_obj(set.id)?;
+                let mut props = ApiMap::new();
+                for (key, diff) in set.props {
+                    if obj.props.get(&key) != diff.after() {
+                        return Err(conflict().into());
+                    }
+                    match diff.before() {
+                        Some(val) => obj.props.insert(key.clone(), val.clone()),
+                        None => obj.props.remove(&key),
+                    };
+                    props.insert(key, diff.inverse());
+                }
+                self.put_obj(set.id, ser(&obj))?;
+                self.append_log(ObjSetProps { id: set.id, props })?;
+            }
+            TaskFinish::LOG_TYPE => {
+                let finish: TaskFinish = deser_log(&bytes)?.inner;
+                // Older finish logs don't know what else they changed
+                if finish.caused.is_empty() {
+                    return Err(Error::NotUndoable(id).into());
+                }
+                for &caused in finish.caused.iter().rev() {
+                    self.undo_log(caused)?;
+                }
+            }
+            TaskSkip::LOG_TYPE | TaskMove::LOG_TYPE | LogGroup::LOG_TYPE => {
+                for caused in caused_logs(&proto.typ, &bytes)?.into_iter().rev() {
+                    self.undo_log(caused)?;
+                }
+            }
+            _ => return Err(Error::NotUndoable(id).into()),
+        }
+        Ok(())
     }
 
-    pub fn obj_del_attr(&self, id: ObjId, attr: String) -> StorageResult<()> {
-        self.obj_set_attr_raw(id, attr, None)
+    fn delete_obj(&mut self, id: ObjId) -> TxResult<()> {
+        let obj = self.remove_obj(id)?.ok_or(Error::InvalidObjID(id))?;
+        self.append_log(ObjDelete {
+            id,
+            obj: deser(&obj).map_err(|e| e.at(id))?,
+        })?;
+        Ok(())
     }
 
-    pub fn get_obj<O: ApiObj + DeserializeOwned>(&self, id: ObjId) -> StorageResult<Obj<O>> {
-        self.objs
-            .get(ser_obj_id(id))
-            .unwrap()
-            .map(|o| deser_obj(&o).map(|r| r.with_id(id)))
-            .unwrap_or(Err(Error::InvalidObjID(id)))
+    fn new_sub_task(
+        &mut self,
+        id: ObjId,
+        deadline: DateTime,
+        notifications: Option<&Vec<Duration>>,
+    ) -> TxResult<ObjId> {
+        let sub = SubTask::new(id, deadline, notifications.cloned().unwrap_or_default());
+        self.create_obj(sub, "subtask".into(), None, None)
     }
+}
 
-    pub fn set_obj<O: ApiObj>(&self, id: ObjId, obj: O) -> StorageResult<()> {
-        // TODO diff props & attrs here?
-        self.objs.insert(ser_obj_id(id), ser(&obj))?;
-        Ok(())
+impl Storage {
+    /// The store of the user, in the config directory, along with its log archive and retention rules
+    pub fn new() -> StorageResult<Storage> {
+        let dir = Storage::user_dir()?;
+        let storage =
+            Storage::with_backend(Storage::user_backend()?)?.with_archive(Archive::new(dir.join(ARCHIVE_FILE)));
+        storage.set_retention(Retention::load(&dir.join(RETENTION_FILE))?);
+        Ok(storage)
     }
 
-    fn filter_script_obj_by<F: Fn(&ScriptObj) -> bool>(
-        iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<ScriptObj> {
-        let iter = iter
-            .map(|res| res.unwrap())
-            .map(|(k, v)| deser::<ProtoObj>(&v).with_id(deser_obj_id(&k)))
-            .filter(filter);
-        if let Some(limit) = limit {
-            iter.take(limit).collect()
-        } else {
-            iter.collect()
+    /// Where the store of the user and what goes with it are kept
+    pub fn user_dir() -> StorageResult<PathBuf> {
+        Ok(dirs::config_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?
+            .join("sched"))
+    }
+
+    /// The backend of the store of the user, sealed with the secret in the environment if there is one
+    pub fn user_backend() -> StorageResult<Box<dyn Backend>> {
+        Storage::sealed_from_env(Storage::user_db()?)
+    }
+
+    /// Seals `backend` with the secret set in the environment, or leaves it as it is if there's none set. A database
+    /// that isn't sealed yet gets sealed.
+    pub fn sealed_from_env(backend: Box<dyn Backend>) -> StorageResult<Box<dyn Backend>> {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(secret) = Secret::from_env() {
+                return Ok(Box::new(SealedBackend::open(backend, &secret)?));
+            }
         }
+        Ok(backend)
     }
 
-    fn filter_obj_by<O: ApiObj + DeserializeOwned, F: Fn(&Obj<O>) -> bool>(
-        iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<Obj<O>> {
-        let iter = iter
-            .map(|res| res.unwrap())
-            .map(|(k, v)| deser::<RawObj<O>>(&v).with_id(deser_obj_id(&k)))
-            .filter(filter);
-        if let Some(limit) = limit {
-            iter.take(limit).collect()
-        } else {
-            iter.collect()
+    /// The database of the store of the user as it is on disk, without unsealing it. A SQLite database is used in
+    /// place of the sled one once it has been migrated to.
+    pub fn user_db() -> StorageResult<Box<dyn Backend>> {
+        let config_dir = Storage::user_dir()?;
+        #[cfg(feature = "sqlite")]
+        {
+            let sqlite = config_dir.join("sched.sqlite");
+            if sqlite.exists() {
+                return Ok(Box::new(SqliteBackend::open(sqlite)?));
+            }
         }
+        Ok(Box::new(SledBackend::open(config_dir.join("sched.db"))?))
     }
 
-    pub fn find_obj<O: ApiObj + DeserializeOwned, F: Fn(&Obj<O>) -> bool>(
-        &self,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<Obj<O>> {
-        Storage::filter_obj_by(self.objs.iter().rev(), filter, limit)
+    /// Opens the sled database at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> StorageResult<Storage> {
+        Storage::with_backend(Box::new(SledBackend::open(path)?))
     }
 
-    pub fn script_find_obj<F: Fn(&ScriptObj) -> bool>(&self, filter: F, limit: Option<usize>) -> Vec<ScriptObj> {
-        Storage::filter_script_obj_by(self.objs.iter().rev(), filter, limit)
+    /// Opens the SQLite database at `path`, creating it if needed
+    #[cfg(feature = "sqlite")]
+    pub fn open_sqlite(path: impl AsRef<Path>) -> StorageResult<Storage> {
+        Storage::with_backend(Box::new(SqliteBackend::open(path)?))
     }
 
-    pub fn script_find_obj_old<F: Fn(&ScriptObj) -> bool>(&self, filter: F, limit: Option<usize>) -> Vec<ScriptObj> {
-        Storage::filter_script_obj_by(self.objs.iter(), filter, limit)
+    /// Opens the sled database at `path` sealed with `secret`, creating it if needed
+    #[cfg(feature = "encryption")]
+    pub fn open_sealed(path: impl AsRef<Path>, secret: &Secret) -> StorageResult<Storage> {
+        let backend = SealedBackend::open(Box::new(SledBackend::open(path)?), secret)?;
+        Storage::with_backend(Box::new(backend))
     }
 
-    pub fn script_find_obj_from<F: Fn(&ScriptObj) -> bool>(
-        &self,
-        id: ObjId,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<ScriptObj> {
-        Storage::filter_script_obj_by(self.objs.range(..ser_obj_id(id)).rev(), filter, limit)
+    /// A fresh store that only lives in memory
+    pub fn in_memory() -> StorageResult<Storage> {
+        Storage::with_backend(Box::new(MemoryBackend::new()))
     }
 
-    pub fn script_find_obj_old_from<F: Fn(&ScriptObj) -> bool>(
-        &self,
-        id: ObjId,
-        filter: F,
-        limit: Option<usize>,
-    ) -> Vec<ScriptObj> {
-        Storage::filter_script_obj_by(self.objs.range(ser_obj_id(id)..), filter, limit)
+    /// Moves the logs past their retention to `archive`
+    pub fn with_archive(mut self, archive: Archive) -> Storage {
+        self.archive = Some(archive);
+        self
     }
 
-    pub fn create_task(
-        &self,
-        name: String,
-        desc: Option<String>,
-        attrs: Option<Attrs>,
-        deadline: OptRepeated,
-        priority: u32,
-    ) -> StorageResult<ObjId> {
-        self.create_obj_with(name, desc, attrs, |id| {
-            // FIXME use batch (atomic) or transaction sematics
-            // TODO inherit notification from config 
-            let mut task = Task::new(deadline, priority, Vec::new());
-            match task.deadline {
-                OptRepeated::Single(time) => {
-                    dbg!(&time);
-                    let new_id = self.new_sub_task(id, time, None)?;
-                    task.cache.push(new_id);
-                }
-                OptRepeated::Repeat(ref mut repeat) => {
-                    // FIXME attribute casting should be an system error and should create log entry
-                    for _ in 0..task.gen_ahead {
-                        if let Some(next_time) = repeat.next() {
-                            let new_id = self.new_sub_task(id, next_time, None)?;
-                            task.cache.push(new_id);
-                        } else {
-                            break;
-                        }
-                    }
-                }
+    /// Opens a store over `backend`, migrating it to the current schema
+    pub fn with_backend(backend: Box<dyn Backend>) -> StorageResult<Storage> {
+        let storage = Storage::unmigrated(backend)?;
+        storage.migrate(false)?;
+        Ok(storage)
+    }
+
+    /// Opens a store over `backend` without migrating it, e.g. to see what `migrate` would do first
+    pub fn unmigrated(backend: Box<dyn Backend>) -> StorageResult<Storage> {
+        // The seal is only to be seen from below the backend that unseals
+        if backend.get(Tree::Meta, b"seal_salt")?.is_some() {
+            return Err(Error::Sealed);
+        }
+        let storage = Storage {
+            backend,
+            handlers: Mutex::new(LogHandlers::new()),
+            archive: None,
+            retention: Mutex::new(Vec::new()),
+        };
+        // Anything written before the schema was versioned is at version 0
+        let fresh = storage.backend.get(Tree::Meta, b"logs_id")?.is_none();
+        // A fresh store hands out IDs on a device of its own from the start, which it keeps
+        let device = if fresh { free_device(std::iter::empty()) } else { 0 };
+        // Databases from before the indexes have to be indexed once
+        let unindexed = if storage.backend.is_empty(Tree::ObjsByType)? {
+            storage
+                .backend
+                .iter(Tree::Objs)
+                .map(|res| {
+                    let (id, obj) = deser_obj_entry::<ObjTyp>(res?)?;
+                    Ok(type_key(&obj.typ, id))
+                })
+                .collect::<StorageResult<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        let unindexed_logs = if storage.backend.is_empty(Tree::LogsByTime)? {
+            storage
+                .backend
+                .iter(Tree::Logs)
+                .map(|res| {
+                    let (k, v) = res?;
+                    log_index_keys(deser_log_id(&k)?, &v)
+                })
+                .collect::<StorageResult<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        storage.transaction(|tx| {
+            for key in &unindexed {
+                tx.trees.insert(Tree::ObjsByType, key, &[])?;
             }
-            Ok(task)
-        })
+            for (by_time, by_type) in &unindexed_logs {
+                tx.trees.insert(Tree::LogsByTime, by_time, &[])?;
+                tx.trees.insert(Tree::LogsByType, by_type, &[])?;
+            }
+            if fresh {
+                tx.trees
+                    .insert(Tree::Meta, b"schema_version", &SCHEMA_VERSION.to_be_bytes())?;
+            }
+            if tx.trees.get(Tree::Meta, b"logs_id")?.is_none() {
+                tx.trees
+                    .insert(Tree::Meta, b"logs_id", &ser_log_id(LogId(first_id(device))))?;
+            }
+            if tx.trees.get(Tree::Meta, b"objs_id")?.is_none() {
+                tx.trees
+                    .insert(Tree::Meta, b"objs_id", &ser_obj_id(ObjId(first_id(device))))?;
+            }
+            let has_valid_state = tx
+                .trees
+                .get(Tree::Objs, &ser_obj_id(State::ID))?
+                .map(|o| {
+                    let state: Result<RawObj<State>, _> = deser_obj(&o);
+                    state.is_ok()
+                })
+                .unwrap_or(false);
+            if !has_valid_state {
+                tx.create_obj_with_id(State::ID, State::new(), "state".into(), None, None)?;
+            }
+            Ok(())
+        })?;
+        // Stores from before there were devices are moved to one of their own, once
+        if storage.device()? == 0 {
+            let (log_ids, obj_ids) = storage.ids()?;
+            let ids = log_ids.iter().map(|id| id.0).chain(obj_ids.iter().map(|id| id.0));
+            storage.set_device(free_device(ids))?;
+        }
+        Ok(storage)
     }
 
-    fn new_sub_task(&self, id: ObjId, deadline: DateTime, notifications: Option<&Vec<Duration>>) -> StorageResult<ObjId> {
-        self.create_obj_with("subtask".into(), None, None, |_| {
-            Ok(SubTask::new(id, deadline, notifications.cloned().unwrap_or_default()))
-        })
+    /// The device this store hands out IDs on, the one its counters are on
+    pub fn device(&self) -> StorageResult<Device> {
+        Ok(device_of(self.counter(b"logs_id")?))
     }
 
-    // FIXME Error on finished tasks? Or how to handle collision
-    /// id is the id for the sub task
-    pub fn task_finish(&self, id: ObjId, finished: DateTime) -> StorageResult<()> {
-        let mut sub: SubTask = self.get_obj(id)?.inner;
-        let mut task: Task = self.get_obj(sub.task_id)?.inner;
-        if let OptRepeated::Repeat(ref mut repeat) = task.deadline {
-            let cache_size = task.cache_size + task.gen_ahead + 1;
-            if let Some(next_time) = repeat.next() {
-                let new_id = self.new_sub_task(id, next_time, None)?;
-                // We only generate one cuz there can be only 1 task completed
-                task.cache.push(new_id);
-                if task.cache.len() > cache_size as usize {
-                    task.cache.remove(0);
-                }
-            }
-            sub.finished = Some(finished);
-            self.set_obj(sub.task_id, task)?;
-            self.set_obj(id, sub)?;
+    /// Hands out IDs on `device` from now on, after any it already has records with
+    pub fn set_device(&self, device: Device) -> StorageResult<()> {
+        if device == 0 {
+            return Err(Error::ReservedDevice);
         }
-        // FIXME missing logs on props & attrs setting
-        self.append_log(TaskFinish { id })?;
-        Ok(())
+        let (log_ids, obj_ids) = self.ids()?;
+        self.transaction(|tx| {
+            let logs_id = next_id(device, log_ids.iter().map(|id| id.0));
+            let objs_id = next_id(device, obj_ids.iter().map(|id| id.0));
+            tx.trees.insert(Tree::Meta, b"logs_id", &ser_log_id(LogId(logs_id)))?;
+            tx.trees.insert(Tree::Meta, b"objs_id", &ser_obj_id(ObjId(objs_id)))?;
+            Ok(())
+        })
     }
 
-    pub fn find_current(&self, id: ObjId) -> StorageResult<Option<ObjId>> {
+    /// The IDs in use, counting the archived logs
+    fn ids(&self) -> StorageResult<(Vec<LogId>, Vec<ObjId>)> {
+        let logs = self.backend.iter(Tree::Logs).map(|res| deser_log_id(&res?.0));
+        let objs = self.backend.iter(Tree::Objs).map(|res| deser_obj_id(&res?.0));
+        let mut logs = logs.collect::<StorageResult<Vec<_>>>()?;
+        logs.extend(self.tombstone()?.last);
+        Ok((logs, objs.collect::<StorageResult<_>>()?))
+    }
+
+    pub fn schema_version(&self) -> StorageResult<u32> {
+        self.backend
+            .get(Tree::Meta, b"schema_version")?
+            .map_or(Ok(0), |v| deser_u32(&v).map_err(|e| e.at("schema_version")))
+    }
+
+    /// Rewrites the records with the migration steps the database hasn't been through yet, and reports what each
+    /// of them has rewritten. Nothing is written if `dry_run` is set.
+    pub fn migrate(&self, dry_run: bool) -> StorageResult<Vec<MigrationReport>> {
+        let version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::SchemaTooNew(version, SCHEMA_VERSION));
+        }
+        let pending = MIGRATIONS.iter().filter(|m| m.version > version).collect::<Vec<_>>();
+        if pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut reports = pending
+            .iter()
+            .map(|m| MigrationReport {
+                version: m.version,
+                desc: m.desc,
+                rewritten: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let mut rewrite = |record: &[u8],
+                           id: EitherId,
+                           step: fn(&Migration) -> fn(&mut AttrValue) -> StorageResult<bool>|
+         -> StorageResult<Option<Vec<u8>>> {
+            let mut val: AttrValue = deser(record).map_err(|e| e.at(id))?;
+            let mut changed = false;
+            for (m, report) in pending.iter().zip(reports.iter_mut()) {
+                if step(m)(&mut val).map_err(|e| e.at(id))? {
+                    report.rewritten.push(id);
+                    changed = true;
+                }
+            }
+            Ok(if changed { Some(ser(&val)) } else { None })
+        };
+        let mut objs = Vec::new();
+        for res in self.backend.iter(Tree::Objs) {
+            let (k, v) = res?;
+            let id = deser_obj_id(&k)?;
+            if let Some(obj) = rewrite(&v, EitherId::Obj(id), |m| m.obj)? {
+                objs.push((id, obj));
+            }
+        }
+        let mut logs = Vec::new();
+        for res in self.backend.iter(Tree::Logs) {
+            let (k, v) = res?;
+            let id = deser_log_id(&k)?;
+            if let Some(log) = rewrite(&v, EitherId::Log(id), |m| m.log)? {
+                logs.push((id, log));
+            }
+        }
+        if !dry_run {
+            self.transaction(|tx| {
+                for (id, obj) in &objs {
+                    tx.put_obj(*id, obj.clone())?;
+                }
+                for (id, log) in &logs {
+                    tx.put_log(*id, log.clone())?;
+                }
+                tx.trees
+                    .insert(Tree::Meta, b"schema_version", &SCHEMA_VERSION.to_be_bytes())?;
+                Ok(())
+            })?;
+        }
+        Ok(reports)
+    }
+
+    /// Runs `f` in a single transaction over all the trees, then dispatches the logs it appended to the handlers.
+    /// `f` may be run more than once if the transaction conflicts, so it shouldn't have side effects of its own.
+    fn transaction<T>(&self, f: impl Fn(&mut Txn) -> TxResult<T>) -> StorageResult<T> {
+        let mut done = None;
+        self.backend.transaction(&mut |trees| {
+            let mut tx = Txn {
+                trees,
+                appended: Vec::new(),
+            };
+            let ret = f(&mut tx)?;
+            // Bumped by every write, so that reading over several calls can tell whether anything changed meanwhile
+            let generation = deser_generation(tx.trees.get(Tree::Meta, b"generation")?)?;
+            tx.trees
+                .insert(Tree::Meta, b"generation", &(generation + 1).to_be_bytes())?;
+            done = Some((ret, tx.appended));
+            Ok(())
+        })?;
+        let (ret, appended) = done.expect("transaction committed without running");
+        let mut handlers = self.handlers.lock().unwrap();
+        for log in &appended {
+            handlers.handle(log);
+        }
+        Ok(ret)
+    }
+
+    #[cfg(features = "scripting")]
+    pub fn add_gluon<T: ApiLog>(&self, pat: &str, f: LogHandler) -> StorageResult<()> {
+        self.handlers.lock().unwrap().add_gluon(pat, f)
+    }
+
+    pub fn append_log<L: ApiLog>(&self, log: L) -> StorageResult<LogId> {
+        self.transaction(|tx| tx.append_log(log.clone()))
+    }
+
+    pub fn append_log_attr<L: ApiLog>(&self, log: L, attrs: Attrs) -> StorageResult<LogId> {
+        self.transaction(|tx| tx.append_log_raw(log.clone(), Some(attrs.clone())))
+    }
+
+    pub fn get_log<L: ApiLog + DeserializeOwned>(&self, id: LogId) -> StorageResult<Log<L>> {
+        self.backend
+            .get(Tree::Logs, &ser_log_id(id))?
+            .map(|l| deser_log(&l).map(|r| r.with_id(id)))
+            .unwrap_or(Err(Error::InvalidLogID(id)))
+    }
+
+    /// The logs in `range` of `logs_by_time`, i.e. in the order they happened, which is the order `sync::merge`
+    /// replays them in. Their IDs only order the logs of each device.
+    fn logs_by_time<'a>(&'a self, range: KeyRange) -> impl DoubleEndedIterator<Item = StorageResult<KvPair>> + 'a {
+        self.backend.range(Tree::LogsByTime, range).map(move |res| {
+            let id = deser_log_id(res?.0.get(TIME_LEN..).unwrap_or_default())?;
+            let key = ser_log_id(id);
+            let bytes = self.backend.get(Tree::Logs, &key)?.ok_or(Error::InvalidLogID(id))?;
+            Ok((key, bytes))
+        })
+    }
+
+    /// The key of the log `id` in `logs_by_time`
+    fn log_time_key(&self, id: LogId) -> StorageResult<Vec<u8>> {
+        let bytes = self
+            .backend
+            .get(Tree::Logs, &ser_log_id(id))?
+            .ok_or(Error::InvalidLogID(id))?;
+        Ok(log_index_keys(id, &bytes)?.0)
+    }
+
+    fn filter_log_by<F: Fn(&ScriptLog) -> bool>(
+        iter: impl Iterator<Item = StorageResult<ScriptLog>>,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptLog>> {
+        // Errors are let through the filter, so that they end up in the result
+        let iter = iter.filter(|res| res.as_ref().map_or(true, &filter));
+        if let Some(limit) = limit {
+            iter.take(limit).collect()
+        } else {
+            iter.collect()
+        }
+    }
+
+    /// The logs matching `filter`, newest first, going through the archived ones as well with `include_archived`
+    pub fn find_log<F: Fn(&ScriptLog) -> bool>(
+        &self,
+        filter: F,
+        limit: Option<usize>,
+        include_archived: bool,
+    ) -> StorageResult<Vec<ScriptLog>> {
+        let mut live = self
+            .logs_by_time((Bound::Unbounded, Bound::Unbounded))
+            .rev()
+            .map(|res| deser_log_entry(res?))
+            .peekable();
+        if !include_archived {
+            return Storage::filter_log_by(live, filter, limit);
+        }
+        // Archived by type, so they aren't all older than the ones kept, and the two are merged as they go
+        let mut archived = self.archived_logs()?.into_iter().rev().peekable();
+        let mut live_ids = HashSet::new();
+        let merged = std::iter::from_fn(|| loop {
+            let live_first = match (live.peek(), archived.peek()) {
+                (Some(Ok(l)), Some(a)) => (l.time, l.id) >= (a.time, a.id),
+                (Some(_), _) => true,
+                (None, Some(_)) => false,
+                (None, None) => return None,
+            };
+            if live_first {
+                let log = live.next()?;
+                if let Ok(log) = &log {
+                    live_ids.insert(log.id);
+                }
+                return Some(log);
+            }
+            let log = archived.next()?;
+            // Still in the database if it went down after archiving them, where it sorts right before
+            if !live_ids.contains(&log.id) {
+                return Some(Ok(log));
+            }
+        });
+        Storage::filter_log_by(merged, filter, limit)
+    }
+
+    /// The logs in the archive, oldest first
+    pub fn archived_logs(&self) -> StorageResult<Vec<ScriptLog>> {
+        let archive = match &self.archive {
+            Some(archive) => archive,
+            None => return Ok(Vec::new()),
+        };
+        let mut logs = archive
+            .read(&*self.backend)?
+            .into_iter()
+            .map(|DumpLog { id, log }| {
+                let proto: ProtoLog = serde_json::from_value(log).map_err(|e| Error::corrupt(e).at(id))?;
+                Ok(proto.with_id(id))
+            })
+            .collect::<StorageResult<Vec<_>>>()?;
+        logs.sort_by_key(|l| (l.time, l.id));
+        Ok(logs)
+    }
+
+    /// What's left in the database of the archived logs
+    pub fn tombstone(&self) -> StorageResult<Tombstone> {
+        match self.backend.get(Tree::Meta, b"archived")? {
+            Some(bytes) => deser(&bytes),
+            None => Ok(Tombstone::default()),
+        }
+    }
+
+    pub fn retention(&self) -> Vec<Retention> {
+        self.retention.lock().unwrap().clone()
+    }
+
+    pub fn set_retention(&self, rules: Vec<Retention>) {
+        *self.retention.lock().unwrap() = rules;
+    }
+
+    /// Moves the logs that are past their retention at `now` to the archive, leaving a tombstone behind, and
+    /// returns them. They're only looked for with `dry_run`.
+    pub fn archive_logs(&self, now: DateTime, dry_run: bool) -> StorageResult<Vec<LogId>> {
+        let archive = self.archive.as_ref().ok_or(Error::NoArchive)?;
+        let rules = self
+            .retention()
+            .iter()
+            .map(|r| {
+                let cutoff = DateTime(now.0 - chrono::Duration::days(r.days.into()));
+                Ok((glob_regex(&r.types)?, ser_time(cutoff)))
+            })
+            .collect::<StorageResult<Vec<_>>>()?;
+        let latest = match rules.iter().map(|(_, cutoff)| cutoff).max() {
+            Some(latest) => latest.clone(),
+            None => return Ok(Vec::new()),
+        };
+        let mut old = Vec::new();
+        for res in self
+            .backend
+            .range(Tree::LogsByTime, (Bound::Unbounded, Bound::Excluded(latest)))
+        {
+            let key = res?.0;
+            let id = deser_log_id(key.get(TIME_LEN..).unwrap_or_default())?;
+            let bytes = self
+                .backend
+                .get(Tree::Logs, &ser_log_id(id))?
+                .ok_or(Error::InvalidLogID(id))?;
+            let LogTyp { typ, .. } = deser(&bytes).map_err(|e| e.at(id))?;
+            // The first rule for the type is the one that applies
+            if let Some((_, cutoff)) = rules.iter().find(|(pat, _)| pat.is_match(&typ)) {
+                if key[..TIME_LEN] < cutoff[..] {
+                    old.push((id, typ, bytes));
+                }
+            }
+        }
+        let ids = old.iter().map(|(id, _, _)| *id).collect();
+        if dry_run || old.is_empty() {
+            return Ok(ids);
+        }
+        let logs = old
+            .iter()
+            .map(|(id, _, bytes)| {
+                Ok(DumpLog {
+                    id: *id,
+                    log: deser(bytes)?,
+                })
+            })
+            .collect::<StorageResult<Vec<_>>>()?;
+        // Archived before they're removed, so that going down in between leaves them in both rather than neither
+        archive.append(&*self.backend, &logs)?;
+        let mut tombstone = self.tombstone()?;
+        for (id, typ, _) in &old {
+            tombstone.add(*id, typ);
+        }
+        self.transaction(|tx| {
+            for (id, _, _) in &old {
+                tx.remove_log(*id)?;
+            }
+            tx.trees.insert(Tree::Meta, b"archived", &ser(&tombstone))?;
+            Ok(())
+        })?;
+        self.flush()?;
+        Ok(ids)
+    }
+
+    pub fn find_log_old<F: Fn(&ScriptLog) -> bool>(
+        &self,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptLog>> {
+        Storage::filter_log_by(
+            self.logs_by_time((Bound::Unbounded, Bound::Unbounded))
+                .map(|res| deser_log_entry(res?)),
+            filter,
+            limit,
+        )
+    }
+
+    pub fn find_log_from<F: Fn(&ScriptLog) -> bool>(
+        &self,
+        id: LogId,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptLog>> {
+        let from = self.log_time_key(id)?;
+        Storage::filter_log_by(
+            self.logs_by_time((Bound::Unbounded, Bound::Excluded(from)))
+                .rev()
+                .map(|res| deser_log_entry(res?)),
+            filter,
+            limit,
+        )
+    }
+
+    pub fn find_log_old_from<F: Fn(&ScriptLog) -> bool>(
+        &self,
+        id: LogId,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptLog>> {
+        let from = self.log_time_key(id)?;
+        Storage::filter_log_by(
+            self.logs_by_time((Bound::Included(from), Bound::Unbounded))
+                .map(|res| deser_log_entry(res?)),
+            filter,
+            limit,
+        )
+    }
+
+    /// The logs from `from` up to but not including `to`, oldest first, whose type matches `type_glob` (e.g.
+    /// `bio.*`). Only the parts of the indexes in the range are scanned; the literal prefix of the glob narrows
+    /// the scan down to the matching types.
+    pub fn logs_between(&self, from: DateTime, to: DateTime, type_glob: &str) -> StorageResult<Vec<ScriptLog>> {
+        let pat = glob_regex(type_glob)?;
+        let (from, to) = (ser_time(from), ser_time(to));
+        // Keys of the `logs_by_time` form, i.e. `time id`
+        let mut found = Vec::new();
+        let prefix = glob_prefix(type_glob);
+        if prefix.is_empty() {
+            for res in self
+                .backend
+                .range(Tree::LogsByTime, (Bound::Included(from), Bound::Excluded(to)))
+            {
+                found.push(res?.0);
+            }
+        } else {
+            // Visit each type under the prefix and range scan its part of the index
+            let mut cursor = prefix.as_bytes().to_vec();
+            while let Some((key, _)) = self.backend.get_gt(Tree::LogsByType, &cursor)? {
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+                let typ_len = key.iter().position(|&b| b == 0).unwrap_or(key.len());
+                let typ = &key[..typ_len];
+                if pat.is_match(&String::from_utf8_lossy(typ)) {
+                    let start = [typ, &[0], &from].concat();
+                    let end = [typ, &[0], &to].concat();
+                    for res in self
+                        .backend
+                        .range(Tree::LogsByType, (Bound::Included(start), Bound::Excluded(end)))
+                    {
+                        found.push(res?.0[typ_len + 1..].to_vec());
+                    }
+                }
+                // Skips past all the keys of this type, as `typ \1` sorts right after `typ \0 ...`
+                cursor = [typ, &[1]].concat();
+            }
+            found.sort();
+        }
+        let mut logs = Vec::with_capacity(found.len());
+        for key in found {
+            let id = deser_log_id(key.get(TIME_LEN..).unwrap_or_default())?;
+            let bytes = self
+                .backend
+                .get(Tree::Logs, &ser_log_id(id))?
+                .ok_or(Error::InvalidLogID(id))?;
+            let log = deser_log_entry((ser_log_id(id), bytes))?;
+            if pat.is_match(&log.typ) {
+                logs.push(log);
+            }
+        }
+        Ok(logs)
+    }
+
+    // Object stuff
+    pub fn get_state(&self) -> StorageResult<State> {
+        Ok(self.get_obj(State::ID)?.inner)
+    }
+
+    pub fn set_state(&self, state: State) -> StorageResult<()> {
+        // TODO diff state and log?
+        self.set_obj(State::ID, state)
+    }
+
+    // pub fn state_get(&self, attr: &str) -> Option<AttrValue> {
+    //     let state: Option<Obj<State>> = self.get_obj(State::ID).ok();
+    //     state.map(|s| s.attrs.get(attr).cloned()).flatten()
+    // }
+
+    // pub fn state_set(&self, attr: &str, val: AttrValue) {
+    //     self.obj_set_attr(0, attr.to_owned(), val).unwrap();
+    // }
+
+    pub fn create_obj<O: ApiObj>(
+        &self,
+        obj: O,
+        name: String,
+        desc: Option<String>,
+        attrs: Option<Attrs>,
+    ) -> StorageResult<ObjId> {
+        self.transaction(|tx| tx.create_obj(obj.clone(), name.clone(), desc.clone(), attrs.clone()))
+    }
+
+    pub fn obj_set_desc(&self, id: ObjId, desc: Option<String>) -> StorageResult<()> {
+        self.transaction(|tx| tx.obj_set_desc(id, desc.clone()))
+    }
+
+    pub fn obj_set_attr(&self, id: ObjId, attr: String, val: AttrValue) -> StorageResult<()> {
+        self.transaction(|tx| tx.obj_set_attr(id, attr.clone(), Some(val.clone())))
+    }
+
+    pub fn obj_set_attrs(&self, id: ObjId, attrs: Attrs) -> StorageResult<()> {
+        self.transaction(|tx| {
+            attrs
+                .iter()
+                .map(|(key, val)| tx.obj_set_attr(id, key.clone(), Some(val.clone())))
+                .collect()
+        })
+    }
+
+    pub fn obj_del_attr(&self, id: ObjId, attr: String) -> StorageResult<()> {
+        self.transaction(|tx| tx.obj_set_attr(id, attr.clone(), None))
+    }
+
+    pub fn get_obj<O: ApiObj + DeserializeOwned>(&self, id: ObjId) -> StorageResult<Obj<O>> {
+        self.backend
+            .get(Tree::Objs, &ser_obj_id(id))?
+            .map(|o| deser_obj(&o).map(|r| r.with_id(id)))
+            .unwrap_or(Err(Error::InvalidObjID(id)))
+    }
+
+    pub fn set_obj<O: ApiObj>(&self, id: ObjId, obj: O) -> StorageResult<()> {
+        // TODO diff props & attrs here?
+        self.transaction(|tx| tx.set_obj(id, obj.clone()))
+    }
+
+    fn filter_script_obj_by<F: Fn(&ScriptObj) -> bool>(
+        iter: impl Iterator<Item = StorageResult<KvPair>>,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        let iter = iter
+            .map(|res| deser_obj_entry::<ProtoObj>(res?).map(|(id, o)| o.with_id(id)))
+            .filter(|res| res.as_ref().map_or(true, &filter));
+        if let Some(limit) = limit {
+            iter.take(limit).collect()
+        } else {
+            iter.collect()
+        }
+    }
+
+    fn filter_obj_by<O: ApiObj + DeserializeOwned, F: Fn(&Obj<O>) -> bool>(
+        iter: impl Iterator<Item = StorageResult<KvPair>>,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<Obj<O>>> {
+        let iter = iter
+            .map(|res| deser_obj_entry::<RawObj<O>>(res?).map(|(id, o)| o.with_id(id)))
+            .filter(|res| res.as_ref().map_or(true, &filter));
+        if let Some(limit) = limit {
+            iter.take(limit).collect()
+        } else {
+            iter.collect()
+        }
+    }
+
+    /// The objects of type `typ` from the type index, oldest first
+    fn objs_of_type<'a>(&'a self, typ: &str) -> impl DoubleEndedIterator<Item = StorageResult<KvPair>> + 'a {
+        self.objs_of_type_in(typ, (Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// The objects of type `typ` with IDs in `ids` from the type index, oldest first. Only that part of the index
+    /// is scanned.
+    fn objs_of_type_in<'a>(
+        &'a self,
+        typ: &str,
+        (from, to): (Bound<ObjId>, Bound<ObjId>),
+    ) -> impl DoubleEndedIterator<Item = StorageResult<KvPair>> + 'a {
+        // The keys of a type are `typ\0id`, all before `typ\1`
+        let mut end = type_prefix(typ);
+        *end.last_mut().unwrap() = 1;
+        let key = |bound, unbounded| match bound {
+            Bound::Included(id) => Bound::Included(type_key(typ, id)),
+            Bound::Excluded(id) => Bound::Excluded(type_key(typ, id)),
+            Bound::Unbounded => unbounded,
+        };
+        let range = (
+            key(from, Bound::Included(type_prefix(typ))),
+            key(to, Bound::Excluded(end)),
+        );
+        self.backend
+            .range(Tree::ObjsByType, range)
+            .map(move |res| {
+                let (k, _) = res?;
+                if k.len() < 4 {
+                    return Err(Error::corrupt(format!("malformed type index key {:?}", k)));
+                }
+                let id = k[k.len() - 4..].to_vec();
+                Ok(self.backend.get(Tree::Objs, &id)?.map(|v| (id, v)))
+            })
+            .filter_map(StorageResult::transpose)
+    }
+
+    /// Only looks at the objects of type `O`, through the type index
+    pub fn find_obj<O: ApiObj + DeserializeOwned, F: Fn(&Obj<O>) -> bool>(
+        &self,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<Obj<O>>> {
+        Storage::filter_obj_by(self.objs_of_type(O::OBJ_TYPE).rev(), filter, limit)
+    }
+
+    pub fn find_obj_old<O: ApiObj + DeserializeOwned, F: Fn(&Obj<O>) -> bool>(
+        &self,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<Obj<O>>> {
+        Storage::filter_obj_by(self.objs_of_type(O::OBJ_TYPE), filter, limit)
+    }
+
+    pub fn find_obj_by_type<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        typ: &str,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        Storage::filter_script_obj_by(self.objs_of_type(typ).rev(), filter, limit)
+    }
+
+    pub fn find_obj_by_type_old<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        typ: &str,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        Storage::filter_script_obj_by(self.objs_of_type(typ), filter, limit)
+    }
+
+    pub fn find_obj_by_type_from<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        typ: &str,
+        id: ObjId,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        let iter = self.objs_of_type_in(typ, (Bound::Unbounded, Bound::Excluded(id))).rev();
+        Storage::filter_script_obj_by(iter, filter, limit)
+    }
+
+    pub fn find_obj_by_type_old_from<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        typ: &str,
+        id: ObjId,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        let iter = self.objs_of_type_in(typ, (Bound::Included(id), Bound::Unbounded));
+        Storage::filter_script_obj_by(iter, filter, limit)
+    }
+
+    pub fn script_find_obj<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        Storage::filter_script_obj_by(self.backend.iter(Tree::Objs).rev(), filter, limit)
+    }
+
+    pub fn script_find_obj_old<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        Storage::filter_script_obj_by(self.backend.iter(Tree::Objs), filter, limit)
+    }
+
+    pub fn script_find_obj_from<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        id: ObjId,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        Storage::filter_script_obj_by(
+            self.backend
+                .range(Tree::Objs, (Bound::Unbounded, Bound::Excluded(ser_obj_id(id))))
+                .rev(),
+            filter,
+            limit,
+        )
+    }
+
+    pub fn script_find_obj_old_from<F: Fn(&ScriptObj) -> bool>(
+        &self,
+        id: ObjId,
+        filter: F,
+        limit: Option<usize>,
+    ) -> StorageResult<Vec<ScriptObj>> {
+        Storage::filter_script_obj_by(
+            self.backend
+                .range(Tree::Objs, (Bound::Included(ser_obj_id(id)), Bound::Unbounded)),
+            filter,
+            limit,
+        )
+    }
+
+    /// All the logs that changed the object `id`, oldest first
+    pub fn obj_history(&self, id: ObjId) -> StorageResult<Vec<ScriptLog>> {
+        self.find_log_old(|l| changed_obj(l) == Some(id), None)
+    }
+
+    /// The object `id` as it was at `time`, folded from its history. `None` if it didn't exist at that time.
+    pub fn get_obj_at(&self, id: ObjId, time: DateTime) -> StorageResult<Option<ScriptObj>> {
+        let mut objs = ObjMap::new();
+        for log in self.obj_history(id)?.iter().take_while(|l| l.time <= time) {
+            replay(&mut objs, log).map_err(|_| Error::Unreplayable(log.id))?;
+        }
+        objs.remove(&id)
+            .map(|obj| Ok(serde_json::from_value::<ProtoObj>(obj)?.with_id(id)))
+            .transpose()
+    }
+
+    pub fn create_task(
+        &self,
+        name: String,
+        desc: Option<String>,
+        attrs: Option<Attrs>,
+        deadline: OptRepeated,
+        priority: u32,
+    ) -> StorageResult<ObjId> {
+        self.transaction(|tx| {
+            // TODO inherit notification from config
+            let id = tx.get_obj_id()?;
+            let mut task = Task::new(deadline.clone(), priority, Vec::new());
+            match task.deadline {
+                OptRepeated::Single(time) => {
+                    let new_id = tx.new_sub_task(id, time, None)?;
+                    task.cache.push(new_id);
+                }
+                OptRepeated::Repeat(ref repeat) => {
+                    // FIXME attribute casting should be an system error and should create log entry
+                    let mut occurrences = repeat.iter();
+                    for next_time in occurrences.by_ref().take(task.gen_ahead) {
+                        let new_id = tx.new_sub_task(id, next_time, None)?;
+                        task.cache.push(new_id);
+                    }
+                    task.generated = occurrences.at();
+                }
+            }
+            tx.create_obj_with_id(id, task, name.clone(), desc.clone(), attrs.clone())
+        })
+    }
+
+    // FIXME Error on finished tasks? Or how to handle collision
+    /// id is the id for the sub task
+    pub fn task_finish(&self, id: ObjId, finished: DateTime) -> StorageResult<()> {
+        self.transaction(|tx| {
+            let start = tx.appended.len();
+            let mut sub: SubTask = tx.get_obj(id)?.inner;
+            let task_id = sub.task_id;
+            let mut task: Task = tx.get_obj(task_id)?.inner;
+            if let OptRepeated::Repeat(ref repeat) = task.deadline {
+                let cache_size = task.cache_size + task.gen_ahead + 1;
+                let mut occurrences = repeat.resume(task.generated);
+                if let Some(next_time) = occurrences.next() {
+                    let new_id = tx.new_sub_task(task_id, next_time, Some(&task.notifications))?;
+                    // We only generate one cuz there can be only 1 task completed
+                    task.cache.push(new_id);
+                    if task.cache.len() > cache_size as usize {
+                        task.cache.remove(0);
+                    }
+                    task.generated = occurrences.at();
+                }
+                tx.set_obj(task_id, task)?;
+            }
+            sub.finished = Some(finished);
+            tx.set_obj(id, sub)?;
+            let caused = tx.appended[start..].iter().map(|l| l.id).collect();
+            tx.append_log(TaskFinish {
+                id,
+                finished: Some(finished),
+                caused,
+            })?;
+            Ok(())
+        })
+    }
+
+    /// Leaves out the occurrence the sub task `id` is for, so that it doesn't come back when the recurrence is
+    /// exported or stepped again. The sub task is deleted, and the one after the last is generated in its place.
+    /// Returns the `task.skip` log grouping the changes.
+    pub fn task_skip(&self, id: ObjId) -> StorageResult<LogId> {
+        self.transaction(|tx| {
+            let start = tx.appended.len();
+            let sub: SubTask = tx.get_obj(id)?.inner;
+            if sub.finished.is_some() {
+                return Err(Error::AlreadyFinished(id).into());
+            }
+            let task_id = sub.task_id;
+            let mut task: Task = tx.get_obj(task_id)?.inner;
+            let repeat = match task.deadline {
+                OptRepeated::Repeat(ref mut repeat) => repeat,
+                OptRepeated::Single(_) => return Err(Error::NotRepeated(task_id).into()),
+            };
+            repeat.skip_occurrence(repeat.unmoved(sub.deadline));
+            task.cache.retain(|&sub_id| sub_id != id);
+            let mut occurrences = repeat.resume(task.generated);
+            if let Some(next_time) = occurrences.next() {
+                let new_id = tx.new_sub_task(task_id, next_time, Some(&task.notifications))?;
+                task.cache.push(new_id);
+                task.generated = occurrences.at();
+            }
+            tx.set_obj(task_id, task)?;
+            tx.delete_obj(id)?;
+            let caused = tx.appended[start..].iter().map(|l| l.id).collect();
+            tx.append_log(TaskSkip { id, caused })
+        })
+    }
+
+    /// Has the occurrence the sub task `id` is for happen at `to`, which the recurrence keeps as an override. Returns
+    /// the `task.move` log grouping the changes.
+    pub fn task_move(&self, id: ObjId, to: DateTime) -> StorageResult<LogId> {
+        self.transaction(|tx| {
+            let start = tx.appended.len();
+            let mut sub: SubTask = tx.get_obj(id)?.inner;
+            if sub.finished.is_some() {
+                return Err(Error::AlreadyFinished(id).into());
+            }
+            let task_id = sub.task_id;
+            let mut task: Task = tx.get_obj(task_id)?.inner;
+            match task.deadline {
+                OptRepeated::Repeat(ref mut repeat) => repeat.move_occurrence(repeat.unmoved(sub.deadline), to),
+                OptRepeated::Single(_) => task.deadline = OptRepeated::Single(to),
+            }
+            tx.set_obj(task_id, task)?;
+            sub.deadline = to;
+            tx.set_obj(id, sub)?;
+            let caused = tx.appended[start..].iter().map(|l| l.id).collect();
+            tx.append_log(TaskMove { id, to, caused })
+        })
+    }
+
+    /// Writes out what's been changed so far, for when the process won't be around for long
+    pub fn flush(&self) -> StorageResult<()> {
+        self.backend.flush()
+    }
+
+    /// Sets when to be notified before the task is due, on the sub tasks yet to be finished as well
+    pub fn task_set_notifications(&self, id: ObjId, notifications: Vec<Duration>) -> StorageResult<()> {
+        self.transaction(|tx| {
+            let mut task: Task = tx.get_obj(id)?.inner;
+            for &sub_id in &task.cache {
+                let mut sub: SubTask = tx.get_obj(sub_id)?.inner;
+                if sub.finished.is_none() {
+                    sub.notifications = notifications.clone();
+                    tx.set_obj(sub_id, sub)?;
+                }
+            }
+            task.notifications = notifications.clone();
+            tx.set_obj(id, task)
+        })
+    }
+
+    pub fn find_current(&self, id: ObjId) -> StorageResult<Option<ObjId>> {
         // It should
         let current_utc = Utc::now();
         let task: Task = self.get_obj(id)?.inner;
         let balanced = task.flavor == TaskFlavor::Balanced;
-        let sub_tasks: Vec<Obj<SubTask>> = task
-            .cache
-            .iter()
-            .map(|&i| self.get_obj(i))
-            .collect::<Result<_, _>>()?;
-        let unfinished = sub_tasks.into_iter()
+        let sub_tasks: Vec<Obj<SubTask>> = task.cache.iter().map(|&i| self.get_obj(i)).collect::<Result<_, _>>()?;
+        let unfinished = sub_tasks
+            .into_iter()
             .filter(|o| o.inner.finished.is_none())
             .collect::<Vec<_>>();
 
-        let deadlines = unfinished
-            .iter()
-            .map(|sub| sub.inner.deadline)
-            .collect::<Vec<_>>();
+        let deadlines = unfinished.iter().map(|sub| sub.inner.deadline).collect::<Vec<_>>();
         let len = unfinished.len();
         let grace = chrono::Duration::minutes(5);
         // TODO sort this instead so that past unfinished tasks maybe current?
@@ -536,55 +1559,977 @@ impl Storage {
         desc: Option<String>,
         attrs: Option<Attrs>,
     ) -> StorageResult<ObjId> {
-        let event = Event {
-            start,
-            duration,
-        };
+        let event = Event { start, duration };
         let id = self.create_obj(event, name, desc, attrs)?;
         Ok(id)
     }
 
-    pub fn export(&self) -> serde_json::Value {
-        let logs = self
-            .logs
-            .iter()
-            .map(|r| r.unwrap())
-            .enumerate()
-            .map(|(i, (k, v))| {
-                assert_eq!(i as u32 + 1, deser_log_id(&k).0);
-                deser(&v)
-            })
-            .collect();
+    /// Runs `f`, which makes its changes one transaction at a time, and groups the logs it appended under a
+    /// `log.group` log described by `desc`, so that they're undone as one. If `f` fails, the changes it had made are
+    /// undone before its error is returned.
+    pub fn group<T>(&self, desc: &str, f: impl FnOnce() -> StorageResult<T>) -> StorageResult<T> {
+        let start = self.counter(b"logs_id")?;
+        let res = f();
+        let end = self.counter(b"logs_id")?;
+        let failed = res.is_err();
+        let grouped = self.transaction(|tx| {
+            // Those another of them lists are undone along with that one
+            let mut caused = Vec::new();
+            let mut listed = BTreeSet::new();
+            for id in (start..end).rev().map(LogId) {
+                let bytes = match tx.trees.get(Tree::Logs, &ser_log_id(id))? {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                let typ = deser::<ProtoLog>(&bytes).map_err(|e| e.at(id))?.typ;
+                listed.extend(caused_logs(&typ, &bytes)?);
+                if !listed.contains(&id) {
+                    caused.push(id);
+                }
+            }
+            if failed {
+                for &id in &caused {
+                    tx.undo_log(id)?;
+                }
+            } else if !caused.is_empty() {
+                caused.reverse();
+                tx.append_log(LogGroup {
+                    desc: desc.into(),
+                    caused,
+                })?;
+            }
+            Ok(())
+        });
+        // The error `f` failed with matters more than one undoing what it had done
+        let ret = res?;
+        grouped?;
+        Ok(ret)
+    }
+
+    /// Reverts the changes recorded by the log `id` by appending compensating logs. Returns the `log.undo` log
+    /// grouping them, which `redo` takes to apply the changes again.
+    pub fn undo(&self, id: LogId) -> StorageResult<LogId> {
+        self.transaction(|tx| {
+            let start = tx.appended.len();
+            tx.undo_log(id)?;
+            let logs = tx.appended[start..].iter().map(|l| l.id).collect();
+            tx.append_log(LogUndo { id, logs })
+        })
+    }
+
+    /// Applies again what the `log.undo` log `id` has reverted
+    pub fn redo(&self, id: LogId) -> StorageResult<LogId> {
+        self.transaction(|tx| {
+            let bytes = tx
+                .trees
+                .get(Tree::Logs, &ser_log_id(id))?
+                .ok_or(Error::InvalidLogID(id))?;
+            let undo: LogUndo = deser_log(&bytes)?.inner;
+            let start = tx.appended.len();
+            for &log in undo.logs.iter().rev() {
+                tx.undo_log(log)?;
+            }
+            let logs = tx.appended[start..].iter().map(|l| l.id).collect();
+            tx.append_log(LogRedo { id, logs })
+        })
+    }
+
+    /// Finds the latest log that can be undone, or the latest `log.undo` log that can be redone if `redo` is set
+    pub fn last_undoable(&self, redo: bool) -> StorageResult<Option<LogId>> {
+        // Logs that are part of a bigger change, or whose effect has been reverted already
+        let mut skipped = BTreeSet::new();
+        for res in self.logs_by_time((Bound::Unbounded, Bound::Unbounded)).rev() {
+            let (k, v) = res?;
+            let id = deser_log_id(&k)?;
+            let typ = deser::<ProtoLog>(&v).map_err(|e| e.at(id))?.typ;
+            skipped.extend(caused_logs(&typ, &v)?);
+            if skipped.contains(&id) {
+                continue;
+            }
+            // Bookkeeping in the state isn't something to undo
+            if typ == ObjSetProps::LOG_TYPE && deser_log::<ObjSetProps>(&v)?.inner.id == State::ID {
+                continue;
+            }
+            match typ.as_str() {
+                LogUndo::LOG_TYPE if redo => return Ok(Some(id)),
+                LogUndo::LOG_TYPE => {
+                    let undo: LogUndo = deser_log(&v)?.inner;
+                    skipped.insert(undo.id);
+                    skipped.extend(undo.logs);
+                }
+                LogRedo::LOG_TYPE => {
+                    let redo: LogRedo = deser_log(&v)?.inner;
+                    skipped.insert(redo.id);
+                    skipped.extend(redo.logs);
+                }
+                CreateObj::LOG_TYPE
+                | ObjDelete::LOG_TYPE
+                | ObjSetDesc::LOG_TYPE
+                | ObjSetAttr::LOG_TYPE
+                | ObjSetProps::LOG_TYPE
+                | TaskFinish::LOG_TYPE
+                | TaskSkip::LOG_TYPE
+                | TaskMove::LOG_TYPE
+                | LogGroup::LOG_TYPE
+                    if !redo =>
+                {
+                    return Ok(Some(id))
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Regenerates the objects and the ID counters by replaying all the logs in the order they happened, and reports
+    /// how that differs from what is currently stored. Nothing is written if `dry_run` is set, and the rebuild is
+    /// refused if a log can't be replayed, as the objects it changed would be lost.
+    pub fn rebuild_from_logs(&self, dry_run: bool) -> StorageResult<Vec<Divergence>> {
+        let mut divergences = Vec::new();
+        let mut rebuilt = ObjMap::new();
+        let device = self.device()?;
+        // The archived logs can't be replayed, but their IDs mustn't be handed out again
+        let mut log_ids = self.tombstone()?.last.iter().map(|id| id.0).collect::<Vec<_>>();
+        let mut obj_ids = Vec::new();
+        for res in self.logs_by_time((Bound::Unbounded, Bound::Unbounded)) {
+            let log = deser_log_entry(res?)?;
+            log_ids.push(log.id.0);
+            // Nor the IDs of objects that have been deleted since
+            obj_ids.extend(changed_obj(&log).map(|id| id.0));
+            if let Err(d) = replay(&mut rebuilt, &log) {
+                divergences.push(d);
+            }
+        }
+        let current = self
+            .backend
+            .iter(Tree::Objs)
+            .map(|res| deser_obj_entry(res?))
+            .collect::<StorageResult<ObjMap>>()?;
+        // The state predates creation logs in older databases, and it's only bookkeeping anyway
+        if let (None, Some(state)) = (rebuilt.get(&State::ID), current.get(&State::ID)) {
+            rebuilt.insert(State::ID, state.clone());
+        }
+        let logs_id = LogId(next_id(device, log_ids));
+        let objs_id = ObjId(next_id(device, rebuilt.keys().map(|id| id.0).chain(obj_ids)));
+
+        for (id, obj) in &current {
+            match rebuilt.get(id) {
+                None => divergences.push(Divergence::Extra(*id)),
+                Some(o) if o != obj => divergences.push(Divergence::Changed(*id)),
+                Some(_) => {}
+            }
+        }
+        for id in rebuilt.keys() {
+            if !current.contains_key(id) {
+                divergences.push(Divergence::Missing(*id));
+            }
+        }
+        let current_logs_id = self
+            .backend
+            .get(Tree::Meta, b"logs_id")?
+            .map(|b| deser_log_id(&b))
+            .transpose()?;
+        if current_logs_id != Some(logs_id) {
+            divergences.push(Divergence::Counter {
+                key: "logs_id",
+                current: current_logs_id.map(|id| id.0),
+                rebuilt: logs_id.0,
+            });
+        }
+        let current_objs_id = self
+            .backend
+            .get(Tree::Meta, b"objs_id")?
+            .map(|b| deser_obj_id(&b))
+            .transpose()?;
+        if current_objs_id != Some(objs_id) {
+            divergences.push(Divergence::Counter {
+                key: "objs_id",
+                current: current_objs_id.map(|id| id.0),
+                rebuilt: objs_id.0,
+            });
+        }
+
+        if !dry_run {
+            if let Some(id) = divergences.iter().find_map(|d| match d {
+                Divergence::Unreplayable(id) => Some(*id),
+                _ => None,
+            }) {
+                return Err(Error::Unreplayable(id));
+            }
+            self.transaction(|tx| {
+                for id in current.keys() {
+                    if !rebuilt.contains_key(id) {
+                        tx.remove_obj(*id)?;
+                    }
+                }
+                for (id, obj) in &rebuilt {
+                    tx.put_obj(*id, ser(obj))?;
+                }
+                tx.trees.insert(Tree::Meta, b"logs_id", &ser_log_id(logs_id))?;
+                tx.trees.insert(Tree::Meta, b"objs_id", &ser_obj_id(objs_id))?;
+                Ok(())
+            })?;
+        }
+        Ok(divergences)
+    }
+
+    /// Copies everything, indexes included, into another backend as is
+    pub fn copy_to(&self, backend: &dyn Backend) -> StorageResult<()> {
+        backend.transaction(&mut |tx| {
+            for &tree in &Tree::ALL {
+                for res in self.backend.iter(tree) {
+                    let (k, v) = res?;
+                    tx.insert(tree, &k, &v)?;
+                }
+            }
+            Ok(())
+        })?;
+        backend.flush()
+    }
+
+    /// Dumps every record along with its ID and the meta counters
+    pub fn export(&self) -> StorageResult<Dump> {
         let objs = self
-            .objs
-            .iter()
-            .map(|r| r.unwrap())
-            .enumerate()
-            .map(|(_i, (_k, v))| {
-                // assert_eq!(i as u32, deser_id(&k));
-                deser(&v)
+            .backend
+            .iter(Tree::Objs)
+            .map(|res| deser_obj_entry(res?).map(|(id, obj)| DumpObj { id, obj }))
+            .collect::<StorageResult<_>>()?;
+        let logs = self
+            .backend
+            .iter(Tree::Logs)
+            .map(|res| {
+                let (k, v) = res?;
+                let id = deser_log_id(&k)?;
+                Ok(DumpLog {
+                    id,
+                    log: deser(&v).map_err(|e| e.at(id))?,
+                })
             })
-            .collect();
-        serde_json::to_value(DbData { logs, objs }).unwrap()
+            .collect::<StorageResult<_>>()?;
+        Ok(Dump {
+            version: DUMP_VERSION,
+            schema_version: self.schema_version()?,
+            logs_id: self.counter(b"logs_id").map(LogId)?,
+            objs_id: self.counter(b"objs_id").map(ObjId)?,
+            objs,
+            logs,
+        })
+    }
+
+    /// The next IDs to be handed out
+    pub fn counters(&self) -> StorageResult<(LogId, ObjId)> {
+        Ok((LogId(self.counter(b"logs_id")?), ObjId(self.counter(b"objs_id")?)))
+    }
+
+    /// Everything as JSON Lines like `export_lines`, all from the same moment: it's exported over again if anything
+    /// has been written meanwhile
+    pub fn snapshot(&self) -> StorageResult<Vec<u8>> {
+        let generation = || deser_generation(self.backend.get(Tree::Meta, b"generation")?);
+        for _ in 0..SNAPSHOT_TRIES {
+            let before = generation()?;
+            let mut out = Vec::new();
+            self.export_lines(&mut out, &Filter::default())?;
+            if generation()? == before {
+                return Ok(out);
+            }
+        }
+        Err(Error::Unsettled)
+    }
+
+    /// Seals what's kept outside of the store, as the backend seals its values
+    pub fn seal_blob(&self, blob: Vec<u8>) -> StorageResult<Vec<u8>> {
+        self.backend.seal_blob(blob)
+    }
+
+    pub fn unseal_blob(&self, blob: Vec<u8>) -> StorageResult<Vec<u8>> {
+        self.backend.unseal_blob(blob)
+    }
+
+    /// Writes the records passing `filter` as JSON Lines, one at a time, and returns how many were written. Objects
+    /// come by type and logs by time, only the parts of the indexes the filter allows are scanned.
+    pub fn export_lines(&self, out: &mut dyn Write, filter: &Filter) -> StorageResult<usize> {
+        let glob = filter.typ.as_deref().unwrap_or("*");
+        let pat = glob_regex(glob)?;
+        let header = Header {
+            version: DUMP_VERSION,
+            schema_version: self.schema_version()?,
+            logs_id: self.counter(b"logs_id").map(LogId)?,
+            objs_id: self.counter(b"objs_id").map(ObjId)?,
+        };
+        serde_json::to_writer(&mut *out, &header)?;
+        writeln!(out)?;
+        let mut written = 0;
+        if filter.objs {
+            for res in self.backend.scan_prefix(Tree::ObjsByType, glob_prefix(glob).as_bytes()) {
+                let (key, _) = res?;
+                let typ_len = key.iter().position(|&b| b == 0).unwrap_or(key.len());
+                if !pat.is_match(&String::from_utf8_lossy(&key[..typ_len])) {
+                    continue;
+                }
+                let id = deser_obj_id(key.get(typ_len + 1..).unwrap_or_default())?;
+                let bytes = self
+                    .backend
+                    .get(Tree::Objs, &ser_obj_id(id))?
+                    .ok_or(Error::InvalidObjID(id))?;
+                let obj = deser(&bytes).map_err(|e| e.at(id))?;
+                serde_json::to_writer(&mut *out, &Line::Obj(DumpObj { id, obj }))?;
+                writeln!(out)?;
+                written += 1;
+            }
+        }
+        if filter.logs {
+            let since = filter.since.map_or(Bound::Unbounded, |t| Bound::Included(ser_time(t)));
+            let until = filter.until.map_or(Bound::Unbounded, |t| Bound::Excluded(ser_time(t)));
+            for res in self.backend.range(Tree::LogsByTime, (since, until)) {
+                let id = deser_log_id(res?.0.get(TIME_LEN..).unwrap_or_default())?;
+                let bytes = self
+                    .backend
+                    .get(Tree::Logs, &ser_log_id(id))?
+                    .ok_or(Error::InvalidLogID(id))?;
+                let log: AttrValue = deser(&bytes).map_err(|e| e.at(id))?;
+                if !pat.is_match(log.get("typ").and_then(AttrValue::as_str).unwrap_or_default()) {
+                    continue;
+                }
+                serde_json::to_writer(&mut *out, &Line::Log(DumpLog { id, log }))?;
+                writeln!(out)?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    fn counter(&self, key: &[u8]) -> StorageResult<IdType> {
+        let key_str = String::from_utf8_lossy(key);
+        match self.backend.get(Tree::Meta, key)? {
+            Some(id) => deser_u32(&id).map_err(|e| e.at(&key_str)),
+            None => Err(Error::corrupt(format!("missing {} in meta", key_str))),
+        }
+    }
+
+    /// Replaces everything with the records of `dump`, keeping their IDs, migrated first if they're from an older
+    /// schema. The dump is checked after that and refused if it's inconsistent, unless `repair` is set and all
+    /// its problems can be repaired; the ones that were are returned. Nothing is touched if the dump is refused,
+    /// and a store that has anything in it is only overwritten with `replace`.
+    pub fn import(&self, dump: Dump, repair: bool, replace: bool) -> StorageResult<Vec<Problem>> {
+        // What was archived goes along with what's replaced
+        let problems = self.load(dump, repair, replace, &Tombstone::default())?;
+        if let Some(archive) = &self.archive {
+            archive.rewrite(&*self.backend, &[])?;
+        }
+        Ok(problems)
     }
 
-    pub fn import(&self, s: &str) {
-        let data: DbData = serde_json::from_str(s).unwrap();
-        self.meta.clear().unwrap();
-        self.logs.clear().unwrap();
-        self.objs.clear().unwrap();
-        for (i, log) in data.logs.iter().enumerate() {
-            self.logs.insert(ser_log_id(LogId(i as u32 + 1)), ser(log)).unwrap();
+    /// Writes `dump` into the store, with `tombstone` for what's been archived of it
+    fn load(&self, mut dump: Dump, repair: bool, replace: bool, tombstone: &Tombstone) -> StorageResult<Vec<Problem>> {
+        dump.upgrade()?;
+        let problems = dump.check(repair);
+        if problems.iter().any(|p| !repair || !p.repairable()) {
+            return Err(Error::InconsistentDump(problems));
+        }
+        // Only the state is ever created in a fresh store
+        if !replace && self.backend.get_gt(Tree::Objs, &ser_obj_id(State::ID))?.is_some() {
+            return Err(Error::NotEmpty);
         }
-        for (i, obj) in data.objs.iter().enumerate() {
-            self.objs.insert(ser_obj_id(ObjId(i as u32)), ser(obj)).unwrap();
+        let device = self.device()?;
+        // The store keeps handing out IDs on its own device, going on from the dump's counters if it's the same
+        if dump.logs_id.device() != device {
+            dump.logs_id = LogId(next_id(device, dump.logs.iter().map(|l| l.id.0)));
         }
-        self.meta
-            .insert("logs_id", ser_log_id(LogId(data.logs.len() as u32 + 1)))
+        if dump.objs_id.device() != device {
+            dump.objs_id = ObjId(next_id(device, dump.objs.iter().map(|o| o.id.0)));
+        }
+        let old_keys = [
+            Tree::Logs,
+            Tree::Objs,
+            Tree::ObjsByType,
+            Tree::LogsByTime,
+            Tree::LogsByType,
+        ]
+        .iter()
+        .map(|&tree| {
+            let keys = self.backend.iter(tree).map(|res| res.map(|(k, _)| k));
+            Ok((tree, keys.collect::<StorageResult<Vec<_>>>()?))
+        })
+        .collect::<StorageResult<Vec<_>>>()?;
+        self.transaction(|tx| {
+            for (tree, keys) in &old_keys {
+                for key in keys {
+                    tx.trees.remove(*tree, key)?;
+                }
+            }
+            for DumpLog { id, log } in &dump.logs {
+                tx.put_log(*id, ser(log))?;
+            }
+            for DumpObj { id, obj } in &dump.objs {
+                tx.put_obj(*id, ser(obj))?;
+            }
+            tx.trees.insert(Tree::Meta, b"logs_id", &ser_log_id(dump.logs_id))?;
+            tx.trees.insert(Tree::Meta, b"objs_id", &ser_obj_id(dump.objs_id))?;
+            tx.trees
+                .insert(Tree::Meta, b"schema_version", &dump.schema_version.to_be_bytes())?;
+            tx.trees.insert(Tree::Meta, b"archived", &ser(tombstone))?;
+            Ok(())
+        })?;
+        self.backend.flush()?;
+        Ok(problems)
+    }
+
+    /// Merges in the records of `other`, a store that diverged from this one, and reports what it brought in. The
+    /// merged records replace these, after being checked and repaired like an import.
+    pub fn sync(&self, mut other: Dump) -> StorageResult<SyncReport> {
+        other.upgrade()?;
+        // What this store has archived isn't pulled back in
+        let tombstone = self.tombstone()?;
+        other.logs.retain(|l| !tombstone.covers(l.id));
+        let mut local = self.export()?;
+        // Both handing out IDs on the same device, what this one has made since moves to a device neither has used
+        let moved_to = if sync::collisions(&local, &other).is_empty() {
+            None
+        } else {
+            let ids = |dump: &Dump| {
+                let logs = dump.logs.iter().map(|l| l.id.0).chain(Some(dump.logs_id.0));
+                logs.chain(dump.objs.iter().map(|o| o.id.0)).collect::<Vec<_>>()
+            };
+            let to = free_device(ids(&local).into_iter().chain(ids(&other)));
+            sync::renumber(&mut local, &other, to);
+            Some(to)
+        };
+        match sync::merge(local, other)? {
+            Some((merged, mut report)) => {
+                report.repaired = self.load(merged, true, true, &tombstone)?;
+                if let Some(to) = moved_to {
+                    self.set_device(to)?;
+                    report.moved_to = moved_to;
+                }
+                Ok(report)
+            }
+            None => Ok(SyncReport::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn task(store: &Storage) -> ObjId {
+        let deadline = OptRepeated::Single(DateTime::now());
+        store.create_task("task".into(), None, None, deadline, 1).unwrap()
+    }
+
+    #[test]
+    fn test_migrate_on_open() {
+        let backend = MemoryBackend::new();
+        // A store from before the schema was versioned, with a task missing the fields added since
+        let task = json!({ "typ": "task", "name": "t", "deadline": { "Single": "2021-01-01T00:00:00Z" }, "priority": 1, "cache": [] });
+        backend
+            .transaction(&mut |tx| {
+                tx.insert(Tree::Meta, b"logs_id", &ser_log_id(LogId(1)))?;
+                tx.insert(Tree::Meta, b"objs_id", &ser_obj_id(ObjId(2)))?;
+                tx.insert(Tree::Objs, &ser_obj_id(ObjId(1)), &ser(&task))?;
+                Ok(())
+            })
+            .unwrap();
+        let store = Storage::unmigrated(Box::new(backend)).unwrap();
+        assert_eq!(store.schema_version().unwrap(), 0);
+        let reports = store.migrate(true).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(matches!(reports[0].rewritten[..], [EitherId::Obj(ObjId(1))]));
+        assert!(reports[1].rewritten.is_empty());
+        assert_eq!(store.schema_version().unwrap(), 0);
+
+        store.migrate(false).unwrap();
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        let raw: AttrValue = deser(&store.backend.get(Tree::Objs, &ser_obj_id(ObjId(1))).unwrap().unwrap()).unwrap();
+        assert_eq!(raw["gen-ahead"], 5);
+        assert!(store.migrate(false).unwrap().is_empty());
+        assert_eq!(Storage::in_memory().unwrap().schema_version().unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_sealed_without_key() {
+        let backend = MemoryBackend::new();
+        backend
+            .transaction(&mut |tx| {
+                tx.insert(Tree::Meta, b"seal_salt", &[0; 16])?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(matches!(Storage::with_backend(Box::new(backend)), Err(Error::Sealed)));
+    }
+
+    #[test]
+    fn test_find_by_type() {
+        let store = Storage::in_memory().unwrap();
+        let id = task(&store);
+        let tasks: Vec<Obj<Task>> = store.find_obj(|_| true, None).unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id, id);
+        let subs = store.find_obj_by_type(SubTask::OBJ_TYPE, |_| true, None).unwrap();
+        assert_eq!(subs.len(), 1);
+        assert!(store.find_obj_by_type("task.", |_| true, None).unwrap().is_empty());
+
+        // Paging from an ID, with the sub tasks in between
+        let (second, third) = (task(&store), task(&store));
+        let ids = |objs: Vec<ScriptObj>| objs.into_iter().map(|o| o.id).collect::<Vec<_>>();
+        let before = store
+            .find_obj_by_type_from(Task::OBJ_TYPE, third, |_| true, None)
             .unwrap();
-        self.meta
-            .insert("objs_id", ser_obj_id(ObjId(data.objs.len() as u32 + 1)))
+        assert_eq!(ids(before), [second, id]);
+        let after = store
+            .find_obj_by_type_old_from(Task::OBJ_TYPE, second, |_| true, Some(1))
             .unwrap();
-        self.db.flush().unwrap();
+        assert_eq!(ids(after), [second]);
+        let subs = store.find_obj_by_type_old_from(SubTask::OBJ_TYPE, third, |_| true, None);
+        assert_eq!(subs.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let store = Storage::in_memory().unwrap();
+        let id = task(&store);
+        // Leaves a gap in the object IDs
+        let event = store
+            .create_event(
+                "event".into(),
+                OptRepeated::Single(DateTime::now()),
+                Duration(chrono::Duration::hours(1)),
+                None,
+                None,
+            )
+            .unwrap();
+        store.undo(store.last_undoable(false).unwrap().unwrap()).unwrap();
+        task(&store);
+        let dump = serde_json::to_value(store.export().unwrap()).unwrap();
+
+        let imported = Storage::in_memory().unwrap();
+        // The counters only go on from the dump's on the same device
+        imported.set_device(store.device().unwrap()).unwrap();
+        imported
+            .import(serde_json::from_value(dump.clone()).unwrap(), false, false)
+            .unwrap();
+        assert_eq!(serde_json::to_value(imported.export().unwrap()).unwrap(), dump);
+        let task: Obj<Task> = imported.get_obj(id).unwrap();
+        assert_eq!(
+            imported.get_obj::<SubTask>(task.inner.cache[0]).unwrap().inner.task_id,
+            id
+        );
+        assert!(matches!(imported.get_obj::<Event>(event), Err(Error::InvalidObjID(_))));
+        assert!(imported.obj_history(id).unwrap().len() > 0);
+
+        // Refused as is, without touching what's there
+        let mut broken: Dump = serde_json::from_value(dump).unwrap();
+        broken.objs.retain(|o| o.id != id);
+        let res = imported.import(broken, false, true);
+        assert!(matches!(res, Err(Error::InconsistentDump(ref p)) if p.len() == 1));
+        assert!(matches!(
+            store.import(imported.export().unwrap(), false, false),
+            Err(Error::NotEmpty)
+        ));
+        assert_eq!(
+            imported.export().unwrap().objs.len(),
+            store.export().unwrap().objs.len()
+        );
+    }
+
+    #[test]
+    fn test_export_lines() {
+        let store = Storage::in_memory().unwrap();
+        task(&store);
+        let mut out = Vec::new();
+        let written = store.export_lines(&mut out, &Filter::default()).unwrap();
+        let dump = Dump::read_lines(&out[..]).unwrap();
+        assert_eq!(written, dump.objs.len() + dump.logs.len());
+        // In another order, objects come by type and logs by time
+        let full = store.export().unwrap();
+        let mut objs = dump.objs.iter().map(|o| o.id).collect::<Vec<_>>();
+        objs.sort();
+        assert_eq!(objs, full.objs.iter().map(|o| o.id).collect::<Vec<_>>());
+        assert_eq!(dump.logs.len(), full.logs.len());
+        assert_eq!((dump.logs_id, dump.objs_id), (full.logs_id, full.objs_id));
+
+        let filter = Filter {
+            typ: Some("task.*".into()),
+            logs: false,
+            ..Filter::default()
+        };
+        out.clear();
+        assert_eq!(store.export_lines(&mut out, &filter).unwrap(), 1);
+        let filter = Filter {
+            since: Some(DateTime(DateTime::now().0 + chrono::Duration::hours(1))),
+            objs: false,
+            ..Filter::default()
+        };
+        out.clear();
+        assert_eq!(store.export_lines(&mut out, &filter).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sync() {
+        let event = |store: &Storage, name: &str| {
+            let start = OptRepeated::Single(DateTime::now());
+            let dur = Duration(chrono::Duration::hours(1));
+            store.create_event(name.into(), start, dur, None, None).unwrap()
+        };
+        let laptop = Storage::in_memory().unwrap();
+        laptop.set_device(1).unwrap();
+        let id = task(&laptop);
+        let desktop = Storage::in_memory().unwrap();
+        desktop.set_device(2).unwrap();
+        desktop.import(laptop.export().unwrap(), false, false).unwrap();
+        assert_eq!(desktop.device().unwrap(), 2);
+
+        laptop.obj_set_attr(id, "a".into(), 1.into()).unwrap();
+        let e1 = event(&laptop, "e1");
+        desktop.obj_set_attr(id, "a".into(), 2.into()).unwrap();
+        desktop.obj_set_desc(id, Some("desc".into())).unwrap();
+        let e2 = event(&desktop, "e2");
+        assert_eq!((e1.device(), e2.device()), (1, 2));
+
+        let report = laptop.sync(desktop.export().unwrap()).unwrap();
+        assert_eq!(report.pulled.len(), 3);
+        assert!(matches!(
+            report.conflicts[..],
+            [sync::Conflict::Edit { obj, ref field, .. }] if obj == id && field == "attrs.a"
+        ));
+        let task: Obj<Task> = laptop.get_obj(id).unwrap();
+        assert_eq!(task.attrs.unwrap()["a"], 2);
+        assert_eq!(task.desc.as_deref(), Some("desc"));
+        assert_eq!(laptop.get_obj::<Event>(e2).unwrap().name, "e2");
+
+        let report = desktop.sync(laptop.export().unwrap()).unwrap();
+        assert_eq!(report.pulled.len(), 2);
+        let objs = |store: &Storage| {
+            let mut dump = store.export().unwrap();
+            dump.objs.retain(|o| o.id != State::ID);
+            serde_json::to_value(dump.objs).unwrap()
+        };
+        assert_eq!(objs(&laptop), objs(&desktop));
+        assert!(laptop.sync(desktop.export().unwrap()).unwrap().pulled.is_empty());
+
+        // Both handing out IDs on the same device, what the laptop made since moves to another
+        let clone = Storage::in_memory().unwrap();
+        clone.import(laptop.export().unwrap(), false, false).unwrap();
+        clone.set_device(1).unwrap();
+        let e3 = event(&clone, "e3");
+        let e4 = event(&laptop, "e4");
+        laptop.obj_set_attr(e4, "a".into(), 1.into()).unwrap();
+        assert_eq!(e3, e4);
+        let report = laptop.sync(clone.export().unwrap()).unwrap();
+        let moved = report.moved_to.unwrap();
+        assert!(moved != 1 && moved != 2);
+        assert_eq!(laptop.device().unwrap(), moved);
+        assert_eq!(laptop.get_obj::<Event>(e3).unwrap().name, "e3");
+        let e4 = laptop.find_obj::<Event, _>(|e| e.name == "e4", None).unwrap()[0].id;
+        assert_eq!(e4.device(), moved);
+        assert_eq!(laptop.get_obj::<Event>(e4).unwrap().attrs.unwrap()["a"], 1);
+        assert!(laptop.rebuild_from_logs(false).unwrap().is_empty());
+        assert_eq!(event(&laptop, "e5").device(), moved);
+
+        assert!(clone.sync(laptop.export().unwrap()).unwrap().moved_to.is_none());
+        assert_eq!(objs(&laptop), objs(&clone));
+    }
+
+    #[test]
+    fn test_sync_archived() {
+        let path = std::env::temp_dir().join(format!("sched-test-sync-archived-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let laptop = Storage::in_memory().unwrap().with_archive(Archive::new(&path));
+        let id = task(&laptop);
+        laptop.obj_set_desc(id, Some("desc".into())).unwrap();
+        let desktop = Storage::in_memory().unwrap();
+        desktop.import(laptop.export().unwrap(), false, false).unwrap();
+        desktop.obj_set_attr(id, "a".into(), 1.into()).unwrap();
+
+        laptop.set_retention(vec![Retention {
+            types: "obj.*".into(),
+            days: 1,
+        }]);
+        let later = DateTime(DateTime::now().0 + chrono::Duration::days(2));
+        let archived = laptop.archive_logs(later, false).unwrap();
+        laptop.obj_set_desc(id, Some("newer".into())).unwrap();
+        // Only the desktop's edit comes back, not what's been archived since it forked
+        let report = laptop.sync(desktop.export().unwrap()).unwrap();
+        assert_eq!(report.pulled.len(), 1);
+        assert!(archived.iter().all(|&l| laptop.get_log::<ObjSetDesc>(l).is_err()));
+        let task: Obj<Task> = laptop.get_obj(id).unwrap();
+        assert_eq!(task.desc.as_deref(), Some("newer"));
+        assert_eq!(task.attrs.unwrap()["a"], 1);
+        assert_eq!(laptop.archived_logs().unwrap().len(), archived.len());
+
+        // Replacing the store leaves nothing of what it had archived
+        laptop.import(desktop.export().unwrap(), false, true).unwrap();
+        assert!(laptop.tombstone().unwrap().last.is_empty());
+        assert!(laptop.archived_logs().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_history_across_devices() {
+        let laptop = Storage::in_memory().unwrap();
+        laptop.set_device(1).unwrap();
+        let id = task(&laptop);
+        let desktop = Storage::in_memory().unwrap();
+        desktop.set_device(2).unwrap();
+        desktop.import(laptop.export().unwrap(), false, false).unwrap();
+
+        // The desktop's edit comes first, though its ID sorts after the laptop's
+        desktop.obj_set_attr(id, "a".into(), 2.into()).unwrap();
+        let between = DateTime::now();
+        laptop.obj_set_attr(id, "a".into(), 1.into()).unwrap();
+        laptop.sync(desktop.export().unwrap()).unwrap();
+        laptop.obj_set_desc(id, Some("desc".into())).unwrap();
+
+        let attrs = |obj: ScriptObj| obj.attrs.unwrap()["a"].clone();
+        assert_eq!(attrs(laptop.get_obj_at(id, between).unwrap().unwrap()), 2);
+        assert_eq!(attrs(laptop.get_obj_at(id, DateTime::now()).unwrap().unwrap()), 1);
+        let history = laptop.obj_history(id).unwrap();
+        assert!(history.windows(2).all(|w| w[0].time <= w[1].time));
+        let last = laptop.find_log(|_| true, Some(1), false).unwrap()[0].id;
+        assert_eq!(laptop.last_undoable(false).unwrap(), Some(last));
+        assert!(laptop.rebuild_from_logs(false).unwrap().is_empty());
+        assert_eq!(attrs(laptop.get_obj_at(id, DateTime::now()).unwrap().unwrap()), 1);
+        assert_eq!(laptop.get_obj::<Task>(id).unwrap().desc.as_deref(), Some("desc"));
+
+        // A log without what it created would lose the object
+        let old = laptop.append_log(CreateObj::new(id, "task".into(), None)).unwrap();
+        assert!(matches!(laptop.rebuild_from_logs(false), Err(Error::Unreplayable(l)) if l == old));
+        assert!(laptop.get_obj::<Task>(id).is_ok());
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let store = Storage::in_memory().unwrap();
+        let id = task(&store);
+        store
+            .backend
+            .transaction(&mut |tx| {
+                tx.insert(Tree::Objs, &ser_obj_id(id), b"{ not json")?;
+                Ok(())
+            })
+            .unwrap();
+        let corrupt = |e| matches!(e, Error::Corrupt { key: Some(ref key), .. } if key == &id.to_string());
+        assert!(corrupt(store.find_obj::<Task, _>(|_| true, None).unwrap_err()));
+        assert!(corrupt(store.obj_set_desc(id, Some("desc".into())).unwrap_err()));
+        assert!(corrupt(store.rebuild_from_logs(true).unwrap_err()));
+        // Everything else is still there
+        assert_eq!(
+            store.find_obj_by_type(SubTask::OBJ_TYPE, |_| true, None).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_undo_and_history() {
+        let store = Storage::in_memory().unwrap();
+        let id = task(&store);
+        store.obj_set_attr(id, "a".into(), 1.into()).unwrap();
+        let set_at = DateTime::now();
+        store.obj_set_attr(id, "a".into(), 2.into()).unwrap();
+        let last = store.last_undoable(false).unwrap().unwrap();
+        store.undo(last).unwrap();
+        let attrs = |obj: ScriptObj| obj.attrs.unwrap()["a"].clone();
+        assert_eq!(attrs(store.get_obj_at(id, DateTime::now()).unwrap().unwrap()), 1);
+        assert_eq!(attrs(store.get_obj_at(id, set_at).unwrap().unwrap()), 1);
+        assert_eq!(store.obj_history(id).unwrap().len(), 4);
+
+        let undo = store.last_undoable(true).unwrap().unwrap();
+        store.redo(undo).unwrap();
+        assert_eq!(store.get_obj::<Task>(id).unwrap().attrs.unwrap()["a"], 2);
+        assert!(store.rebuild_from_logs(true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_group() {
+        let store = Storage::in_memory().unwrap();
+        let objs = |store: &Storage| store.find_obj_by_type(SubTask::OBJ_TYPE, |_| true, None).unwrap().len();
+        store
+            .group("two tasks", || {
+                task(&store);
+                task(&store);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(objs(&store), 2);
+        let group = store.last_undoable(false).unwrap().unwrap();
+        // A task and its sub task each
+        assert_eq!(store.get_log::<LogGroup>(group).unwrap().inner.caused.len(), 4);
+        store.undo(group).unwrap();
+        assert_eq!(objs(&store), 0);
+
+        // What's been done before failing is undone
+        let res = store.group("failing", || {
+            let id = task(&store);
+            store.obj_set_desc(id, Some("desc".into()))?;
+            store.undo(LogId(0)).map(|_| ())
+        });
+        assert!(matches!(res, Err(Error::InvalidLogID(_))));
+        assert_eq!(objs(&store), 0);
+        assert!(store.find_obj::<Task, _>(|_| true, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_undo_task_creation() {
+        let store = Storage::in_memory().unwrap();
+        let id = task(&store);
+        assert_eq!(
+            store.find_obj_by_type(SubTask::OBJ_TYPE, |_| true, None).unwrap().len(),
+            1
+        );
+        let undo = store.undo(store.last_undoable(false).unwrap().unwrap()).unwrap();
+        assert!(store.get_obj::<Task>(id).is_err());
+        assert!(store
+            .find_obj_by_type(SubTask::OBJ_TYPE, |_| true, None)
+            .unwrap()
+            .is_empty());
+        assert!(store.rebuild_from_logs(true).unwrap().is_empty());
+
+        store.redo(undo).unwrap();
+        let task: Obj<Task> = store.get_obj(id).unwrap();
+        let subs = store.find_obj_by_type(SubTask::OBJ_TYPE, |_| true, None).unwrap();
+        assert_eq!(subs.iter().map(|s| s.id).collect::<Vec<_>>(), task.inner.cache);
+        assert!(store.export().unwrap().check(false).is_empty());
+    }
+
+    #[test]
+    fn test_skip_and_move() {
+        use crate::storage::{Every, Exception, Repeated, Stop};
+        let store = Storage::in_memory().unwrap();
+        let day = |d, h| DateTime(Utc.with_ymd_and_hms(2021, 3, d, h, 0, 0).unwrap().into());
+        let every = Every::Time(chrono::Duration::days(1).into());
+        let deadline = OptRepeated::Repeat(Repeated::new(vec![day(1, 9)], every, Stop::Nonstop).unwrap());
+        let id = store.create_task("task".into(), None, None, deadline, 1).unwrap();
+        let cache = |store: &Storage| store.get_obj::<Task>(id).unwrap().inner.cache;
+        let deadlines = |store: &Storage| {
+            cache(store)
+                .into_iter()
+                .map(|sub| store.get_obj::<SubTask>(sub).unwrap().inner.deadline)
+                .collect::<Vec<_>>()
+        };
+        let before = cache(&store);
+        let single = store.get_obj::<Task>(task(&store)).unwrap().inner.cache[0];
+        assert!(matches!(store.task_skip(single), Err(Error::NotRepeated(_))));
+
+        store.task_skip(before[1]).unwrap();
+        assert_eq!(
+            deadlines(&store),
+            vec![day(1, 9), day(3, 9), day(4, 9), day(5, 9), day(6, 9)]
+        );
+        assert!(store.get_obj::<SubTask>(before[1]).is_err());
+        store.task_move(before[2], day(3, 18)).unwrap();
+        assert_eq!(deadlines(&store)[1], day(3, 18));
+        match store.get_obj::<Task>(id).unwrap().inner.deadline {
+            OptRepeated::Repeat(repeat) => {
+                let skipped = Exception {
+                    at: day(2, 9),
+                    to: None,
+                };
+                let moved = Exception {
+                    at: day(3, 9),
+                    to: Some(day(3, 18)),
+                };
+                assert_eq!(repeat.exceptions(), &[skipped, moved]);
+            }
+            OptRepeated::Single(_) => panic!("the task should still repeat"),
+        }
+
+        // Each is undone as a whole
+        for _ in 0..2 {
+            let last = store.last_undoable(false).unwrap().unwrap();
+            store.undo(last).unwrap();
+        }
+        assert_eq!(cache(&store), before);
+        assert_eq!(
+            deadlines(&store),
+            vec![day(1, 9), day(2, 9), day(3, 9), day(4, 9), day(5, 9)]
+        );
+    }
+
+    #[test]
+    fn test_logs_between() {
+        let store = Storage::in_memory().unwrap();
+        let start = DateTime::now();
+        let id = task(&store);
+        store.obj_set_desc(id, Some("desc".into())).unwrap();
+        let end = DateTime::now();
+        store.obj_set_desc(id, None).unwrap();
+        let typs = |glob| {
+            let logs = store.logs_between(start, end, glob).unwrap();
+            logs.into_iter().map(|l| l.typ).collect::<Vec<_>>()
+        };
+        assert_eq!(typs("*"), vec!["obj.create", "obj.create", "obj.set_desc"]);
+        assert_eq!(typs("obj.set_*"), vec!["obj.set_desc"]);
+        assert_eq!(typs("*create"), vec!["obj.create", "obj.create"]);
+        assert!(typs("obj.set_desc?").is_empty());
+    }
+
+    #[test]
+    fn test_archive_logs() {
+        let path = std::env::temp_dir().join(format!("sched-test-archive-logs-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = Storage::in_memory().unwrap().with_archive(Archive::new(&path));
+        let id = task(&store);
+        store.obj_set_desc(id, Some("desc".into())).unwrap();
+        store.obj_set_desc(id, None).unwrap();
+        let later = DateTime(DateTime::now().0 + chrono::Duration::days(2));
+        assert!(store.archive_logs(later, false).unwrap().is_empty());
+
+        store.set_retention(vec![
+            Retention {
+                types: "obj.set_*".into(),
+                days: 3,
+            },
+            Retention {
+                types: "obj.*".into(),
+                days: 1,
+            },
+        ]);
+        // Only the creations are past their rule
+        let creations = store.find_log(|l| l.typ == "obj.create", None, false).unwrap();
+        assert_eq!(store.archive_logs(later, true).unwrap().len(), creations.len());
+        store.set_retention(vec![Retention {
+            types: "obj.set_*".into(),
+            days: 1,
+        }]);
+        let archived = store.archive_logs(later, true).unwrap();
+        assert_eq!(archived.len(), 2);
+        assert!(store.archived_logs().unwrap().is_empty());
+        assert_eq!(store.archive_logs(later, false).unwrap(), archived);
+
+        let typs = |include_archived| {
+            let logs = store.find_log(|_| true, None, include_archived).unwrap();
+            logs.into_iter().map(|l| l.typ).collect::<Vec<_>>()
+        };
+        assert!(typs(false).iter().all(|t| t == "obj.create"));
+        assert_eq!(typs(true)[..3], ["obj.set_desc", "obj.set_desc", "obj.create"]);
+        // Logs both archived and still kept, as if it went down before removing them, are only found once
+        let kept = store.export().unwrap().logs;
+        Archive::new(&path).append(&*store.backend, &kept).unwrap();
+        let all = store.find_log(|_| true, None, true).unwrap();
+        assert_eq!(all.len(), kept.len() + archived.len());
+        let first = store.find_log(|_| true, Some(2), true).unwrap();
+        assert_eq!(first.iter().map(|l| l.id).collect::<Vec<_>>(), [all[0].id, all[1].id]);
+        let since = DateTime(later.0 - chrono::Duration::days(3));
+        assert!(store.logs_between(since, later, "obj.set_*").unwrap().is_empty());
+        let tombstone = store.tombstone().unwrap();
+        assert_eq!(tombstone.last, [*archived.iter().max().unwrap()]);
+        assert_eq!(tombstone.counts["obj.set_desc"], 2);
+        // Archiving leaves the counters alone, but not the generation a snapshot goes by
+        let generation = || deser_generation(store.backend.get(Tree::Meta, b"generation").unwrap()).unwrap();
+        let (counters, before) = (store.counters().unwrap(), generation());
+        store.set_retention(vec![Retention {
+            types: "obj.*".into(),
+            days: 1,
+        }]);
+        assert!(!store.archive_logs(later, false).unwrap().is_empty());
+        assert_eq!(store.counters().unwrap(), counters);
+        assert!(generation() > before);
+        // The archived IDs aren't handed out again
+        store.set_device(store.device().unwrap()).unwrap();
+        store.obj_set_desc(id, Some("again".into())).unwrap();
+        assert!(store.find_log(|_| true, Some(1), false).unwrap()[0].id > tombstone.last[0]);
+        std::fs::remove_file(&path).unwrap();
     }
 }
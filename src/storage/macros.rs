@@ -12,7 +12,7 @@ macro_rules! storage_objs {
                     pub typ: String,
                     #[serde(flatten)]
                     pub props: Attrs,
-                    $(pub $field: $field_ty),+
+                    $($(#[$field_meta])* pub $field: $field_ty),+
                 }
 
                 // TODO explore how typed log can be passed directly
@@ -0,0 +1,297 @@
+//! Versioning of the stored records. Whenever the serialized shape of an object or a log changes, a step is added
+//! here that rewrites the older records, and `Storage` runs the steps the database hasn't seen yet when opening it.
+
+use crate::storage::{api::*, time::DateTime, Error, Every, Repeated, Result, Stop};
+
+/// The schema version written by this build
+pub const SCHEMA_VERSION: u32 = 2;
+
+pub struct Migration {
+    /// The version the database is at after this step
+    pub version: u32,
+    pub desc: &'static str,
+    /// Rewrites an object in place, returning whether it has changed, or an error if it can't be
+    pub obj: fn(&mut AttrValue) -> Result<bool>,
+    /// Rewrites a log in place, returning whether it has changed, or an error if it can't be
+    pub log: fn(&mut AttrValue) -> Result<bool>,
+}
+
+/// All the steps, in order of version
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        desc: "Write out the task fields added with defaults (`flavor`, `gen-ahead`, `cache-size`)",
+        obj: task_defaults,
+        log: unchanged,
+    },
+    Migration {
+        version: 2,
+        desc:
+            "Take where recurrences were stepped to out of them, into the `generated` of their tasks, and repair the \
+               ones that can't be read anymore",
+        obj: repeat_cursors,
+        log: repeat_cursors_in_log,
+    },
+];
+
+/// What a step has rewritten, or would rewrite
+#[derive(Clone, Debug)]
+pub struct MigrationReport {
+    pub version: u32,
+    pub desc: &'static str,
+    pub rewritten: Vec<EitherId>,
+}
+
+fn unchanged(_: &mut AttrValue) -> Result<bool> {
+    Ok(false)
+}
+
+fn task_defaults(obj: &mut AttrValue) -> Result<bool> {
+    let obj = match obj.as_object_mut() {
+        Some(obj) if obj.get("typ").and_then(AttrValue::as_str) == Some(Task::OBJ_TYPE) => obj,
+        _ => return Ok(false),
+    };
+    let mut changed = false;
+    let defaults = [
+        ("flavor", AttrValue::from("deadline")),
+        ("gen-ahead", 5.into()),
+        ("cache-size", 10.into()),
+    ];
+    for (key, val) in defaults.iter() {
+        if !obj.contains_key(*key) {
+            obj.insert(key.to_string(), val.clone());
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Makes what older versions took of a recurrence what it has to be now: the start times less than a step apart,
+/// stepping by at least a month, and a negative count, which never ran out, nonstop
+fn repair_repeat(repeat: &mut serde_json::Map<String, AttrValue>) -> bool {
+    let mut changed = false;
+    let every = repeat.get("every").cloned().map(serde_json::from_value::<Every>);
+    if let Some(Ok(Every::Month(0))) = every {
+        repeat.insert("every".into(), serde_json::to_value(Every::Month(1)).unwrap());
+        changed = true;
+    }
+    if let Some(Ok(Every::Time(every))) = every {
+        let start = repeat
+            .get("start")
+            .cloned()
+            .map(serde_json::from_value::<Vec<DateTime>>);
+        if let (Some(Ok(mut start)), true) = (start, every.0 > chrono::Duration::zero()) {
+            start.sort();
+            let len = start.len();
+            if let Some(&first) = start.first() {
+                start.retain(|s| s.0 - first.0 < every.0);
+            }
+            if start.len() < len {
+                repeat.insert("start".into(), serde_json::to_value(start).unwrap());
+                changed = true;
+            }
+        }
+    }
+    let count = repeat
+        .get("stop")
+        .and_then(|stop| stop.get("count"))
+        .and_then(AttrValue::as_i64);
+    if matches!(count, Some(count) if count < 0) {
+        repeat.insert("stop".into(), "nonstop".into());
+        changed = true;
+    }
+    changed
+}
+
+/// Repairs a recurrence and takes `last` and `index` out of it, returning whether it has changed and the `last`
+/// taken out. A count of the times left becomes one of the times from the start.
+fn repeat_cursor(opt: &mut AttrValue) -> Result<(bool, Option<AttrValue>)> {
+    let repeat = match opt.get_mut("repeat").and_then(AttrValue::as_object_mut) {
+        Some(repeat) => repeat,
+        None => return Ok((false, None)),
+    };
+    let mut changed = repair_repeat(repeat);
+    changed |= repeat.remove("index").is_some() | repeat.contains_key("last");
+    let last = repeat.remove("last").filter(|last| !last.is_null());
+    let checked = serde_json::from_value::<Repeated>(repeat.clone().into())
+        .map_err(|e| Error::corrupt(format!("the recurrence can't be repaired: {}", e)))?;
+    if let (Some(last), Stop::Count(left)) = (&last, checked.stop()) {
+        let last = serde_json::from_value::<DateTime>(last.clone()).map_err(Error::corrupt)?;
+        let count = i64::from(*left) + i64::from(checked.count_until(last));
+        repeat.insert("stop".into(), serde_json::json!({ "count": count }));
+    }
+    Ok((changed, last))
+}
+
+fn repeat_cursors(obj: &mut AttrValue) -> Result<bool> {
+    let field = match obj.get("typ").and_then(AttrValue::as_str) {
+        Some(Task::OBJ_TYPE) => "deadline",
+        Some(Event::OBJ_TYPE) => "start",
+        _ => return Ok(false),
+    };
+    let (changed, last) = match obj.get_mut(field) {
+        Some(opt) => repeat_cursor(opt)?,
+        None => return Ok(false),
+    };
+    // Only tasks go on generating from where they were
+    if let (Some(last), "deadline", Some(task)) = (last, field, obj.as_object_mut()) {
+        task.entry("generated").or_insert(last);
+    }
+    Ok(changed)
+}
+
+/// The sides of a serialized `Diff`, before and after
+fn diff_sides(diff: &mut AttrValue) -> (Option<&mut AttrValue>, Option<&mut AttrValue>) {
+    match diff.as_object_mut().and_then(|diff| diff.iter_mut().next()) {
+        Some((side, val)) if side == "New" => (None, Some(val)),
+        Some((side, val)) if side == "Del" => (Some(val), None),
+        Some((side, AttrValue::Array(sides))) if side == "Diff" => {
+            let mut sides = sides.iter_mut();
+            (sides.next(), sides.next())
+        }
+        _ => (None, None),
+    }
+}
+
+/// The objects the logs carry, and the changes to recurrences along with the change to `generated` they made
+fn repeat_cursors_in_log(log: &mut AttrValue) -> Result<bool> {
+    match log.get("typ").and_then(AttrValue::as_str) {
+        Some(CreateObj::LOG_TYPE) | Some(ObjDelete::LOG_TYPE) => match log.get_mut("obj") {
+            Some(obj) => repeat_cursors(obj),
+            None => Ok(false),
+        },
+        Some(ObjSetProps::LOG_TYPE) => {
+            let props = match log.get_mut("props").and_then(AttrValue::as_object_mut) {
+                Some(props) => props,
+                None => return Ok(false),
+            };
+            let cursor = |side: Option<&mut AttrValue>| side.map_or(Ok((false, None)), repeat_cursor);
+            let mut changed = false;
+            if let Some(start) = props.get_mut("start") {
+                let (before, after) = diff_sides(start);
+                changed |= cursor(before)?.0 | cursor(after)?.0;
+            }
+            let (before, after) = match props.get_mut("deadline") {
+                Some(deadline) => {
+                    let (before, after) = diff_sides(deadline);
+                    let (before, after) = (cursor(before)?, cursor(after)?);
+                    changed |= before.0 | after.0;
+                    (before.1, after.1)
+                }
+                None => (None, None),
+            };
+            let generated = match (before, after) {
+                (Some(o), Some(n)) if o != n => Some(serde_json::json!({ "Diff": [o, n] })),
+                (None, Some(n)) => Some(serde_json::json!({ "New": n })),
+                (Some(o), None) => Some(serde_json::json!({ "Del": o })),
+                _ => None,
+            };
+            if let Some(generated) = generated {
+                props.entry("generated").or_insert(generated);
+                changed = true;
+            }
+            Ok(changed)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_versions_ordered() {
+        let versions = MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>();
+        assert!(versions.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(versions.last(), Some(&SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn test_task_defaults() {
+        let mut task = json!({ "typ": "task", "name": "t", "priority": 1, "flavor": "balanced" });
+        assert!(task_defaults(&mut task).unwrap());
+        assert_eq!(task["flavor"], "balanced");
+        assert_eq!(task["gen-ahead"], 5);
+        assert_eq!(task["cache-size"], 10);
+        assert!(!task_defaults(&mut task).unwrap());
+        assert!(!task_defaults(&mut json!({ "typ": "event", "name": "e" })).unwrap());
+    }
+
+    #[test]
+    fn test_repeat_cursors() {
+        use chrono::{Duration, TimeZone, Utc};
+
+        use crate::storage::Every;
+
+        let day = |d| serde_json::to_value(DateTime::from(Utc.with_ymd_and_hms(2021, 1, d, 9, 0, 0).unwrap())).unwrap();
+        let start = serde_json::from_value(day(1)).unwrap();
+        let repeat = Repeated::new(vec![start], Every::Time(Duration::days(1).into()), Stop::Count(4)).unwrap();
+        // Two of four gone through, as the recurrence used to keep it
+        let stepped = |last| {
+            let mut old = serde_json::to_value(&repeat).unwrap();
+            old["stop"] = json!({ "count": 4 - last as i32 });
+            old["last"] = day(last);
+            old["index"] = 0.into();
+            json!({ "repeat": old })
+        };
+        let mut task = json!({ "typ": "task", "name": "t", "deadline": stepped(2), "priority": 1, "cache": [] });
+        assert!(repeat_cursors(&mut task).unwrap());
+        assert_eq!(task["deadline"]["repeat"], serde_json::to_value(&repeat).unwrap());
+        assert_eq!(task["generated"], day(2));
+        assert!(!repeat_cursors(&mut task).unwrap());
+        // Events don't generate anything
+        let mut event = json!({ "typ": "event", "name": "e", "start": stepped(2) });
+        assert!(repeat_cursors(&mut event).unwrap());
+        assert_eq!(event["start"]["repeat"], serde_json::to_value(&repeat).unwrap());
+        assert!(event.get("generated").is_none());
+
+        let mut log =
+            json!({ "typ": "obj.set_props", "id": 1, "props": { "deadline": { "Diff": [stepped(1), stepped(2)] } } });
+        assert!(repeat_cursors_in_log(&mut log).unwrap());
+        assert_eq!(log["props"]["deadline"]["Diff"][0], log["props"]["deadline"]["Diff"][1]);
+        assert_eq!(log["props"]["generated"], json!({ "Diff": [day(1), day(2)] }));
+        assert!(!repeat_cursors_in_log(&mut log).unwrap());
+    }
+
+    #[test]
+    fn test_repair_repeat() {
+        use chrono::{TimeZone, Utc};
+
+        use crate::storage::Every;
+
+        let at =
+            |d, h| serde_json::to_value(DateTime::from(Utc.with_ymd_and_hms(2021, 1, d, h, 0, 0).unwrap())).unwrap();
+        let day = serde_json::to_value(Every::Time(chrono::Duration::days(1).into())).unwrap();
+        // Older versions took starts a step or more apart, which are dropped
+        let mut task = json!({ "typ": "task", "name": "t", "priority": 1, "cache": [], "deadline": { "repeat": {
+            "start": [at(1, 9), at(1, 8), at(2, 8)], "every": day, "stop": { "count": -1 }
+        } } });
+        assert!(repeat_cursors(&mut task).unwrap());
+        let repeat: Repeated = serde_json::from_value(task["deadline"]["repeat"].clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(repeat.start()).unwrap(),
+            json!([at(1, 8), at(1, 9)])
+        );
+        assert_eq!(repeat.stop(), &Stop::Nonstop);
+        assert!(!repeat_cursors(&mut task).unwrap());
+
+        // and ones stepping by no month at all
+        let mut event = json!({ "typ": "event", "name": "e", "start": { "repeat": {
+            "start": [at(1, 9)], "every": { "month": 0 }, "stop": "nonstop"
+        } } });
+        assert!(repeat_cursors(&mut event).unwrap());
+        assert_eq!(
+            event["start"]["repeat"]["every"],
+            serde_json::to_value(Every::Month(1)).unwrap()
+        );
+
+        // What can't be repaired fails the migration
+        let mut event = json!({ "typ": "event", "name": "e", "start": { "repeat": {
+            "start": [], "every": day, "stop": "nonstop"
+        } } });
+        assert!(matches!(repeat_cursors(&mut event), Err(Error::Corrupt { .. })));
+    }
+}
@@ -1,22 +1,43 @@
 pub mod api;
+pub mod archive;
+pub mod backend;
+pub mod dump;
 mod kv;
 mod macros;
+mod migrate;
+mod replay;
+pub mod rule;
+#[cfg(feature = "encryption")]
+pub mod sealed;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod sync;
 pub mod time;
 
 pub use kv::*;
 pub use macros::*;
+pub use migrate::SCHEMA_VERSION;
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
 
-use chrono::Datelike;
 use lazy_static::lazy_static;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use thiserror::Error;
 
 use crate::storage::{
-    api::{LogId, ObjId},
-    time::{DateTime, Duration},
+    api::{EitherId, LogId, ObjId},
+    dump::Problem,
+    rule::Overflow,
+    time::{DateTime, Duration, Tz},
 };
 
 lazy_static! {
-    pub static ref STORE: Storage = Storage::new();
+    pub static ref STORE: Storage = Storage::new().unwrap_or_else(|e| {
+        // Nothing works without the store, so there's no point going on
+        eprintln!("Can't open the database: {}", e);
+        std::process::exit(1)
+    });
 }
 
 // FIXME define types (newtype?) for log and object IDs
@@ -26,7 +47,16 @@ pub enum Error {
     #[error("Deadlock")]
     Deadlock,
     #[error("Database error: {0}")]
-    Database(#[from] sled::Error),
+    Database(sled::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The database at {} is locked, is sched running elsewhere?", .0.display())]
+    Locked(PathBuf),
+    #[error("Corrupt record{}: {reason}", .key.as_ref().map_or(String::new(), |k| format!(" {}", k)))]
+    Corrupt { key: Option<String>, reason: String },
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
     #[error("Can't compile regex '{0}'")]
     Regex(String),
     #[error("Invalid Log ID {0}")]
@@ -37,27 +67,191 @@ pub enum Error {
     TypeMismatch { expected: String, actual: String },
     #[error("Cannot delete an non-existent attribute '{1}' at object '{0}'")]
     DelNonExistent(ObjId, String),
+    #[error("Log {0} can't be undone")]
+    NotUndoable(LogId),
+    #[error("Can't undo {0}, it has been changed since")]
+    UndoConflict(LogId),
+    #[error("Log {0} doesn't carry enough to be replayed")]
+    Unreplayable(LogId),
+    #[error("The database has schema version {0}, newer than the {1} this version of sched knows")]
+    SchemaTooNew(u32, u32),
+    #[error("The dump has format version {0}, newer than the {1} this version of sched reads")]
+    DumpTooNew(u64, u32),
+    #[error(
+        "The dump is inconsistent:{}",
+        .0.iter().map(|p| format!("\n  {}{}", p, if p.repairable() { " (repairable)" } else { "" })).collect::<String>()
+    )]
+    InconsistentDump(Vec<Problem>),
+    #[error("The database already has data in it")]
+    NotEmpty,
+    #[error(
+        "The stores have different records under the same IDs ({}), they have been written to on the same device",
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    SyncCollision(Vec<EitherId>),
+    #[error("The database is encrypted, set SCHED_PASSPHRASE or SCHED_KEYFILE to open it")]
+    Sealed,
+    #[cfg(feature = "encryption")]
+    #[error("The passphrase or keyfile isn't the one the database is encrypted with")]
+    WrongKey,
+    #[cfg(feature = "encryption")]
+    #[error("Can't encrypt: {0}")]
+    Seal(String),
+    #[error("The database kept being written to while taking a snapshot of it")]
+    Unsettled,
+    #[error("The store has no archive to move logs to")]
+    NoArchive,
+    #[error("Device 0 is where the records from before there were devices are, it can't be handed out again")]
+    ReservedDevice,
+    #[error("Task {0} doesn't repeat, it has no occurrences to skip")]
+    NotRepeated(ObjId),
+    #[error("Sub task {0} is finished already")]
+    AlreadyFinished(ObjId),
     #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-// FIXME manually implement `Pushable` and `Getable`, so that internal state is not passed to Gluon, and that
-// they are set to reset state when passed from Gluon
-// FIXME use other internal states to record when to stop i.e. can't change the stop properties for public
-// interface
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl Error {
+    pub fn corrupt(reason: impl std::fmt::Display) -> Error {
+        Error::Corrupt {
+            key: None,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Attaches the key of the record to a corrupt record error that doesn't know it yet
+    pub fn at(self, key: impl std::fmt::Display) -> Error {
+        match self {
+            Error::Corrupt { key: None, reason } => Error::Corrupt {
+                key: Some(key.to_string()),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Error {
+        match e {
+            sled::Error::Io(e) => Error::Io(e),
+            sled::Error::Corruption { at, .. } => Error::corrupt(format!("sled found corrupt data at {:?}", at)),
+            e => Error::Database(e),
+        }
+    }
+}
+
+impl From<TransactionError<Error>> for Error {
+    fn from(e: TransactionError<Error>) -> Error {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+impl From<Error> for ConflictableTransactionError<Error> {
+    fn from(e: Error) -> ConflictableTransactionError<Error> {
+        ConflictableTransactionError::Abort(e)
+    }
+}
+
+/// How far a start time is stepped looking for a time it's at, when it's left out of months without its day
+const MAX_ROUNDS: u32 = 100;
+
+/// Why a recurrence can't be made
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum RepeatError {
+    #[error("A recurrence needs a time to start at")]
+    NoStart,
+    #[error("A recurrence has to step forward")]
+    NoStep,
+    #[error("The start times of a recurrence have to be less than a step apart")]
+    StartsApart,
+    #[error("A recurrence can't happen {0} times")]
+    NegativeCount(i32),
+}
+
+/// When something happens again and again: the start times, stepped the same way until it stops. It's only what the
+/// recurrence is, going through its occurrences is up to `Occurrences`, so that it can be asked about any time
+/// without being changed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Unchecked")]
 #[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata))]
 pub struct Repeated {
-    /// A set of actual start times
+    /// A set of actual start times, sorted
     start: Vec<DateTime>,
     every: Every,
     stop: Stop,
+    /// Where it is in months without the day it's on, when stepping by months
+    #[serde(default)]
+    overflow: Overflow,
+    /// The zone on whose wall clock it steps by days, months or calendar rules, so that it stays at its time of the
+    /// day after the clocks change. Without one, it's the offset of the start times.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    zone: Option<Tz>,
+    /// Occurrences that are skipped or happen at some other time, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exceptions: Vec<Exception>,
+}
 
-    last: Option<DateTime>,
-    /// Index into `start`, for from which start time the current time is derived from
-    index: usize,
+/// A `Repeated` as it's read, before it's checked
+#[derive(Deserialize)]
+struct Unchecked {
+    start: Vec<DateTime>,
+    every: Every,
+    stop: Stop,
+    #[serde(default)]
+    overflow: Overflow,
+    #[serde(default)]
+    zone: Option<Tz>,
+    #[serde(default)]
+    exceptions: Vec<Exception>,
+}
+
+impl TryFrom<Unchecked> for Repeated {
+    type Error = RepeatError;
+    fn try_from(mut repeat: Unchecked) -> std::result::Result<Repeated, RepeatError> {
+        repeat.start.sort();
+        let (first, last) = match (repeat.start.first(), repeat.start.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return Err(RepeatError::NoStart),
+        };
+        match &repeat.every {
+            Every::Time(every) if every.0 <= chrono::Duration::zero() => return Err(RepeatError::NoStep),
+            Every::Time(every) if last.0 - first.0 >= every.0 => return Err(RepeatError::StartsApart),
+            Every::Month(0) => return Err(RepeatError::NoStep),
+            Every::Rule(rule) if rule.interval == 0 => return Err(RepeatError::NoStep),
+            _ => {}
+        }
+        if let Stop::Count(count) = repeat.stop {
+            if count < 0 {
+                return Err(RepeatError::NegativeCount(count));
+            }
+        }
+        repeat.exceptions.sort_by_key(|e| e.at);
+        Ok(Repeated {
+            start: repeat.start,
+            every: repeat.every,
+            stop: repeat.stop,
+            overflow: repeat.overflow,
+            zone: repeat.zone,
+            exceptions: repeat.exceptions,
+        })
+    }
+}
+
+/// An occurrence of a recurrence that doesn't happen when the recurrence has it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata))]
+pub struct Exception {
+    /// When the recurrence has it
+    pub at: DateTime,
+    /// When it happens instead, it's skipped if there's no such time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<DateTime>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -68,93 +262,336 @@ pub enum OptRepeated {
     Repeat(Repeated),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata))]
 #[serde(rename_all = "lowercase")]
 pub enum Every {
     Time(Duration),
     Month(u32),
+    /// On the days a calendar rule picks, at the times of the day of the start times
+    Rule(rule::Rule),
 }
 
-impl Every {
-    fn advance(&self, time: DateTime) -> DateTime {
-        let time = match self {
-            Every::Time(dur) => time.0 + dur.0,
-            Every::Month(c) => {
-                let month = time.0.month0() + c;
-                let (year_diff, month) = (month / 12, month % 12);
-                time.0
-                    .with_year(time.0.year() + year_diff as i32)
-                    .unwrap()
-                    .with_month0(month)
-                    .expect(&format!("invalid month {}", month))
-            }
-        };
-        DateTime(time)
-    }
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata))]
 #[serde(rename_all = "lowercase")]
 pub enum Stop {
     Nonstop,
+    /// Doesn't happen at all, which is what older records that had run out were left at
     Stopped,
+    /// After this many times, counted from the start
     Count(i32),
     After(DateTime),
 }
 
-// TODO `start[0]` + `every` < `start[n]`?
 impl Repeated {
-    pub fn new(mut start: Vec<DateTime>, every: Every, stop: Stop) -> Repeated {
-        start.sort();
-        Repeated {
+    pub fn new(start: Vec<DateTime>, every: Every, stop: Stop) -> std::result::Result<Repeated, RepeatError> {
+        Repeated::try_from(Unchecked {
             start,
             every,
             stop,
+            overflow: Overflow::default(),
+            zone: None,
+            exceptions: Vec::new(),
+        })
+    }
+
+    pub fn with_overflow(mut self, overflow: Overflow) -> Repeated {
+        self.overflow = overflow;
+        self
+    }
+
+    pub fn with_zone(mut self, zone: Option<Tz>) -> Repeated {
+        self.zone = zone;
+        self
+    }
+
+    /// The start times, sorted
+    pub fn start(&self) -> &[DateTime] {
+        &self.start
+    }
+
+    pub fn every(&self) -> &Every {
+        &self.every
+    }
+
+    pub fn stop(&self) -> &Stop {
+        &self.stop
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    pub fn zone(&self) -> Option<Tz> {
+        self.zone
+    }
+
+    /// The occurrences that are skipped or moved, sorted by when the recurrence has them
+    pub fn exceptions(&self) -> &[Exception] {
+        &self.exceptions
+    }
+
+    fn exception(&self, at: DateTime) -> Option<&Exception> {
+        let i = self.exceptions.binary_search_by_key(&at, |e| e.at).ok()?;
+        Some(&self.exceptions[i])
+    }
+
+    fn set_exception(&mut self, exception: Exception) {
+        match self.exceptions.binary_search_by_key(&exception.at, |e| e.at) {
+            Ok(i) => self.exceptions[i] = exception,
+            Err(i) => self.exceptions.insert(i, exception),
+        }
+    }
+
+    /// Leaves out the occurrence at `time`, the time the recurrence has it at. Skipped ones still count towards
+    /// `Stop::Count`, as with EXDATE.
+    pub fn skip_occurrence(&mut self, time: DateTime) {
+        self.set_exception(Exception { at: time, to: None });
+    }
+
+    /// Has the occurrence at `from`, the time the recurrence has it at, happen at `to` instead
+    pub fn move_occurrence(&mut self, from: DateTime, to: DateTime) {
+        if from == to {
+            self.exceptions.retain(|e| e.at != from);
+        } else {
+            self.set_exception(Exception { at: from, to: Some(to) });
+        }
+    }
+
+    /// When the recurrence has the occurrence that happens at `time`, which is elsewhere if it's been moved
+    pub fn unmoved(&self, time: DateTime) -> DateTime {
+        self.exceptions
+            .iter()
+            .find(|e| e.to == Some(time))
+            .map_or(time, |e| e.at)
+    }
+
+    /// The same recurrence `by` later, along with what's skipped or moved and when it stops
+    pub fn shifted(&self, by: chrono::Duration) -> Repeated {
+        let shift = |t: DateTime| DateTime(t.0 + by);
+        let stop = match self.stop {
+            Stop::After(until) => Stop::After(shift(until)),
+            ref stop => stop.clone(),
+        };
+        let exceptions = self
+            .exceptions
+            .iter()
+            .map(|e| Exception {
+                at: shift(e.at),
+                to: e.to.map(shift),
+            })
+            .collect();
+        Repeated {
+            start: self.start.iter().copied().map(shift).collect(),
+            stop,
+            exceptions,
+            ..self.clone()
+        }
+    }
+
+    /// When the recurrence has its first occurrence, whether or not it's skipped or moved
+    pub fn first(&self) -> Option<DateTime> {
+        self.iter().step()
+    }
+
+    /// Goes through the occurrences from the start
+    pub fn iter(&self) -> Occurrences<'_> {
+        Occurrences {
+            repeat: self,
             last: None,
-            index: 0,
+            count: 0,
         }
     }
-}
 
-impl Iterator for Repeated {
-    type Item = DateTime;
-    fn next(&mut self) -> Option<DateTime> {
-        match self.stop {
-            Stop::Count(count) => {
-                if count == 0 {
-                    self.stop = Stop::Stopped;
-                } else {
-                    self.stop = Stop::Count(count - 1);
+    /// Goes through the occurrences after the one the recurrence has at `at`, which is what `Occurrences::at` says
+    /// it's at. From the start if there's no such time.
+    pub fn resume(&self, at: Option<DateTime>) -> Occurrences<'_> {
+        let mut occurrences = self.iter();
+        if let Some(at) = at {
+            // Only how it stops needs to know how many came before
+            if let Stop::Count(count) = self.stop {
+                occurrences.count = self.count_until(at).min(count as u32);
+            }
+            occurrences.last = Some(at);
+        }
+        occurrences
+    }
+
+    /// The occurrences from `from` until before `to`, in order
+    pub fn occurrences_between(&self, from: DateTime, to: DateTime) -> Vec<DateTime> {
+        let mut occurrences = self.resume(Some(DateTime(from.0 - chrono::Duration::nanoseconds(1))));
+        let mut times = std::iter::from_fn(|| occurrences.step())
+            .take_while(|&t| t < to)
+            .filter(|&t| self.exception(t).is_none())
+            .collect::<Vec<_>>();
+        times.extend(
+            self.exceptions
+                .iter()
+                .filter_map(|e| e.to)
+                .filter(|&t| from <= t && t < to),
+        );
+        times.sort();
+        times
+    }
+
+    /// How many times the recurrence has until `time`, however it stops. Stepped by a time, it's how many steps each
+    /// start time is from `time`, as they're less than a step apart.
+    fn count_until(&self, time: DateTime) -> u32 {
+        let mut starts = self.start.clone();
+        starts.dedup();
+        let starts = starts.into_iter().filter(|&start| start <= time);
+        match (&self.every, self.clock_step()) {
+            (_, Some(every)) => starts
+                .map(|start| ((time.0 - start.0).num_milliseconds() / every.num_milliseconds().max(1)) as u32 + 1)
+                .sum(),
+            // The step `steps` ends on is on the day of `time`, which may be later in the day
+            (Every::Time(_), None) => starts
+                .map(|start| match self.steps(start, time) {
+                    steps if matches!(self.step(start, steps), Some(t) if t <= time) => steps + 1,
+                    steps => steps,
+                })
+                .sum(),
+            _ => {
+                let mut count = 0;
+                let mut last = None;
+                while let Some(next) = self.step_after(last).filter(|&t| t <= time) {
+                    last = Some(next);
+                    count += 1;
                 }
+                count
+            }
+        }
+    }
+
+    /// The step if the recurrence is stepped by the clock rather than the wall clock, as it is by less than days
+    fn clock_step(&self) -> Option<chrono::Duration> {
+        match &self.every {
+            Every::Time(every) if every.0.num_milliseconds() % 86_400_000 != 0 => Some(every.0),
+            _ => None,
+        }
+    }
+
+    /// The first time the recurrence has after `after`, or its first one without `after`, however it stops
+    fn step_after(&self, after: Option<DateTime>) -> Option<DateTime> {
+        if let Every::Rule(rule) = &self.every {
+            return rule.next(&self.start, after, self.overflow, self.zone);
+        }
+        let after = match after {
+            Some(after) => after,
+            None => return Some(self.start[0]),
+        };
+        match self.clock_step() {
+            Some(every) => self
+                .start
+                .iter()
+                .map(|&start| {
+                    let rounds = match (after.0 - start.0).num_milliseconds() {
+                        ms if ms < 0 => 0,
+                        ms => ms / every.num_milliseconds().max(1),
+                    };
+                    let mut time = start.0 + chrono::Duration::milliseconds(every.num_milliseconds() * rounds);
+                    while time <= after.0 {
+                        time = time + every;
+                    }
+                    DateTime(time)
+                })
+                .min(),
+            None => self
+                .start
+                .iter()
+                .filter_map(|&start| self.step_from(start, after))
+                .min(),
+        }
+    }
+
+    /// The first time after `after` that `start` is stepped to by whole days or months on the wall clock. Each start
+    /// time is stepped from itself rather than from the time before, so that it stays on its day after a month it
+    /// didn't fit in, and at its time of the day after the clocks change.
+    fn step_from(&self, start: DateTime, after: DateTime) -> Option<DateTime> {
+        if after < start {
+            return Some(start);
+        }
+        let round = self.steps(start, after).saturating_sub(1);
+        // Each start time fits in its own month, which comes again within a few years
+        (round..round + MAX_ROUNDS)
+            .filter_map(|n| self.step(start, n))
+            .find(|&t| t > after)
+    }
+
+    /// `start` stepped `n` times on the wall clock, if it's anywhere
+    fn step(&self, start: DateTime, n: u32) -> Option<DateTime> {
+        match &self.every {
+            Every::Month(months) => self.overflow.add_months(start, n * months, self.zone),
+            Every::Time(every) => {
+                let wall = start.wall(self.zone) + every.0 * n as i32;
+                start.at_wall(wall, self.zone)
             }
-            _ => (),
-        }
-        if matches!(self.stop, Stop::Stopped) {
-            return None;
-        }
-        // Assuming `start` is sorted
-        if let Some(DateTime(mut last)) = self.last {
-            if self.index == self.start.len() - 1 {
-                last = last - (self.start[self.start.len() - 1].0 - self.start[0].0);
-                last = self.every.advance(DateTime(last)).0;
-                self.index = 0;
-            } else {
-                self.index += 1;
-                last = last + (self.start[self.index].0 - self.start[self.index - 1].0);
+            Every::Rule(_) => unreachable!("rules aren't stepped"),
+        }
+    }
+
+    /// How many times `start` was stepped to get to `time`
+    fn steps(&self, start: DateTime, time: DateTime) -> u32 {
+        match &self.every {
+            Every::Month(months) => self.overflow.months_between(start, time, self.zone) / months,
+            Every::Time(every) => {
+                let days = (time.wall(self.zone).date() - start.wall(self.zone).date()).num_days();
+                (days / every.0.num_days().max(1)).max(0) as u32
             }
-            self.last = Some(DateTime(last));
-        } else {
-            self.last = Some(self.start[0]);
+            Every::Rule(_) => unreachable!("rules aren't stepped"),
+        }
+    }
+}
+
+/// Goes through the occurrences of a recurrence in the order the recurrence has them, so a moved one may come after
+/// where it's moved to
+#[derive(Clone, Debug)]
+pub struct Occurrences<'a> {
+    repeat: &'a Repeated,
+    /// When the recurrence has the last one stepped over
+    last: Option<DateTime>,
+    /// How many have been stepped over, for `Stop::Count`
+    count: u32,
+}
+
+impl Occurrences<'_> {
+    /// When the recurrence has the last occurrence gone through, which `Repeated::resume` goes on from
+    pub fn at(&self) -> Option<DateTime> {
+        self.last
+    }
+
+    /// The next time the recurrence has, before any are skipped or moved
+    fn step(&mut self) -> Option<DateTime> {
+        let repeat = self.repeat;
+        match repeat.stop {
+            Stop::Stopped => return None,
+            Stop::Count(count) if self.count >= count as u32 => return None,
+            _ => {}
         }
-        if let Stop::After(time) = self.stop {
-            if time.0 < self.last.unwrap().0 {
-                self.stop = Stop::Stopped;
+        let time = repeat.step_after(self.last)?;
+        if let Stop::After(until) = repeat.stop {
+            if time > until {
                 return None;
             }
         }
-        self.last
+        self.last = Some(time);
+        self.count += 1;
+        Some(time)
+    }
+}
+
+impl Iterator for Occurrences<'_> {
+    type Item = DateTime;
+    fn next(&mut self) -> Option<DateTime> {
+        loop {
+            let time = self.step()?;
+            match self.repeat.exception(time) {
+                Some(Exception { to: Some(to), .. }) => return Some(*to),
+                Some(_) => {}
+                None => return Some(time),
+            }
+        }
     }
 }
 
@@ -164,28 +601,72 @@ mod test {
     use chrono::Duration;
 
     fn datetime(y: i32, m: u32, d: u32, h: u32, mi: u32, s: u32) -> super::DateTime {
-        super::DateTime(Utc.ymd(y, m, d).and_hms(h, mi, s).into())
+        super::DateTime(Utc.with_ymd_and_hms(y, m, d, h, mi, s).unwrap().into())
     }
 
     #[test]
     fn test_every_advance() {
-        use super::Every;
+        use super::{rule::Overflow, Every, Repeated, Stop};
         let now = datetime(2020, 12, 25, 12, 13, 14);
+        let second = |every| {
+            let repeat = Repeated::new(vec![now], every, Stop::Nonstop).unwrap();
+            repeat.iter().nth(1).unwrap()
+        };
         assert_eq!(
-            Every::Time(Duration::days(3).into()).advance(now),
+            second(Every::Time(Duration::days(3).into())),
             datetime(2020, 12, 28, 12, 13, 14)
         );
         assert_eq!(
-            Every::Time(Duration::days(7).into()).advance(now),
+            second(Every::Time(Duration::days(7).into())),
             datetime(2021, 1, 1, 12, 13, 14)
         );
         assert_eq!(
-            Every::Time(Duration::weeks(1).into()).advance(now),
+            second(Every::Time(Duration::weeks(1).into())),
             datetime(2021, 1, 1, 12, 13, 14)
         );
-        assert_eq!(Every::Month(1).advance(now), datetime(2021, 1, 25, 12, 13, 14));
-        assert_eq!(Every::Month(12).advance(now), datetime(2021, 12, 25, 12, 13, 14));
-        assert_eq!(Every::Month(18).advance(now), datetime(2022, 6, 25, 12, 13, 14));
+        assert_eq!(second(Every::Month(1)), datetime(2021, 1, 25, 12, 13, 14));
+        assert_eq!(second(Every::Month(12)), datetime(2021, 12, 25, 12, 13, 14));
+        assert_eq!(second(Every::Month(18)), datetime(2022, 6, 25, 12, 13, 14));
+
+        // Back on the 31st after a shorter month, over the end of the year too
+        let end = datetime(2020, 12, 31, 9, 0, 0);
+        let months = |overflow| {
+            Repeated::new(vec![end], Every::Month(1), Stop::Count(5))
+                .unwrap()
+                .with_overflow(overflow)
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            months(Overflow::Clamp),
+            vec![
+                end,
+                datetime(2021, 1, 31, 9, 0, 0),
+                datetime(2021, 2, 28, 9, 0, 0),
+                datetime(2021, 3, 31, 9, 0, 0),
+                datetime(2021, 4, 30, 9, 0, 0),
+            ]
+        );
+        assert_eq!(
+            months(Overflow::Skip),
+            vec![
+                end,
+                datetime(2021, 1, 31, 9, 0, 0),
+                datetime(2021, 3, 31, 9, 0, 0),
+                datetime(2021, 5, 31, 9, 0, 0),
+                datetime(2021, 7, 31, 9, 0, 0),
+            ]
+        );
+        assert_eq!(
+            months(Overflow::Roll),
+            vec![
+                end,
+                datetime(2021, 1, 31, 9, 0, 0),
+                datetime(2021, 3, 3, 9, 0, 0),
+                datetime(2021, 3, 31, 9, 0, 0),
+                datetime(2021, 5, 1, 9, 0, 0),
+            ]
+        );
     }
 
     #[test]
@@ -199,9 +680,10 @@ mod test {
             ],
             Every::Time(Duration::weeks(1).into()),
             Stop::Count(7),
-        );
+        )
+        .unwrap();
         assert_eq!(
-            repeat.collect::<Vec<_>>(),
+            repeat.iter().collect::<Vec<_>>(),
             vec![
                 datetime(2020, 12, 21, 10, 0, 0),
                 datetime(2020, 12, 23, 11, 0, 0),
@@ -220,9 +702,10 @@ mod test {
             ],
             Every::Time(Duration::weeks(1).into()),
             Stop::After(datetime(2021, 1, 4, 11, 0, 0)),
-        );
+        )
+        .unwrap();
         assert_eq!(
-            repeat.collect::<Vec<_>>(),
+            repeat.iter().collect::<Vec<_>>(),
             vec![
                 datetime(2020, 12, 21, 10, 0, 0),
                 datetime(2020, 12, 23, 11, 0, 0),
@@ -233,5 +716,171 @@ mod test {
                 datetime(2021, 1, 4, 10, 0, 0),
             ]
         );
+
+        // Mondays and Wednesdays, at the times of the day of the start times
+        let mut rule = super::rule::Rule::new(super::rule::Freq::Weekly, 1);
+        rule.by_day = vec![
+            super::rule::ByDay {
+                nth: 0,
+                day: Weekday::Mon,
+            },
+            super::rule::ByDay {
+                nth: 0,
+                day: Weekday::Wed,
+            },
+        ];
+        let repeat = Repeated::new(
+            vec![datetime(2020, 12, 23, 9, 0, 0), datetime(2020, 12, 23, 18, 0, 0)],
+            Every::Rule(rule),
+            Stop::Count(4),
+        )
+        .unwrap();
+        assert_eq!(
+            repeat.iter().collect::<Vec<_>>(),
+            vec![
+                datetime(2020, 12, 23, 9, 0, 0),
+                datetime(2020, 12, 23, 18, 0, 0),
+                datetime(2020, 12, 28, 9, 0, 0),
+                datetime(2020, 12, 28, 18, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repeat_zone() {
+        use super::{Every, Repeated, Stop};
+        let zone = "Europe/Paris".parse().ok();
+        // 8 in Paris, the day before the clocks go forward
+        let start = datetime(2021, 3, 27, 7, 0, 0);
+        let times = |every| {
+            Repeated::new(vec![start], every, Stop::Count(2))
+                .unwrap()
+                .with_zone(zone)
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            times(Every::Time(Duration::days(1).into())),
+            vec![start, datetime(2021, 3, 28, 6, 0, 0)]
+        );
+        assert_eq!(
+            times(Every::Time(Duration::hours(24).into())),
+            vec![start, datetime(2021, 3, 28, 6, 0, 0)]
+        );
+        assert_eq!(
+            times(Every::Time(Duration::hours(12).into())),
+            vec![start, datetime(2021, 3, 27, 19, 0, 0)]
+        );
+        assert_eq!(times(Every::Month(1)), vec![start, datetime(2021, 4, 27, 6, 0, 0)]);
+        let weekly = super::rule::Rule::new(super::rule::Freq::Weekly, 1);
+        assert_eq!(times(Every::Rule(weekly)), vec![start, datetime(2021, 4, 3, 6, 0, 0)]);
+        // Without a zone, it's the offset of the start time
+        let fixed = Repeated::new(vec![start], Every::Time(Duration::days(1).into()), Stop::Nonstop).unwrap();
+        assert_eq!(fixed.iter().nth(1), Some(datetime(2021, 3, 28, 7, 0, 0)));
+        // The zone is kept by its name
+        let json = serde_json::to_value(fixed.with_zone(zone)).unwrap();
+        assert_eq!(json["zone"], "Europe/Paris");
+    }
+
+    #[test]
+    fn test_repeat_exceptions() {
+        use super::{Every, Repeated, Stop};
+        let day = |d| datetime(2021, 3, d, 9, 0, 0);
+        let mut repeat = Repeated::new(vec![day(1)], Every::Time(Duration::weeks(1).into()), Stop::Count(5)).unwrap();
+        repeat.skip_occurrence(day(15));
+        repeat.move_occurrence(day(22), datetime(2021, 3, 25, 9, 0, 0));
+        // Skipped ones still count
+        assert_eq!(
+            repeat.iter().collect::<Vec<_>>(),
+            vec![day(1), day(8), datetime(2021, 3, 25, 9, 0, 0), day(29)]
+        );
+        assert_eq!(repeat.unmoved(datetime(2021, 3, 25, 9, 0, 0)), day(22));
+        assert_eq!(repeat.unmoved(day(8)), day(8));
+        // Skipping a moved one leaves it out altogether, and moving it back puts it where it was
+        let mut skipped = repeat.clone();
+        skipped.skip_occurrence(day(22));
+        assert_eq!(skipped.iter().collect::<Vec<_>>(), vec![day(1), day(8), day(29)]);
+        repeat.move_occurrence(day(22), day(22));
+        assert_eq!(repeat.exceptions().len(), 1);
+        // They're kept along with the recurrence
+        let json = serde_json::to_string(&repeat).unwrap();
+        let read: Repeated = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.exceptions(), &[super::Exception { at: day(15), to: None }]);
+    }
+
+    #[test]
+    fn test_count_until() {
+        use super::{Every, Repeated, Stop};
+        let start = datetime(2021, 3, 20, 6, 0, 0);
+        let zone = "Europe/Paris".parse().ok();
+        for (starts, hours) in &[(1, 5), (2, 5), (1, 24), (2, 48), (1, 24 * 7)] {
+            let starts = (0..*starts).map(|i| datetime(2021, 3, 20, 6 + i, 0, 0)).collect();
+            let every = Every::Time(Duration::hours(*hours).into());
+            let repeat = Repeated::new(starts, every, Stop::Nonstop).unwrap().with_zone(zone);
+            for h in (-24..24 * 30).step_by(7) {
+                let time = super::DateTime(start.0 + Duration::hours(h));
+                let stepped = repeat.iter().take_while(|&t| t <= time).count();
+                assert_eq!(
+                    repeat.count_until(time) as usize,
+                    stepped,
+                    "every {}h until {}",
+                    hours,
+                    time.0
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_repeat_queries() {
+        use super::{Every, Repeated, Stop};
+        let day = |d| datetime(2021, 3, d, 9, 0, 0);
+        let mut repeat = Repeated::new(vec![day(1)], Every::Time(Duration::weeks(1).into()), Stop::Count(5)).unwrap();
+        repeat.skip_occurrence(day(8));
+        repeat.move_occurrence(day(22), day(16));
+        assert_eq!(repeat.iter().nth(1), Some(day(15)));
+        assert_eq!(repeat.iter().nth(4), None);
+        assert_eq!(repeat.occurrences_between(day(1), day(16)), vec![day(1), day(15)]);
+        assert_eq!(
+            repeat.occurrences_between(day(2), day(30)),
+            vec![day(15), day(16), day(29)]
+        );
+
+        // Going on from where it was left counts what came before
+        let mut occurrences = repeat.iter();
+        occurrences.by_ref().take(2).for_each(drop);
+        assert_eq!(occurrences.at(), Some(day(15)));
+        assert_eq!(
+            repeat.resume(occurrences.at()).collect::<Vec<_>>(),
+            vec![day(16), day(29)]
+        );
+        assert_eq!(repeat.resume(None).count(), 4);
+    }
+
+    #[test]
+    fn test_repeat_checked() {
+        use super::{Every, RepeatError, Repeated, Stop};
+        let weekly = || Every::Time(Duration::weeks(1).into());
+        assert_eq!(
+            Repeated::new(Vec::new(), weekly(), Stop::Nonstop),
+            Err(RepeatError::NoStart)
+        );
+        let start = datetime(2021, 3, 1, 9, 0, 0);
+        assert_eq!(
+            Repeated::new(vec![start], Every::Time(Duration::zero().into()), Stop::Nonstop),
+            Err(RepeatError::NoStep)
+        );
+        assert_eq!(
+            Repeated::new(vec![start, datetime(2021, 3, 8, 9, 0, 0)], weekly(), Stop::Nonstop),
+            Err(RepeatError::StartsApart)
+        );
+        assert_eq!(
+            Repeated::new(vec![start], weekly(), Stop::Count(-1)),
+            Err(RepeatError::NegativeCount(-1))
+        );
+        // Nor can one be read
+        let mut json = serde_json::to_value(Repeated::new(vec![start], weekly(), Stop::Nonstop).unwrap()).unwrap();
+        json["start"] = serde_json::json!([]);
+        assert!(serde_json::from_value::<Repeated>(json).is_err());
     }
 }
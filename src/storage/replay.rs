@@ -0,0 +1,260 @@
+//! Replaying the logs back into objects, so that the object store can be recovered from the event log alone
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::storage::api::*;
+
+/// Objects by ID in their stored (JSON) form
+pub type ObjMap = BTreeMap<ObjId, AttrValue>;
+
+/// A difference between the stored objects and the ones replayed from the logs
+#[derive(Clone, Debug)]
+pub enum Divergence {
+    /// The object is stored but never created by any log
+    Extra(ObjId),
+    /// The object is created by the logs but not stored
+    Missing(ObjId),
+    /// The stored object differs from the replayed one
+    Changed(ObjId),
+    /// An ID counter in `meta` differs from the one derived from the logs
+    Counter {
+        key: &'static str,
+        current: Option<IdType>,
+        rebuilt: IdType,
+    },
+    /// The log is about an object, but doesn't carry enough to replay it (e.g. written by an older version)
+    Unreplayable(LogId),
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::Extra(id) => write!(f, "{} is stored but not created by any log", id),
+            Divergence::Missing(id) => write!(f, "{} is created by the logs but not stored", id),
+            Divergence::Changed(id) => write!(f, "{} differs from the replayed logs", id),
+            Divergence::Counter { key, current, rebuilt } => match current {
+                Some(current) => write!(f, "meta '{}' is {}, the logs give {}", key, current, rebuilt),
+                None => write!(f, "meta '{}' is missing, the logs give {}", key, rebuilt),
+            },
+            Divergence::Unreplayable(id) => write!(f, "{} can't be replayed", id),
+        }
+    }
+}
+
+/// Computes how the props of an object changed, only keeping the ones that did
+pub fn diff_props(old: &Attrs, new: &Attrs) -> ApiMap<String, Diff<AttrValue>> {
+    let mut diff = ApiMap::new();
+    for (key, old_val) in old {
+        match new.get(key) {
+            Some(new_val) if new_val != old_val => {
+                diff.insert(key.clone(), Diff::Diff(old_val.clone(), new_val.clone()));
+            }
+            Some(_) => {}
+            None => {
+                diff.insert(key.clone(), Diff::Del(old_val.clone()));
+            }
+        }
+    }
+    for (key, new_val) in new {
+        if !old.contains_key(key) {
+            diff.insert(key.clone(), Diff::New(new_val.clone()));
+        }
+    }
+    diff
+}
+
+fn apply_diff(map: &mut Map<String, Value>, key: &str, diff: Diff<AttrValue>) {
+    match diff {
+        Diff::New(val) | Diff::Diff(_, val) => {
+            map.insert(key.into(), val);
+        }
+        Diff::Del(_) => {
+            map.remove(key);
+        }
+    }
+}
+
+/// Reads a log as the type it is, e.g. to know what it changed
+pub fn typed<L: ApiLog + DeserializeOwned>(log: &ScriptLog) -> Result<L, Divergence> {
+    let props = log.props.clone().into_iter().collect();
+    serde_json::from_value(Value::Object(props)).map_err(|_| Divergence::Unreplayable(log.id))
+}
+
+fn target<'a>(objs: &'a mut ObjMap, log: &ScriptLog, id: ObjId) -> Result<&'a mut Map<String, Value>, Divergence> {
+    objs.get_mut(&id)
+        .and_then(Value::as_object_mut)
+        .ok_or(Divergence::Unreplayable(log.id))
+}
+
+/// The object changed by a log, if it's one of the logs that change objects
+pub fn changed_obj(log: &ScriptLog) -> Option<ObjId> {
+    match log.typ.as_str() {
+        CreateObj::LOG_TYPE
+        | ObjDelete::LOG_TYPE
+        | ObjSetDesc::LOG_TYPE
+        | ObjSetAttr::LOG_TYPE
+        | ObjSetProps::LOG_TYPE
+        | TaskFinish::LOG_TYPE => serde_json::from_value(log.props.get("id")?.clone()).ok(),
+        _ => None,
+    }
+}
+
+/// Applies one log onto `objs`. Logs that don't change any object are skipped.
+pub fn replay(objs: &mut ObjMap, log: &ScriptLog) -> Result<(), Divergence> {
+    apply(objs, log, false)
+}
+
+/// Takes one log back off `objs`, which has to be the state right after it
+pub fn revert(objs: &mut ObjMap, log: &ScriptLog) -> Result<(), Divergence> {
+    apply(objs, log, true)
+}
+
+fn apply(objs: &mut ObjMap, log: &ScriptLog, back: bool) -> Result<(), Divergence> {
+    let way = |diff: Diff<AttrValue>| if back { diff.inverse() } else { diff };
+    match log.typ.as_str() {
+        CreateObj::LOG_TYPE => {
+            let create: CreateObj = typed(log)?;
+            if back {
+                objs.remove(&create.id).ok_or(Divergence::Unreplayable(log.id))?;
+            } else {
+                let obj = create.obj.ok_or(Divergence::Unreplayable(log.id))?;
+                objs.insert(create.id, obj);
+            }
+        }
+        ObjDelete::LOG_TYPE => {
+            let delete: ObjDelete = typed(log)?;
+            if back {
+                objs.insert(delete.id, delete.obj);
+            } else {
+                objs.remove(&delete.id).ok_or(Divergence::Unreplayable(log.id))?;
+            }
+        }
+        ObjSetDesc::LOG_TYPE => {
+            let set: ObjSetDesc = typed(log)?;
+            let diff = match set.diff {
+                Diff::New(n) => Diff::New(n.into()),
+                Diff::Del(o) => Diff::Del(o.into()),
+                Diff::Diff(o, n) => Diff::Diff(o.into(), n.into()),
+            };
+            apply_diff(target(objs, log, set.id)?, "desc", way(diff));
+        }
+        ObjSetAttr::LOG_TYPE => {
+            let set: ObjSetAttr = typed(log)?;
+            let obj = target(objs, log, set.id)?;
+            let attrs = obj
+                .entry("attrs")
+                .or_insert_with(|| Value::Object(Map::new()))
+                .as_object_mut()
+                .ok_or(Divergence::Unreplayable(log.id))?;
+            apply_diff(attrs, &set.attr, way(set.diff));
+            if attrs.is_empty() {
+                obj.remove("attrs");
+            }
+        }
+        ObjSetProps::LOG_TYPE => {
+            let set: ObjSetProps = typed(log)?;
+            let obj = target(objs, log, set.id)?;
+            for (key, diff) in set.props {
+                apply_diff(obj, &key, way(diff));
+            }
+        }
+        TaskFinish::LOG_TYPE => {
+            let finish: TaskFinish = typed(log)?;
+            if back {
+                // What finishing changed is taken back with the logs it caused, older ones don't list them
+                if finish.caused.is_empty() {
+                    return Err(Divergence::Unreplayable(log.id));
+                }
+            } else {
+                let finished = finish.finished.ok_or(Divergence::Unreplayable(log.id))?;
+                let finished = serde_json::to_value(finished).map_err(|_| Divergence::Unreplayable(log.id))?;
+                target(objs, log, finish.id)?.insert("finished".into(), finished);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::storage::time::DateTime;
+
+    fn log<L: ApiLog>(id: IdType, log: L) -> ScriptLog {
+        let raw = RawLog {
+            inner: log,
+            typ: L::LOG_TYPE.into(),
+            time: DateTime::now(),
+            attrs: None,
+        };
+        let proto: ProtoLog = serde_json::from_value(serde_json::to_value(raw).unwrap()).unwrap();
+        proto.with_id(LogId(id))
+    }
+
+    #[test]
+    fn test_diff_props() {
+        let old: Attrs = serde_json::from_value(json!({ "a": 1, "b": 2, "c": 3 })).unwrap();
+        let new: Attrs = serde_json::from_value(json!({ "a": 1, "b": 4, "d": 5 })).unwrap();
+        let diff = diff_props(&old, &new);
+        assert_eq!(diff.len(), 3);
+        assert!(matches!(diff["b"], Diff::Diff(ref o, ref n) if o == &json!(2) && n == &json!(4)));
+        assert!(matches!(diff["c"], Diff::Del(ref o) if o == &json!(3)));
+        assert!(matches!(diff["d"], Diff::New(ref n) if n == &json!(5)));
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut objs = ObjMap::new();
+        let created = json!({ "typ": "task.sub", "name": "subtask", "task-id": 1, "deadline": "2021-01-01T00:00:00Z" });
+        let logs = vec![
+            log(1, CreateObj::new(ObjId(2), "task.sub".into(), Some(created))),
+            log(2, ObjSetDesc::new(ObjId(2), Diff::New("desc".into()))),
+            log(3, ObjSetAttr::new(ObjId(2), "a".into(), Diff::New(json!(1)))),
+            log(4, ObjSetAttr::new(ObjId(2), "b".into(), Diff::New(json!(2)))),
+            log(5, ObjSetAttr::new(ObjId(2), "a".into(), Diff::Del(json!(1)))),
+            log(
+                6,
+                ObjSetProps::new(
+                    ObjId(2),
+                    vec![("task-id".into(), Diff::Diff(json!(1), json!(3)))]
+                        .into_iter()
+                        .collect(),
+                ),
+            ),
+        ];
+        for l in &logs {
+            assert_eq!(changed_obj(l), Some(ObjId(2)));
+            replay(&mut objs, l).unwrap();
+        }
+        assert_eq!(
+            objs[&ObjId(2)],
+            json!({
+                "typ": "task.sub",
+                "name": "subtask",
+                "desc": "desc",
+                "attrs": { "b": 2 },
+                "task-id": 3,
+                "deadline": "2021-01-01T00:00:00Z",
+            })
+        );
+        assert!(matches!(
+            replay(&mut objs, &log(7, CreateObj::new(ObjId(3), "task".into(), None))),
+            Err(Divergence::Unreplayable(LogId(7)))
+        ));
+        assert!(matches!(
+            replay(&mut objs, &log(8, ObjSetDesc::new(ObjId(4), Diff::New("desc".into())))),
+            Err(Divergence::Unreplayable(LogId(8)))
+        ));
+        for l in logs.iter().rev() {
+            revert(&mut objs, l).unwrap();
+        }
+        assert!(objs.is_empty());
+    }
+}
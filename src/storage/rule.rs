@@ -0,0 +1,489 @@
+//! Calendar recurrence rules, after the RRULE of iCalendar: a frequency, an interval, and `BY*` parts that pick the
+//! days in each period, then the positions among what's picked.
+//!
+//! The days are worked out on the wall clock of the zone of the recurrence, or of the offset of the first start time
+//! without one, at each of the times of the day of the start times. Weeks start on Monday.
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime, Weekday};
+
+use crate::storage::time::{DateTime, Tz};
+
+/// How many periods are looked through for the next occurrence before giving up, for rules which pick days that
+/// never come, e.g. February the 30th
+const MAX_PERIODS: i64 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata), gluon_trace(skip))]
+#[serde(rename_all = "lowercase")]
+pub enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// What a recurrence by months does in a month without the day it's on, e.g. from the 31st or from February the
+/// 29th
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata), gluon_trace(skip))]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// On the last day of the month
+    #[default]
+    Clamp,
+    /// Not in that month
+    Skip,
+    /// As many days into the next month as the day is past the end of the month
+    Roll,
+}
+
+/// The year and the month, 1 to 12, of `months` counted from January of year 0
+fn month_of(months: i64) -> (i32, u32) {
+    (months.div_euclid(12) as i32, months.rem_euclid(12) as u32 + 1)
+}
+
+/// The months counted from January of year 0 to the month of `date`
+fn months_of(date: NaiveDate) -> i64 {
+    date.year() as i64 * 12 + date.month0() as i64
+}
+
+impl Overflow {
+    /// The `day` of the month `months` counted from January of year 0, if it's anywhere
+    pub fn date(self, months: i64, day: u32) -> Option<NaiveDate> {
+        let (year, month) = month_of(months);
+        let last = days_in_month(year, month);
+        match self {
+            _ if day <= last => NaiveDate::from_ymd_opt(year, month, day),
+            Overflow::Clamp => NaiveDate::from_ymd_opt(year, month, last),
+            Overflow::Skip => None,
+            Overflow::Roll => {
+                let (year, month) = month_of(months + 1);
+                NaiveDate::from_ymd_opt(year, month, day - last)
+            }
+        }
+    }
+
+    /// `start` `n` months on, at the same time of the day on the wall clock of `zone`, if it's anywhere
+    pub fn add_months(self, start: DateTime, n: u32, zone: Option<Tz>) -> Option<DateTime> {
+        let wall = start.wall(zone);
+        let date = self.date(months_of(wall.date()) + n as i64, wall.day())?;
+        start.at_wall(date.and_time(wall.time()), zone)
+    }
+
+    /// How many months on from `start` the month `time`, which is one of its steps, was meant for. Rolling over the
+    /// end of a month is what puts a step on an earlier day than `start`.
+    pub fn months_between(self, start: DateTime, time: DateTime, zone: Option<Tz>) -> u32 {
+        let (start, time) = (start.wall(zone).date(), time.wall(zone).date());
+        let mut months = months_of(time) - months_of(start);
+        if self == Overflow::Roll && time.day() < start.day() {
+            months -= 1;
+        }
+        months.max(0) as u32
+    }
+}
+
+/// A day of the week, or only the `nth` one of it in the month, counted from the end if negative
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByDay {
+    /// 0 for every one of them
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub nth: i32,
+    pub day: Weekday,
+}
+
+fn is_zero(n: &i32) -> bool {
+    *n == 0
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn is_one(n: &u32) -> bool {
+    *n == 1
+}
+
+/// Without any of `by_month_day` and `by_day`, it's on the same day as the first start time: the same day of the
+/// week, of the month, or of the year. `nth` in `by_day` counts within the month, or within the year for a yearly
+/// rule without `by_month`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(features = "scripting", derive(Trace, VmType, Userdata), gluon_trace(skip))]
+pub struct Rule {
+    pub freq: Freq,
+    /// Every how many periods
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub interval: u32,
+    /// Months, 1 to 12
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub by_month: Vec<u32>,
+    /// Days of the month, counted from the end if negative, -1 being the last
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub by_month_day: Vec<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub by_day: Vec<ByDay>,
+    /// Positions among the occurrences of each period, counted from the end if negative
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub by_set_pos: Vec<i32>,
+}
+
+/// No days at all for the months outside the years chrono has dates in
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let (y, m) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    NaiveDate::from_ymd_opt(y, m, 1)
+        .and_then(|next| next.pred_opt())
+        .map_or(0, |last| last.day())
+}
+
+fn days_of_month(year: i32, month: u32) -> impl Iterator<Item = NaiveDate> {
+    (1..=days_in_month(year, month)).filter_map(move |d| NaiveDate::from_ymd_opt(year, month, d))
+}
+
+/// Whether `index`, 1-based or negative from the end, is `pos` (0-based) of `len`
+fn at_index(index: i32, pos: usize, len: usize) -> bool {
+    index == pos as i32 + 1 || index == pos as i32 - len as i32
+}
+
+impl Rule {
+    pub fn new(freq: Freq, interval: u32) -> Rule {
+        Rule {
+            freq,
+            interval: interval.max(1),
+            by_month: Vec::new(),
+            by_month_day: Vec::new(),
+            by_day: Vec::new(),
+            by_set_pos: Vec::new(),
+        }
+    }
+
+    /// The index of the period of `date`, in periods of this frequency since the one of `anchor`
+    fn period(&self, anchor: NaiveDate, date: NaiveDate) -> i64 {
+        let monday = |d: NaiveDate| d - Duration::days(d.weekday().num_days_from_monday() as i64);
+        match self.freq {
+            Freq::Daily => (date - anchor).num_days(),
+            Freq::Weekly => (monday(date) - monday(anchor)).num_days() / 7,
+            Freq::Monthly => (date.year() - anchor.year()) as i64 * 12 + date.month() as i64 - anchor.month() as i64,
+            Freq::Yearly => (date.year() - anchor.year()) as i64,
+        }
+    }
+
+    /// The days that `by_day` picks can be counted within: the days of the period, split into months where
+    /// they're counted within months
+    fn scopes(&self, anchor: NaiveDate, period: i64) -> Vec<Vec<NaiveDate>> {
+        match self.freq {
+            Freq::Daily => vec![vec![anchor + Duration::days(period)]],
+            Freq::Weekly => {
+                let monday =
+                    anchor - Duration::days(anchor.weekday().num_days_from_monday() as i64) + Duration::weeks(period);
+                vec![(0..7).map(|d| monday + Duration::days(d)).collect()]
+            }
+            Freq::Monthly => {
+                let (year, month) = month_of(months_of(anchor) + period);
+                vec![days_of_month(year, month).collect()]
+            }
+            Freq::Yearly => {
+                let year = anchor.year() + period as i32;
+                if self.by_month.is_empty() {
+                    vec![(1..=12).flat_map(|m| days_of_month(year, m)).collect()]
+                } else {
+                    let mut months = self.by_month.clone();
+                    months.sort_unstable();
+                    months.dedup();
+                    months
+                        .into_iter()
+                        .filter(|m| (1..=12).contains(m))
+                        .map(|m| days_of_month(year, m).collect())
+                        .collect()
+                }
+            }
+        }
+    }
+
+    /// Whether the rule picks `date` out of `scope`
+    fn picks(&self, anchor: NaiveDate, scope: &[NaiveDate], date: NaiveDate) -> bool {
+        if !self.by_month.is_empty() && !self.by_month.contains(&date.month()) {
+            return false;
+        }
+        let month_days = days_in_month(date.year(), date.month()) as usize;
+        if !self.by_month_day.is_empty()
+            && !self
+                .by_month_day
+                .iter()
+                .any(|&n| at_index(n, date.day0() as usize, month_days))
+        {
+            return false;
+        }
+        if !self.by_day.is_empty() {
+            let same_day = scope.iter().filter(|d| d.weekday() == date.weekday());
+            let pos = same_day.clone().take_while(|&&d| d != date).count();
+            let len = same_day.count();
+            if !self
+                .by_day
+                .iter()
+                .any(|b| b.day == date.weekday() && (b.nth == 0 || at_index(b.nth, pos, len)))
+            {
+                return false;
+            }
+        }
+        if self.by_month_day.is_empty() && self.by_day.is_empty() {
+            // Days of months are worked out in `dates`
+            return self.freq != Freq::Weekly || date.weekday() == anchor.weekday();
+        }
+        true
+    }
+
+    /// The days picked in the `period`th period since the one of `anchor`. Those on the day of the month of
+    /// `anchor` are where `overflow` puts them.
+    fn dates(&self, anchor: NaiveDate, period: i64, overflow: Overflow) -> Vec<NaiveDate> {
+        let on_anchor_day = self.by_month_day.is_empty() && self.by_day.is_empty();
+        let months = match self.freq {
+            Freq::Monthly if on_anchor_day => vec![months_of(anchor) + period],
+            Freq::Yearly if on_anchor_day => {
+                let year = (anchor.year() as i64 + period) * 12;
+                if self.by_month.is_empty() {
+                    vec![year + anchor.month0() as i64]
+                } else {
+                    self.by_month.iter().map(|&m| year + m as i64 - 1).collect()
+                }
+            }
+            _ => {
+                return self
+                    .scopes(anchor, period)
+                    .iter()
+                    .flat_map(|scope| scope.iter().filter(move |&&d| self.picks(anchor, scope, d)))
+                    .copied()
+                    .collect();
+            }
+        };
+        months
+            .into_iter()
+            .filter_map(|m| overflow.date(m, anchor.day()))
+            .collect()
+    }
+
+    /// The occurrences in the `period`th period since the one of the day `first` is on, sorted
+    fn occurrences(
+        &self,
+        first: DateTime,
+        period: i64,
+        times: &[NaiveTime],
+        overflow: Overflow,
+        zone: Option<Tz>,
+    ) -> Vec<DateTime> {
+        let mut occurrences = Vec::new();
+        for date in self.dates(first.wall(zone).date(), period, overflow) {
+            for &time in times {
+                occurrences.extend(first.at_wall(date.and_time(time), zone));
+            }
+        }
+        occurrences.sort();
+        occurrences.dedup();
+        if self.by_set_pos.is_empty() {
+            return occurrences;
+        }
+        let len = occurrences.len();
+        occurrences
+            .into_iter()
+            .enumerate()
+            .filter(|&(pos, _)| self.by_set_pos.iter().any(|&n| at_index(n, pos, len)))
+            .map(|(_, t)| t)
+            .collect()
+    }
+
+    /// The first occurrence after `after`, or from `start[0]` on if there's no `after`. The times of the day are the
+    /// ones of `start`, which is sorted. `overflow` says where it is in months without the day of the month of
+    /// `start[0]`, when no other day is picked.
+    pub fn next(
+        &self,
+        start: &[DateTime],
+        after: Option<DateTime>,
+        overflow: Overflow,
+        zone: Option<Tz>,
+    ) -> Option<DateTime> {
+        let first = *start.first()?;
+        let anchor = first.wall(zone).date();
+        let mut times = start.iter().map(|t| t.wall(zone).time()).collect::<Vec<_>>();
+        times.sort();
+        times.dedup();
+        let from = after.map_or(first, |a| a.max(first));
+        let interval = self.interval.max(1) as i64;
+        let mut period = self.period(anchor, from.wall(zone).date());
+        period = period.div_euclid(interval).max(0) * interval;
+        for _ in 0..MAX_PERIODS {
+            let next = self
+                .occurrences(first, period, &times, overflow, zone)
+                .into_iter()
+                .find(|&t| t >= first && after.is_none_or(|a| t > a));
+            if next.is_some() {
+                return next;
+            }
+            period += interval;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{FixedOffset, TimeZone, Weekday};
+
+    use super::*;
+
+    fn datetime(y: i32, m: u32, d: u32, h: u32, mi: u32) -> DateTime {
+        DateTime(
+            FixedOffset::east_opt(3600)
+                .unwrap()
+                .with_ymd_and_hms(y, m, d, h, mi, 0)
+                .unwrap(),
+        )
+    }
+
+    fn take(rule: &Rule, start: &[DateTime], n: usize) -> Vec<DateTime> {
+        take_over(rule, start, n, Overflow::Clamp)
+    }
+
+    fn take_over(rule: &Rule, start: &[DateTime], n: usize, overflow: Overflow) -> Vec<DateTime> {
+        let mut last = None;
+        (0..n)
+            .map(|_| {
+                last = rule.next(start, last, overflow, None);
+                last.unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow() {
+        let jan31 = datetime(2021, 1, 31, 9, 0);
+        let months = |overflow: Overflow| (0..4).map(|n| overflow.add_months(jan31, n, None)).collect::<Vec<_>>();
+        assert_eq!(
+            months(Overflow::Clamp),
+            [
+                Some(jan31),
+                Some(datetime(2021, 2, 28, 9, 0)),
+                Some(datetime(2021, 3, 31, 9, 0)),
+                Some(datetime(2021, 4, 30, 9, 0)),
+            ]
+        );
+        assert_eq!(
+            months(Overflow::Skip),
+            [Some(jan31), None, Some(datetime(2021, 3, 31, 9, 0)), None]
+        );
+        assert_eq!(months(Overflow::Roll)[1], Some(datetime(2021, 3, 3, 9, 0)));
+        assert_eq!(
+            Overflow::Clamp.add_months(datetime(2020, 12, 31, 9, 0), 14, None),
+            Some(datetime(2022, 2, 28, 9, 0))
+        );
+        for &overflow in &[Overflow::Clamp, Overflow::Roll] {
+            for n in 0..24 {
+                let time = overflow.add_months(jan31, n, None).unwrap();
+                assert_eq!(overflow.months_between(jan31, time, None), n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rule() {
+        // Mon/Wed/Fri, from a Wednesday
+        let mut rule = Rule::new(Freq::Weekly, 1);
+        rule.by_day = [Weekday::Mon, Weekday::Wed, Weekday::Fri]
+            .iter()
+            .map(|&day| ByDay { nth: 0, day })
+            .collect();
+        let start = [datetime(2021, 1, 6, 9, 0)];
+        assert_eq!(
+            take(&rule, &start, 4),
+            [
+                datetime(2021, 1, 6, 9, 0),
+                datetime(2021, 1, 8, 9, 0),
+                datetime(2021, 1, 11, 9, 0),
+                datetime(2021, 1, 13, 9, 0),
+            ]
+        );
+        // Every other week, at two times of the day
+        rule.interval = 2;
+        let start = [datetime(2021, 1, 8, 9, 0), datetime(2021, 1, 8, 18, 0)];
+        assert_eq!(
+            take(&rule, &start, 4),
+            [
+                datetime(2021, 1, 8, 9, 0),
+                datetime(2021, 1, 8, 18, 0),
+                datetime(2021, 1, 18, 9, 0),
+                datetime(2021, 1, 18, 18, 0),
+            ]
+        );
+
+        // The second Tuesday of the month
+        let mut rule = Rule::new(Freq::Monthly, 1);
+        rule.by_day = vec![ByDay {
+            nth: 2,
+            day: Weekday::Tue,
+        }];
+        let start = [datetime(2021, 1, 1, 10, 0)];
+        assert_eq!(
+            take(&rule, &start, 3),
+            [
+                datetime(2021, 1, 12, 10, 0),
+                datetime(2021, 2, 9, 10, 0),
+                datetime(2021, 3, 9, 10, 0),
+            ]
+        );
+
+        // The last weekday of the month
+        rule.by_day = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]
+            .iter()
+            .map(|&day| ByDay { nth: 0, day })
+            .collect();
+        rule.by_set_pos = vec![-1];
+        assert_eq!(
+            take(&rule, &start, 3),
+            [
+                datetime(2021, 1, 29, 10, 0),
+                datetime(2021, 2, 26, 10, 0),
+                datetime(2021, 3, 31, 10, 0),
+            ]
+        );
+
+        // The day before the last of the month
+        let mut rule = Rule::new(Freq::Monthly, 1);
+        rule.by_month_day = vec![-2];
+        assert_eq!(
+            take(&rule, &start, 3),
+            [
+                datetime(2021, 1, 30, 10, 0),
+                datetime(2021, 2, 27, 10, 0),
+                datetime(2021, 3, 30, 10, 0),
+            ]
+        );
+
+        // Yearly on March 3, from after it
+        let mut rule = Rule::new(Freq::Yearly, 1);
+        rule.by_month = vec![3];
+        rule.by_month_day = vec![3];
+        let start = [datetime(2021, 6, 1, 8, 0)];
+        assert_eq!(
+            take(&rule, &start, 2),
+            [datetime(2022, 3, 3, 8, 0), datetime(2023, 3, 3, 8, 0)]
+        );
+        // Yearly on the day it starts, which only comes every 4 years
+        let rule = Rule::new(Freq::Yearly, 1);
+        let start = [datetime(2020, 2, 29, 8, 0)];
+        assert_eq!(
+            take_over(&rule, &start, 2, Overflow::Skip),
+            [datetime(2020, 2, 29, 8, 0), datetime(2024, 2, 29, 8, 0)]
+        );
+        assert_eq!(
+            take(&rule, &start, 2),
+            [datetime(2020, 2, 29, 8, 0), datetime(2021, 2, 28, 8, 0)]
+        );
+        assert_eq!(
+            take_over(&rule, &start, 2, Overflow::Roll),
+            [datetime(2020, 2, 29, 8, 0), datetime(2021, 3, 1, 8, 0)]
+        );
+
+        // Never
+        let mut rule = Rule::new(Freq::Yearly, 1);
+        rule.by_month = vec![2];
+        rule.by_month_day = vec![30];
+        assert_eq!(rule.next(&start, None, Overflow::Clamp, None), None);
+    }
+}
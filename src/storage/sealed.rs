@@ -0,0 +1,404 @@
+//! Sealing the objects and logs, so that a database at rest can't be read without its key. `SealedBackend` wraps
+//! another backend and seals the values of the `objs` and `logs` trees as they're written, with a key derived from
+//! a passphrase or a keyfile. The keys of the trees are left as they are, as the IDs and times have to stay in order
+//! for the range scans, and so are the indexes: when records were made and of what type can still be told, but not
+//! what's in them.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use sled::transaction::ConflictableTransactionError;
+
+use crate::storage::{
+    backend::{Backend, BackendTxn, Iter, KeyRange, Tree, TxResult},
+    Error, Result,
+};
+
+/// The meta entries of the seal, which are only seen from below `SealedBackend`
+const SALT: &[u8] = b"seal_salt";
+const CHECK: &[u8] = b"seal_check";
+const PREFIX: &[u8] = b"seal_";
+/// Where the blobs kept outside of the trees are sealed as if they were
+const BLOB: &[u8] = b"seal_blob";
+/// What's sealed under `CHECK`, to tell whether a key is the right one
+const CHECK_VALUE: &[u8] = b"sched";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// What the key is derived from
+#[derive(Clone, Debug)]
+pub enum Secret {
+    Passphrase(String),
+    /// A file whose whole content is the secret
+    Keyfile(PathBuf),
+}
+
+impl Secret {
+    /// The secret set in the environment: a keyfile in `SCHED_KEYFILE`, or else a passphrase in `SCHED_PASSPHRASE`
+    pub fn from_env() -> Option<Secret> {
+        env::var_os("SCHED_KEYFILE")
+            .map(|path| Secret::Keyfile(path.into()))
+            .or_else(|| env::var("SCHED_PASSPHRASE").ok().map(Secret::Passphrase))
+    }
+
+    fn seal(&self, salt: &[u8]) -> Result<Seal> {
+        let secret = match self {
+            Secret::Passphrase(pass) => pass.as_bytes().to_vec(),
+            Secret::Keyfile(path) => fs::read(path)?,
+        };
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(&secret, salt, &mut key)
+            .map_err(|e| Error::Seal(e.to_string()))?;
+        Ok(Seal(XChaCha20Poly1305::new(Key::from_slice(&key))))
+    }
+}
+
+fn random(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::Seal(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn is_sealed(tree: Tree) -> bool {
+    matches!(tree, Tree::Objs | Tree::Logs)
+}
+
+fn is_hidden(tree: Tree, key: &[u8]) -> bool {
+    tree == Tree::Meta && key.starts_with(PREFIX)
+}
+
+struct Seal(XChaCha20Poly1305);
+
+impl Seal {
+    /// Sealed along with where it's stored, so that it can't be moved elsewhere
+    fn aad(tree: Tree, key: &[u8]) -> Vec<u8> {
+        [&[tree as u8][..], key].concat()
+    }
+
+    /// A random nonce followed by the sealed value
+    fn seal(&self, tree: Tree, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let nonce = random(NONCE_LEN)?;
+        let aad = Seal::aad(tree, key);
+        let sealed = self
+            .0
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: value, aad: &aad })
+            .map_err(|e| Error::Seal(e.to_string()))?;
+        Ok([nonce, sealed].concat())
+    }
+
+    fn unseal(&self, tree: Tree, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        if value.len() < NONCE_LEN {
+            return Err(Error::corrupt("sealed value too short"));
+        }
+        let (nonce, sealed) = value.split_at(NONCE_LEN);
+        let aad = Seal::aad(tree, key);
+        self.0
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad: &aad })
+            .map_err(|_| Error::corrupt("can't be unsealed, it's been tampered with or sealed with another key"))
+    }
+
+    /// Unseals a value read from `tree`, if it's one of the sealed ones
+    fn open(&self, tree: Tree, key: &[u8], value: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        match value {
+            Some(value) if is_sealed(tree) => self.unseal(tree, key, &value).map(Some),
+            value => Ok(value),
+        }
+    }
+}
+
+/// The seal of a sealed database, if `secret` is the right one for it
+fn existing_seal(backend: &dyn Backend, salt: &[u8], secret: &Secret) -> Result<Seal> {
+    let seal = secret.seal(salt)?;
+    let check = backend
+        .get(Tree::Meta, CHECK)?
+        .ok_or_else(|| Error::corrupt("the database is sealed without a check"))?;
+    match seal.unseal(Tree::Meta, CHECK, &check) {
+        Ok(value) if value == CHECK_VALUE => Ok(seal),
+        _ => Err(Error::WrongKey),
+    }
+}
+
+/// A change of the key a database is sealed with, worked out before anything is written, so that what's kept
+/// outside of the database can be sealed with the new key first
+pub struct Rekey {
+    old: Option<Seal>,
+    /// The new seal with its salt and check, or none to leave the database unsealed
+    new: Option<(Seal, Vec<u8>, Vec<u8>)>,
+}
+
+impl Rekey {
+    /// A change to a new key derived from `new`, or to no key without one. `old` is what `backend` is sealed with
+    /// now, if it is.
+    pub fn new(backend: &dyn Backend, old: Option<&Secret>, new: Option<&Secret>) -> Result<Rekey> {
+        let old = match (backend.get(Tree::Meta, SALT)?, old) {
+            (Some(salt), Some(old)) => Some(existing_seal(backend, &salt, old)?),
+            (Some(_), None) => return Err(Error::Sealed),
+            (None, _) => None,
+        };
+        let new = match new {
+            Some(new) => {
+                let salt = random(SALT_LEN)?;
+                let seal = new.seal(&salt)?;
+                let check = seal.seal(Tree::Meta, CHECK, CHECK_VALUE)?;
+                Some((seal, salt, check))
+            }
+            None => None,
+        };
+        Ok(Rekey { old, new })
+    }
+
+    /// Seals a blob the way the database will once it has the new key
+    pub fn seal_blob(&self, blob: Vec<u8>) -> Result<Vec<u8>> {
+        match &self.new {
+            Some((seal, _, _)) => seal.seal(Tree::Meta, BLOB, &blob),
+            None => Ok(blob),
+        }
+    }
+
+    /// Seals everything in `backend` with the new key, in one transaction
+    pub fn apply(&self, backend: &dyn Backend) -> Result<()> {
+        let mut records = Vec::new();
+        for &tree in &[Tree::Objs, Tree::Logs] {
+            for res in backend.iter(tree) {
+                let (key, mut value) = res?;
+                if let Some(old) = &self.old {
+                    value = old.unseal(tree, &key, &value)?;
+                }
+                if let Some((new, _, _)) = &self.new {
+                    value = new.seal(tree, &key, &value)?;
+                }
+                records.push((tree, key, value));
+            }
+        }
+        backend.transaction(&mut |tx| {
+            for (tree, key, value) in &records {
+                tx.insert(*tree, key, value)?;
+            }
+            match &self.new {
+                Some((_, salt, check)) => {
+                    tx.insert(Tree::Meta, SALT, salt)?;
+                    tx.insert(Tree::Meta, CHECK, check)?;
+                }
+                None => {
+                    tx.remove(Tree::Meta, SALT)?;
+                    tx.remove(Tree::Meta, CHECK)?;
+                }
+            }
+            Ok(())
+        })?;
+        backend.flush()
+    }
+}
+
+/// Seals everything in `backend` with a new key derived from `new`, or leaves it unsealed without one. `old` is what
+/// it's sealed with now, if it is.
+pub fn rekey(backend: &dyn Backend, old: Option<&Secret>, new: Option<&Secret>) -> Result<()> {
+    Rekey::new(backend, old, new)?.apply(backend)
+}
+
+/// A backend whose objects and logs are sealed in the one below it
+pub struct SealedBackend {
+    inner: Box<dyn Backend>,
+    seal: Seal,
+}
+
+impl SealedBackend {
+    /// Opens `inner` with the key derived from `secret`. A database that isn't sealed yet is sealed then and there,
+    /// along with everything already in it.
+    pub fn open(inner: Box<dyn Backend>, secret: &Secret) -> Result<SealedBackend> {
+        let salt = match inner.get(Tree::Meta, SALT)? {
+            Some(salt) => salt,
+            None => {
+                rekey(&*inner, None, Some(secret))?;
+                inner
+                    .get(Tree::Meta, SALT)?
+                    .ok_or_else(|| Error::corrupt("the seal is gone right after sealing"))?
+            }
+        };
+        let seal = existing_seal(&*inner, &salt, secret)?;
+        Ok(SealedBackend { inner, seal })
+    }
+}
+
+fn abort<T>(res: Result<T>) -> TxResult<T> {
+    res.map_err(ConflictableTransactionError::Abort)
+}
+
+struct SealedTxn<'a> {
+    inner: &'a dyn BackendTxn,
+    seal: &'a Seal,
+}
+
+impl BackendTxn for SealedTxn<'_> {
+    fn get(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        if is_hidden(tree, key) {
+            return Ok(None);
+        }
+        let value = self.inner.get(tree, key)?;
+        abort(self.seal.open(tree, key, value))
+    }
+
+    fn insert(&self, tree: Tree, key: &[u8], value: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        let old = if is_sealed(tree) {
+            let sealed = abort(self.seal.seal(tree, key, value))?;
+            self.inner.insert(tree, key, &sealed)?
+        } else {
+            self.inner.insert(tree, key, value)?
+        };
+        abort(self.seal.open(tree, key, old))
+    }
+
+    fn remove(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        let old = self.inner.remove(tree, key)?;
+        abort(self.seal.open(tree, key, old))
+    }
+}
+
+impl Backend for SealedBackend {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if is_hidden(tree, key) {
+            return Ok(None);
+        }
+        self.seal.open(tree, key, self.inner.get(tree, key)?)
+    }
+
+    fn range(&self, tree: Tree, range: KeyRange) -> Iter<'_> {
+        Box::new(
+            self.inner
+                .range(tree, range)
+                .filter(move |res| !matches!(res, Ok((key, _)) if is_hidden(tree, key)))
+                .map(move |res| {
+                    let (key, value) = res?;
+                    let value = self.seal.open(tree, &key, Some(value))?.unwrap_or_default();
+                    Ok((key, value))
+                }),
+        )
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn BackendTxn) -> TxResult<()>) -> Result<()> {
+        self.inner.transaction(&mut |tx| {
+            f(&SealedTxn {
+                inner: tx,
+                seal: &self.seal,
+            })
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn seal_blob(&self, blob: Vec<u8>) -> Result<Vec<u8>> {
+        self.seal.seal(Tree::Meta, BLOB, &blob)
+    }
+
+    fn unseal_blob(&self, blob: Vec<u8>) -> Result<Vec<u8>> {
+        self.seal.unseal(Tree::Meta, BLOB, &blob)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::backend::MemoryBackend;
+
+    /// Lets the test look below the seal of a backend it has handed over
+    struct Shared(Arc<MemoryBackend>);
+
+    impl Backend for Shared {
+        fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.0.get(tree, key)
+        }
+
+        fn range(&self, tree: Tree, range: KeyRange) -> Iter<'_> {
+            self.0.range(tree, range)
+        }
+
+        fn transaction(&self, f: &mut dyn FnMut(&dyn BackendTxn) -> TxResult<()>) -> Result<()> {
+            self.0.transaction(f)
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.0.flush()
+        }
+    }
+
+    fn pass(s: &str) -> Secret {
+        Secret::Passphrase(s.into())
+    }
+
+    #[test]
+    fn test_sealed() {
+        let raw = Arc::new(MemoryBackend::new());
+        raw.transaction(&mut |tx| {
+            tx.insert(Tree::Objs, b"\0\0\0\x01", b"{\"mood\":\"meh\"}")?;
+            Ok(())
+        })
+        .unwrap();
+        let sealed = SealedBackend::open(Box::new(Shared(raw.clone())), &pass("old")).unwrap();
+        // What was there already is sealed too
+        assert_eq!(
+            sealed.get(Tree::Objs, b"\0\0\0\x01").unwrap().unwrap(),
+            b"{\"mood\":\"meh\"}".to_vec()
+        );
+        sealed
+            .transaction(&mut |tx| {
+                tx.insert(Tree::Logs, b"\0\0\0\x02", b"{\"sleep\":8}")?;
+                tx.insert(Tree::Meta, b"logs_id", b"\0\0\0\x03")?;
+                Ok(())
+            })
+            .unwrap();
+        let below = raw.get(Tree::Logs, b"\0\0\0\x02").unwrap().unwrap();
+        assert!(!below.windows(5).any(|w| w == b"sleep"));
+        assert_eq!(
+            raw.get(Tree::Meta, b"logs_id").unwrap().unwrap(),
+            b"\0\0\0\x03".to_vec()
+        );
+        assert!(sealed.get(Tree::Meta, SALT).unwrap().is_none());
+        assert_eq!(sealed.iter(Tree::Meta).count(), 1);
+
+        // Values can't be swapped around
+        raw.transaction(&mut |tx| {
+            tx.insert(Tree::Objs, b"\0\0\0\x02", &below)?;
+            Ok(())
+        })
+        .unwrap();
+        assert!(sealed.get(Tree::Objs, b"\0\0\0\x02").is_err());
+
+        assert!(matches!(
+            SealedBackend::open(Box::new(Shared(raw.clone())), &pass("wrong")),
+            Err(Error::WrongKey)
+        ));
+        rekey(&Shared(raw.clone()), Some(&pass("old")), Some(&pass("new"))).unwrap_err();
+        raw.transaction(&mut |tx| {
+            tx.remove(Tree::Objs, b"\0\0\0\x02")?;
+            Ok(())
+        })
+        .unwrap();
+        // What's sealed ahead of the database opens once it has the new key, and nothing's written until then
+        let change = Rekey::new(&Shared(raw.clone()), Some(&pass("old")), Some(&pass("new"))).unwrap();
+        let blob = change.seal_blob(b"snapshot".to_vec()).unwrap();
+        assert!(SealedBackend::open(Box::new(Shared(raw.clone())), &pass("new")).is_err());
+        change.apply(&Shared(raw.clone())).unwrap();
+        let sealed = SealedBackend::open(Box::new(Shared(raw.clone())), &pass("new")).unwrap();
+        assert_eq!(sealed.unseal_blob(blob).unwrap(), b"snapshot".to_vec());
+        assert_eq!(
+            sealed.get(Tree::Logs, b"\0\0\0\x02").unwrap().unwrap(),
+            b"{\"sleep\":8}".to_vec()
+        );
+        rekey(&Shared(raw.clone()), Some(&pass("new")), None).unwrap();
+        assert_eq!(
+            raw.get(Tree::Logs, b"\0\0\0\x02").unwrap().unwrap(),
+            b"{\"sleep\":8}".to_vec()
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! A backend on top of SQLite. Objects and logs get a row each, with their JSON in a text column and `typ`/`time`
+//! pulled out into generated columns, so the data can be looked into with `sqlite3` and `json_extract`. Sealed ones
+//! aren't JSON, and are kept as blobs in the same column without anything pulled out.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, types::Value, Connection, ErrorCode, OptionalExtension};
+use sled::transaction::ConflictableTransactionError;
+
+use crate::storage::{
+    backend::{Backend, BackendTxn, Iter, KeyRange, KvPair, Tree, TxResult},
+    Error, Result,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS objs (
+    id INTEGER PRIMARY KEY,
+    obj TEXT NOT NULL,
+    typ TEXT GENERATED ALWAYS AS (CASE WHEN typeof(obj) = 'text' THEN json_extract(obj, '$.typ') END) VIRTUAL
+);
+CREATE TABLE IF NOT EXISTS logs (
+    id INTEGER PRIMARY KEY,
+    log TEXT NOT NULL,
+    typ TEXT GENERATED ALWAYS AS (CASE WHEN typeof(log) = 'text' THEN json_extract(log, '$.typ') END) VIRTUAL,
+    time TEXT GENERATED ALWAYS AS (CASE WHEN typeof(log) = 'text' THEN json_extract(log, '$.time') END) VIRTUAL
+);
+CREATE TABLE IF NOT EXISTS objs_by_type (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS logs_by_time (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS logs_by_type (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID;
+";
+
+/// How a tree is laid out as a table. The objects and logs are keyed by their integer IDs and hold JSON text;
+/// everything else is kept as blobs, which SQLite orders bytewise just like sled.
+struct Table {
+    name: &'static str,
+    key: &'static str,
+    value: &'static str,
+    json: bool,
+}
+
+fn table(tree: Tree) -> Table {
+    let (name, key, value, json) = match tree {
+        Tree::Meta => ("meta", "key", "value", false),
+        Tree::Objs => ("objs", "id", "obj", true),
+        Tree::Logs => ("logs", "id", "log", true),
+        Tree::ObjsByType => ("objs_by_type", "key", "value", false),
+        Tree::LogsByTime => ("logs_by_time", "key", "value", false),
+        Tree::LogsByType => ("logs_by_type", "key", "value", false),
+    };
+    Table { name, key, value, json }
+}
+
+impl Table {
+    fn key_param(&self, key: &[u8]) -> rusqlite::Result<Value> {
+        if self.json {
+            let id = key.try_into().map(u32::from_be_bytes).map_err(|_| {
+                let e = Error::corrupt(format!("malformed id key {:?}", key));
+                rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+            })?;
+            Ok(Value::Integer(id.into()))
+        } else {
+            Ok(Value::Blob(key.to_vec()))
+        }
+    }
+
+    fn value_param(&self, value: &[u8]) -> Value {
+        match std::str::from_utf8(value) {
+            Ok(text) if self.json => Value::Text(text.to_string()),
+            // Sealed objects and logs aren't text, and are kept byte for byte like everything else
+            _ => Value::Blob(value.to_vec()),
+        }
+    }
+
+    fn get(&self, conn: &Connection, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let sql = format!("SELECT {} FROM {} WHERE {} = ?1", self.value, self.name, self.key);
+        conn.query_row(&sql, params![self.key_param(key)?], |row| row.get(0))
+            .optional()
+            .map(|v| v.map(from_sql))
+    }
+
+    fn insert(&self, conn: &Connection, key: &[u8], value: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let old = self.get(conn, key)?;
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} ({}, {}) VALUES (?1, ?2)",
+            self.name, self.key, self.value
+        );
+        conn.execute(&sql, params![self.key_param(key)?, self.value_param(value)])?;
+        Ok(old)
+    }
+
+    fn remove(&self, conn: &Connection, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let old = self.get(conn, key)?;
+        let sql = format!("DELETE FROM {} WHERE {} = ?1", self.name, self.key);
+        conn.execute(&sql, params![self.key_param(key)?])?;
+        Ok(old)
+    }
+
+    /// Up to `limit` of the entries in the range, from its start, or from its end with `rev`
+    fn page(&self, conn: &Connection, range: &KeyRange, rev: bool, limit: usize) -> rusqlite::Result<Vec<KvPair>> {
+        let mut conds = vec!["1".to_string()];
+        let mut args = Vec::new();
+        for (bound, incl, excl) in [(&range.0, ">=", ">"), (&range.1, "<=", "<")] {
+            let (op, key) = match bound {
+                Bound::Included(key) => (incl, key),
+                Bound::Excluded(key) => (excl, key),
+                Bound::Unbounded => continue,
+            };
+            args.push(self.key_param(key)?);
+            conds.push(format!("{} {} ?{}", self.key, op, args.len()));
+        }
+        let sql = format!(
+            "SELECT {}, {} FROM {} WHERE {} ORDER BY {} {} LIMIT {}",
+            self.key,
+            self.value,
+            self.name,
+            conds.join(" AND "),
+            self.key,
+            if rev { "DESC" } else { "ASC" },
+            limit
+        );
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(args, |row| Ok((from_sql(row.get(0)?), from_sql(row.get(1)?))))?;
+        rows.collect()
+    }
+}
+
+fn from_sql(value: Value) -> Vec<u8> {
+    match value {
+        Value::Integer(id) => (id as u32).to_be_bytes().to_vec(),
+        Value::Text(s) => s.into_bytes(),
+        Value::Blob(b) => b,
+        Value::Null | Value::Real(_) => Vec::new(),
+    }
+}
+
+/// How many rows a range reads at a time
+const PAGE_SIZE: usize = 128;
+
+/// Reads a range a page at a time from either end, narrowing it down past what's been read. The statements can't
+/// outlive the lock on the connection, so each page is a query of its own.
+struct RangeIter<'a> {
+    backend: &'a SqliteBackend,
+    table: Table,
+    range: KeyRange,
+    front: VecDeque<KvPair>,
+    /// Read from the end, so the last entry is at the front
+    back: VecDeque<KvPair>,
+    /// Whether everything in the range has been read into the pages
+    done: bool,
+}
+
+impl RangeIter<'_> {
+    fn fill(&mut self, rev: bool) -> Result<()> {
+        let conn = self.backend.conn.lock().unwrap();
+        let page = self
+            .table
+            .page(&conn, &self.range, rev, PAGE_SIZE)
+            .map_err(|e| classify(&self.backend.path, from_sqlite(e)))?;
+        self.done = page.len() < PAGE_SIZE;
+        if let Some((key, _)) = page.last() {
+            let past = Bound::Excluded(key.clone());
+            if rev {
+                self.range.1 = past;
+            } else {
+                self.range.0 = past;
+            }
+        }
+        if rev {
+            self.back.extend(page);
+        } else {
+            self.front.extend(page);
+        }
+        Ok(())
+    }
+
+    fn next_from(&mut self, rev: bool) -> Option<Result<KvPair>> {
+        loop {
+            let near = if rev { &mut self.back } else { &mut self.front };
+            if let Some(entry) = near.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                break;
+            }
+            if let Err(e) = self.fill(rev) {
+                // Nothing more is read after an error
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        // The rest has been read from the other end already
+        let far = if rev { &mut self.front } else { &mut self.back };
+        far.pop_back().map(Ok)
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<KvPair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_from(false)
+    }
+}
+
+impl DoubleEndedIterator for RangeIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_from(true)
+    }
+}
+
+pub struct SqliteBackend {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+}
+
+impl SqliteBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteBackend> {
+        let path = path.as_ref().to_owned();
+        let conn = Connection::open(&path).map_err(|e| classify(&path, e.into()))?;
+        conn.execute_batch(SCHEMA).map_err(|e| classify(&path, e.into()))?;
+        Ok(SqliteBackend {
+            conn: Mutex::new(conn),
+            path,
+        })
+    }
+}
+
+/// Takes out the errors of our own that were passed through SQLite
+fn from_sqlite(e: rusqlite::Error) -> Error {
+    match e {
+        rusqlite::Error::ToSqlConversionFailure(e) => match e.downcast::<Error>() {
+            Ok(e) => *e,
+            Err(e) => Error::Sqlite(rusqlite::Error::ToSqlConversionFailure(e)),
+        },
+        e => Error::Sqlite(e),
+    }
+}
+
+/// Picks out the SQLite errors that have a variant of their own
+fn classify(path: &Path, e: Error) -> Error {
+    let code = match &e {
+        Error::Sqlite(rusqlite::Error::SqliteFailure(f, _)) => Some(f.code),
+        _ => None,
+    };
+    match code {
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => Error::Locked(path.to_owned()),
+        Some(ErrorCode::DatabaseCorrupt) | Some(ErrorCode::NotADatabase) => Error::corrupt(e),
+        _ => e,
+    }
+}
+
+struct SqliteTxn<'a>(&'a Connection);
+
+impl BackendTxn for SqliteTxn<'_> {
+    fn get(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(table(tree).get(self.0, key).map_err(from_sqlite)?)
+    }
+
+    fn insert(&self, tree: Tree, key: &[u8], value: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(table(tree).insert(self.0, key, value).map_err(from_sqlite)?)
+    }
+
+    fn remove(&self, tree: Tree, key: &[u8]) -> TxResult<Option<Vec<u8>>> {
+        Ok(table(tree).remove(self.0, key).map_err(from_sqlite)?)
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        table(tree)
+            .get(&self.conn.lock().unwrap(), key)
+            .map_err(|e| classify(&self.path, from_sqlite(e)))
+    }
+
+    fn range(&self, tree: Tree, range: KeyRange) -> Iter<'_> {
+        Box::new(RangeIter {
+            backend: self,
+            table: table(tree),
+            range,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn BackendTxn) -> TxResult<()>) -> Result<()> {
+        // Holding the lock throughout means there's never a conflict to retry
+        let mut conn = self.conn.lock().unwrap();
+        let res = conn.transaction().map_err(Error::from).and_then(|tx| {
+            // Dropping `tx` without committing rolls it back
+            match f(&SqliteTxn(&tx)) {
+                Ok(()) => Ok(tx.commit()?),
+                Err(ConflictableTransactionError::Abort(e)) => Err(e),
+                Err(ConflictableTransactionError::Storage(e)) => Err(e.into()),
+                Err(_) => unreachable!("conflict in a SQLite transaction"),
+            }
+        });
+        res.map_err(|e| classify(&self.path, e))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{
+        api::*,
+        time::{DateTime, Duration},
+        OptRepeated, Storage,
+    };
+
+    #[test]
+    fn test_json_columns() {
+        let path = std::env::temp_dir().join(format!("sched-test-{}.sqlite", std::process::id()));
+        let store = Storage::open_sqlite(&path).unwrap();
+        let start = OptRepeated::Single(DateTime::now());
+        let duration = Duration(chrono::Duration::hours(1));
+        let id = store.create_event("event".into(), start, duration, None, None).unwrap();
+        store.obj_set_desc(id, Some("desc".into())).unwrap();
+        assert_eq!(store.get_obj::<Event>(id).unwrap().desc.as_deref(), Some("desc"));
+
+        let conn = Connection::open(&path).unwrap();
+        let (typ, desc): (String, String) = conn
+            .query_row(
+                "SELECT typ, json_extract(obj, '$.desc') FROM objs WHERE id = ?1",
+                params![id.0],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((typ.as_str(), desc.as_str()), ("event", "desc"));
+        let logs: u32 = conn
+            .query_row("SELECT count(*) FROM logs WHERE typ LIKE 'obj.%'", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(logs, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_key() {
+        let path = std::env::temp_dir().join(format!("sched-test-key-{}.sqlite", std::process::id()));
+        let backend = SqliteBackend::open(&path).unwrap();
+        assert!(matches!(backend.get(Tree::Objs, b"id"), Err(Error::Corrupt { .. })));
+        let res = backend.transaction(&mut |tx| tx.insert(Tree::Logs, b"id", b"{}").map(drop));
+        assert!(matches!(res, Err(Error::Corrupt { .. })));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_range_pages() {
+        let path = std::env::temp_dir().join(format!("sched-test-range-{}.sqlite", std::process::id()));
+        let backend = SqliteBackend::open(&path).unwrap();
+        let n = PAGE_SIZE as u32 * 2 + 10;
+        backend
+            .transaction(&mut |tx| {
+                for i in 0..n {
+                    tx.insert(Tree::Logs, &i.to_be_bytes(), b"{}")?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let keys = |iter: Iter| {
+            iter.map(|res| u32::from_be_bytes(res.unwrap().0[..].try_into().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(keys(backend.range(Tree::Logs, all.clone())), (0..n).collect::<Vec<_>>());
+        assert_eq!(
+            keys(Box::new(backend.range(Tree::Logs, all.clone()).rev())),
+            (0..n).rev().collect::<Vec<_>>()
+        );
+        // Both ends meet in the middle without leaving anything out or reading it twice
+        let mut iter = backend.range(Tree::Logs, all);
+        let mut seen = Vec::new();
+        while let (Some(a), b) = (iter.next(), iter.next_back()) {
+            seen.push(a.unwrap().0);
+            seen.extend(b.map(|b| b.unwrap().0));
+        }
+        assert_eq!(seen.len(), n as usize);
+        let range = (
+            Bound::Excluded(5u32.to_be_bytes().to_vec()),
+            Bound::Included(200u32.to_be_bytes().to_vec()),
+        );
+        assert_eq!(keys(backend.range(Tree::Logs, range)), (6..=200).collect::<Vec<_>>());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_sealed() {
+        use crate::storage::sealed::{SealedBackend, Secret};
+
+        let path = std::env::temp_dir().join(format!("sched-test-sealed-{}.sqlite", std::process::id()));
+        let store = Storage::open_sqlite(&path).unwrap();
+        let start = OptRepeated::Single(DateTime::now());
+        let id = store
+            .create_event("event".into(), start, Duration(chrono::Duration::hours(1)), None, None)
+            .unwrap();
+        drop(store);
+
+        // What's there is sealed when it's first opened with a key, and still there after that
+        let secret = Secret::Passphrase("pass".into());
+        let open = || {
+            let backend = SealedBackend::open(Box::new(SqliteBackend::open(&path).unwrap()), &secret).unwrap();
+            Storage::with_backend(Box::new(backend)).unwrap()
+        };
+        let store = open();
+        assert_eq!(store.get_obj::<Event>(id).unwrap().name, "event");
+        store.obj_set_desc(id, Some("desc".into())).unwrap();
+        drop(store);
+        assert_eq!(open().get_obj::<Event>(id).unwrap().desc.as_deref(), Some("desc"));
+
+        let conn = Connection::open(&path).unwrap();
+        let typ: String = conn
+            .query_row("SELECT typeof(obj) FROM objs WHERE id = ?1", params![id.0], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(typ, "blob");
+        std::fs::remove_file(path).unwrap();
+    }
+}
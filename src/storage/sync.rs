@@ -0,0 +1,355 @@
+//! Merging in another store that diverged from this one, e.g. the same schedule kept on a laptop and a desktop.
+//! IDs are unique across devices, so the records only one side has can simply be added, and it's the logs that tell
+//! how the objects have to change: the objects are taken back to where the two sides forked by reverting the logs
+//! made since, then those of both sides are replayed in the order they happened.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::storage::{
+    api::*,
+    dump::{Dump, DumpLog, DumpObj, Problem, DUMP_VERSION},
+    replay::{self, ObjMap},
+    Error, Result,
+};
+
+/// A change that doesn't apply cleanly once the logs of both sides are put in order, because the other side has
+/// changed the same thing. Nothing is dropped: the later change is kept, and both stay in the logs.
+#[derive(Clone, Debug)]
+pub enum Conflict {
+    /// The log changed `field` from `expected`, but by then it held `found`
+    Edit {
+        log: LogId,
+        obj: ObjId,
+        /// `desc`, `attrs.<name>` or the name of a prop
+        field: String,
+        expected: Option<AttrValue>,
+        found: Option<AttrValue>,
+    },
+    /// The log changed an object that had been deleted, so it stays deleted
+    EditDeleted { log: LogId, obj: ObjId },
+    /// The log deleted an object that had been changed since the deleted copy was taken
+    DeleteEdited { log: LogId, obj: ObjId },
+}
+
+fn show(val: &Option<AttrValue>) -> String {
+    val.as_ref().map_or_else(|| "unset".into(), AttrValue::to_string)
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Conflict::Edit {
+                log,
+                obj,
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} changed {} '{}' from {}, but it was {} by then",
+                log,
+                obj,
+                field,
+                show(expected),
+                show(found)
+            ),
+            Conflict::EditDeleted { log, obj } => write!(f, "{} changed {}, which had been deleted", log, obj),
+            Conflict::DeleteEdited { log, obj } => {
+                write!(f, "{} deleted {}, which had been changed since", log, obj)
+            }
+        }
+    }
+}
+
+/// What a sync has brought in
+#[derive(Clone, Debug, Default)]
+pub struct SyncReport {
+    /// The logs only the other store had
+    pub pulled: Vec<LogId>,
+    /// The device this store moved what it made to, as the other one had handed out IDs on the same device
+    pub moved_to: Option<Device>,
+    pub conflicts: Vec<Conflict>,
+    /// What had to be repaired for the merged records to be consistent, e.g. sub tasks of a deleted task
+    pub repaired: Vec<Problem>,
+}
+
+fn script_log(id: LogId, log: &AttrValue) -> Result<ScriptLog> {
+    let proto: ProtoLog = serde_json::from_value(log.clone()).map_err(|e| Error::corrupt(e).at(id))?;
+    Ok(proto.with_id(id))
+}
+
+/// Applies a log of the merged history, reporting where it doesn't start from what the object holds
+fn apply(objs: &mut ObjMap, log: &ScriptLog, conflicts: &mut Vec<Conflict>) -> Result<()> {
+    let unreplayable = |_| Error::Unreplayable(log.id);
+    let id = match replay::changed_obj(log) {
+        // The state is restored afterwards anyway
+        Some(State::ID) | None => return Ok(()),
+        Some(id) => id,
+    };
+    // (field, what the object holds, what the log expects it to, what the log sets it to)
+    let fields = match (log.typ.as_str(), objs.get(&id)) {
+        (CreateObj::LOG_TYPE, _) => Vec::new(),
+        (ObjDelete::LOG_TYPE, None) => return Ok(()),
+        (ObjDelete::LOG_TYPE, Some(obj)) => {
+            let delete: ObjDelete = replay::typed(log).map_err(unreplayable)?;
+            if &delete.obj != obj {
+                conflicts.push(Conflict::DeleteEdited { log: log.id, obj: id });
+            }
+            Vec::new()
+        }
+        // What finishing changes is in the logs it caused, older ones are replayed as they are
+        (TaskFinish::LOG_TYPE, _)
+            if !replay::typed::<TaskFinish>(log)
+                .map_err(unreplayable)?
+                .caused
+                .is_empty() =>
+        {
+            return Ok(())
+        }
+        (_, None) => {
+            conflicts.push(Conflict::EditDeleted { log: log.id, obj: id });
+            return Ok(());
+        }
+        (ObjSetDesc::LOG_TYPE, Some(obj)) => {
+            let set: ObjSetDesc = replay::typed(log).map_err(unreplayable)?;
+            let (before, after) = (set.diff.before().cloned(), set.diff.after().cloned());
+            vec![(
+                "desc".into(),
+                obj.get("desc").cloned(),
+                before.map(Into::into),
+                after.map(Into::into),
+            )]
+        }
+        (ObjSetAttr::LOG_TYPE, Some(obj)) => {
+            let set: ObjSetAttr = replay::typed(log).map_err(unreplayable)?;
+            let current = obj.get("attrs").and_then(|attrs| attrs.get(&set.attr)).cloned();
+            let (before, after) = (set.diff.before().cloned(), set.diff.after().cloned());
+            vec![(format!("attrs.{}", set.attr), current, before, after)]
+        }
+        (ObjSetProps::LOG_TYPE, Some(obj)) => {
+            let set: ObjSetProps = replay::typed(log).map_err(unreplayable)?;
+            set.props
+                .into_iter()
+                .map(|(key, diff)| {
+                    let current = obj.get(&key).cloned();
+                    (key, current, diff.before().cloned(), diff.after().cloned())
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    for (field, found, expected, after) in fields {
+        // Both sides making the same change isn't a conflict
+        if found != expected && found != after {
+            conflicts.push(Conflict::Edit {
+                log: log.id,
+                obj: id,
+                field,
+                expected,
+                found,
+            });
+        }
+    }
+    replay::replay(objs, log).map_err(unreplayable)
+}
+
+/// The logs both sides have under the same ID, but not the same. Only possible if both have handed out IDs on the
+/// same device.
+pub fn collisions(local: &Dump, remote: &Dump) -> Vec<EitherId> {
+    let logs = local.logs.iter().map(|l| (l.id, &l.log)).collect::<BTreeMap<_, _>>();
+    remote
+        .logs
+        .iter()
+        .filter(|l| matches!(logs.get(&l.id), Some(log) if *log != &l.log))
+        .map(|l| EitherId::Log(l.id))
+        .collect()
+}
+
+/// Maps an ID, or the IDs in an array of them (or of arrays of them)
+fn map_ids(val: &mut AttrValue, map: &impl Fn(IdType) -> IdType) {
+    match val {
+        AttrValue::Number(n) => {
+            if let Some(id) = n.as_u64() {
+                *val = map(id as IdType).into();
+            }
+        }
+        AttrValue::Array(ids) => ids.iter_mut().for_each(|id| map_ids(id, map)),
+        _ => {}
+    }
+}
+
+/// Maps the IDs an object refers to
+fn map_obj(obj: &mut AttrValue, objs: &impl Fn(IdType) -> IdType) {
+    let key = match obj.get("typ").and_then(AttrValue::as_str) {
+        Some(Task::OBJ_TYPE) => "cache",
+        Some(SubTask::OBJ_TYPE) => "task-id",
+        _ => return,
+    };
+    if let Some(ids) = obj.get_mut(key) {
+        map_ids(ids, objs);
+    }
+}
+
+/// Maps the IDs a log refers to, those in the objects and changes it carries included
+fn map_log(log: &mut AttrValue, logs: &impl Fn(IdType) -> IdType, objs: &impl Fn(IdType) -> IdType) {
+    let typ = log
+        .get("typ")
+        .and_then(AttrValue::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let undo = typ == LogUndo::LOG_TYPE || typ == LogRedo::LOG_TYPE;
+    if let Some(id) = log.get_mut("id") {
+        if undo {
+            map_ids(id, logs);
+        } else {
+            map_ids(id, objs);
+        }
+    }
+    if let Some(listed) = log.get_mut(if undo { "logs" } else { "caused" }) {
+        map_ids(listed, logs);
+    }
+    if let Some(obj) = log.get_mut("obj") {
+        map_obj(obj, objs);
+    }
+    if typ == ObjSetProps::LOG_TYPE {
+        for key in &["cache", "task-id"] {
+            // Each side of the `Diff` is an ID or an array of them
+            if let Some(AttrValue::Object(diff)) = log.get_mut("props").and_then(|props| props.get_mut(*key)) {
+                diff.values_mut().for_each(|side| map_ids(side, objs));
+            }
+        }
+    }
+}
+
+/// Moves the records `local` has made on its device since it forked from `remote` to the device `to`, with the
+/// references to them, and has `local` hand out IDs on `to` from now on. It's for when both sides have handed out
+/// IDs on the same device, which then can be told apart again. What `remote` has is left as it is, so that it
+/// doesn't collide the other way around either.
+pub fn renumber(local: &mut Dump, remote: &Dump, to: Device) {
+    let device = local.logs_id.device();
+    let remote_logs = remote.logs.iter().map(|l| (l.id, &l.log)).collect::<BTreeMap<_, _>>();
+    let moved_logs = local
+        .logs
+        .iter()
+        .filter(|l| l.id.device() == device && remote_logs.get(&l.id) != Some(&&l.log))
+        .map(|l| l.id.0)
+        .collect::<BTreeSet<_>>();
+    // The objects created since, which the logs creating them tell
+    let moved_objs = local
+        .logs
+        .iter()
+        .filter(|l| moved_logs.contains(&l.id.0))
+        .filter(|l| l.log.get("typ").and_then(AttrValue::as_str) == Some(CreateObj::LOG_TYPE))
+        .filter_map(|l| l.log.get("id").and_then(AttrValue::as_u64))
+        .map(|id| id as IdType)
+        .filter(|&id| device_of(id) == device)
+        .collect::<BTreeSet<_>>();
+    let on = |moved: &BTreeSet<IdType>, id: IdType| {
+        if moved.contains(&id) {
+            id - first_id(device) + first_id(to)
+        } else {
+            id
+        }
+    };
+    let logs = |id| on(&moved_logs, id);
+    let objs = |id| on(&moved_objs, id);
+    for DumpLog { id, log } in &mut local.logs {
+        *id = LogId(logs(id.0));
+        map_log(log, &logs, &objs);
+    }
+    for DumpObj { id, obj } in &mut local.objs {
+        *id = ObjId(objs(id.0));
+        map_obj(obj, &objs);
+    }
+    local.logs.sort_by_key(|l| l.id);
+    local.objs.sort_by_key(|o| o.id);
+    local.logs_id = LogId(next_id(to, local.logs.iter().map(|l| l.id.0)));
+    local.objs_id = ObjId(next_id(to, local.objs.iter().map(|o| o.id.0)));
+}
+
+/// Merges `remote` into `local`, both at the current schema. `None` if `remote` has nothing `local` doesn't.
+pub fn merge(local: Dump, remote: Dump) -> Result<Option<(Dump, SyncReport)>> {
+    let collided = collisions(&local, &remote);
+    if !collided.is_empty() {
+        return Err(Error::SyncCollision(collided));
+    }
+    let mut logs = local
+        .logs
+        .into_iter()
+        .map(|l| (l.id, l.log))
+        .collect::<BTreeMap<_, _>>();
+    let remote_logs = remote
+        .logs
+        .into_iter()
+        .map(|l| (l.id, l.log))
+        .collect::<BTreeMap<_, _>>();
+    let local_only = logs
+        .iter()
+        .filter(|(id, _)| !remote_logs.contains_key(id))
+        .map(|(id, log)| script_log(*id, log))
+        .collect::<Result<Vec<_>>>()?;
+    let remote_only = remote_logs
+        .into_iter()
+        .filter(|(id, _)| !logs.contains_key(id))
+        .map(|(id, log)| Ok((script_log(id, &log)?, log)))
+        .collect::<Result<Vec<_>>>()?;
+    if remote_only.is_empty() {
+        return Ok(None);
+    }
+    let mut report = SyncReport {
+        pulled: remote_only.iter().map(|(log, _)| log.id).collect(),
+        ..SyncReport::default()
+    };
+
+    // The objects are what the logs give in the order they happened, so everything from the first log one of the
+    // sides is missing on is taken back, and played again along with the other side's
+    let key = |log: &ScriptLog| (log.time, log.id);
+    let fork = local_only
+        .iter()
+        .chain(remote_only.iter().map(|(log, _)| log))
+        .map(key)
+        .min()
+        .unwrap();
+    let mut since = logs
+        .iter()
+        .map(|(id, log)| script_log(*id, log))
+        .filter(|log| log.as_ref().map_or(true, |log| key(log) >= fork))
+        .collect::<Result<Vec<_>>>()?;
+    since.sort_by_key(key);
+    let mut objs = local.objs.into_iter().map(|o| (o.id, o.obj)).collect::<ObjMap>();
+    // The state is each device's own bookkeeping, it's not merged
+    let state = objs.get(&State::ID).cloned();
+    for log in since.iter().rev() {
+        replay::revert(&mut objs, log).map_err(|_| Error::Unreplayable(log.id))?;
+    }
+    for (log, val) in remote_only {
+        logs.insert(log.id, val);
+        since.push(log);
+    }
+    since.sort_by_key(key);
+    for log in &since {
+        apply(&mut objs, log, &mut report.conflicts)?;
+    }
+    if let Some(state) = state {
+        objs.insert(State::ID, state);
+    }
+
+    // The other side's counters only matter if it's on the same device, then whichever went further wins
+    let counter = |local: IdType, remote: IdType| {
+        if device_of(local) == device_of(remote) {
+            local.max(remote)
+        } else {
+            local
+        }
+    };
+    let merged = Dump {
+        version: DUMP_VERSION,
+        schema_version: local.schema_version,
+        logs_id: LogId(counter(local.logs_id.0, remote.logs_id.0)),
+        objs_id: ObjId(counter(local.objs_id.0, remote.objs_id.0)),
+        objs: objs.into_iter().map(|(id, obj)| DumpObj { id, obj }).collect(),
+        logs: logs.into_iter().map(|(id, log)| DumpLog { id, log }).collect(),
+    };
+    Ok(Some((merged, report)))
+}
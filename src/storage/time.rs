@@ -1,4 +1,6 @@
-use chrono::{FixedOffset, Local, Utc};
+use chrono::{FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone as _, Utc};
+/// A time zone by its IANA name, e.g. `Europe/Paris`
+pub use chrono_tz::Tz;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -28,6 +30,44 @@ impl DateTime {
     pub fn now() -> Self {
         Self(Local::now().into())
     }
+
+    /// Parses a local time in the same `%Y.%m.%d..%H.%M.%S` form it's displayed in. The time of the day can be
+    /// left out for midnight.
+    pub fn parse_local(s: &str) -> Option<Self> {
+        let naive = NaiveDateTime::parse_from_str(s, "%Y.%m.%d..%H.%M.%S")
+            .ok()
+            .or_else(|| NaiveDate::parse_from_str(s, "%Y.%m.%d").ok()?.and_hms_opt(0, 0, 0))?;
+        Local.from_local_datetime(&naive).earliest().map(Self::from)
+    }
+
+    /// What the wall clock shows at this time in `zone`, or in the offset of this time without one
+    pub fn wall(&self, zone: Option<Tz>) -> NaiveDateTime {
+        match zone {
+            Some(zone) => self.0.with_timezone(&zone).naive_local(),
+            None => self.0.naive_local(),
+        }
+    }
+
+    /// When the wall clock shows `wall` in `zone`, in the offset the zone is in then. When the clocks go back, it's
+    /// the earlier of the two times. A time the clocks skip when they go forward is taken an hour later, past the
+    /// change.
+    pub fn in_zone(wall: NaiveDateTime, zone: Tz) -> Option<DateTime> {
+        zone.from_local_datetime(&wall)
+            .earliest()
+            .or_else(|| {
+                zone.from_local_datetime(&(wall + chrono::Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|t| DateTime(t.with_timezone(&t.offset().fix())))
+    }
+
+    /// When the wall clock shows `wall` in `zone` as `in_zone` has it, or in the offset of this time without a zone
+    pub fn at_wall(&self, wall: NaiveDateTime, zone: Option<Tz>) -> Option<DateTime> {
+        match zone {
+            Some(zone) => DateTime::in_zone(wall, zone),
+            None => self.0.offset().from_local_datetime(&wall).single().map(DateTime),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -67,17 +107,23 @@ impl<'de> Deserialize<'de> for Duration {
             fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
                 Ok(Duration(chrono::Duration::seconds(value as i64)))
             }
+
+            // Negative for notifications ahead of a deadline
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                Ok(Duration(chrono::Duration::seconds(value)))
+            }
         }
-        deserializer.deserialize_u64(DurationVisitor)
+        deserializer.deserialize_i64(DurationVisitor)
     }
 }
 
-#[derive(Clone, Debug)]
-#[cfg_attr(
-    features = "scripting",
-    derive(Trace, VmType, Userdata),
-    gluon_userdata(clone),
-    gluon(vm_type = "time.TimeZone"),
-    gluon_trace(skip)
-)]
-pub struct TimeZone(pub chrono::FixedOffset);
+/// The zone the scripts step and show times in, which is all it's for
+#[cfg(features = "scripting")]
+#[derive(Clone, Debug, Trace, VmType, Userdata)]
+#[gluon_userdata(clone)]
+#[gluon(vm_type = "time.TimeZone")]
+#[gluon_trace(skip)]
+pub enum TimeZone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}